@@ -0,0 +1,295 @@
+use std::collections::BTreeMap;
+
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_json::Value;
+
+use crate::backup::dump_table;
+use crate::reports::effective_date;
+use crate::timestamp::now_stored;
+use crate::{connect_and_setup_db, mark_changed_at, periods, snapshots, transaction_from_row, AllTransactions, AppError, TransactionType};
+
+// audit_log is append-only: triggers created in setup_db refuse updates and deletes. Every change
+// to the ledger, the settings, the closed periods or the price history is recorded with the row as
+// it was (before) and as it became (after), as JSON; a row that was added has no before image and
+// one that was removed has no after image. The lot tables are not logged, as replays rebuild them
+// from the ledger. Backups carry the log, and a restore only ever adds to it.
+
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+pub struct AuditEntry {
+    id: i64,
+    recorded_at: String,
+    actor: String,   // operating system user running the app
+    command: String, // command that made the change
+    action: TransactionType,
+    table_name: String,
+    row_id: Option<i64>,
+    before: Option<Value>,
+    after: Option<Value>,
+    imported_from: Option<String>, // backup and entry id, for entries brought in by import_backup
+}
+
+// app_settings columns that track replay progress, the ledger format or the snapshot scheduler
+// rather than anything the user set.
+const UNAUDITED_SETTINGS: [&str; 4] = ["replay_from_date", "replay_from_txn_id", "ledger_format", "snapshot_last_error"];
+
+fn actor() -> String {
+    std::env::var("USER").or_else(|_| std::env::var("USERNAME")).unwrap_or_default()
+}
+
+fn action_name(action: &TransactionType) -> &'static str {
+    match action {
+        TransactionType::Add => "Add",
+        TransactionType::Edit => "Edit",
+        TransactionType::Remove => "Remove",
+    }
+}
+
+pub fn record(
+    conn: &Connection,
+    command: &str,
+    table_name: &str,
+    row_id: Option<i64>,
+    action: TransactionType,
+    before: Option<&Value>,
+    after: Option<&Value>,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO audit_log (recorded_at, actor, command, action, table_name, row_id, before_image, after_image)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![now_stored(), actor(), command, action_name(&action), table_name, row_id, before.map(Value::to_string), after.map(Value::to_string)],
+    )?;
+    Ok(())
+}
+
+// Records the difference between two images of a row: an addition, an edit, a removal or nothing.
+pub fn record_change(conn: &Connection, command: &str, table_name: &str, row_id: Option<i64>, before: Option<&Value>, after: Option<&Value>) -> rusqlite::Result<()> {
+    let action = match (before, after) {
+        (None, Some(_)) => TransactionType::Add,
+        (Some(_), None) => TransactionType::Remove,
+        (Some(before), Some(after)) if before != after => TransactionType::Edit,
+        _ => return Ok(()),
+    };
+    record(conn, command, table_name, row_id, action, before, after)
+}
+
+fn transaction_image(txn: &AllTransactions) -> Value {
+    serde_json::to_value(txn).unwrap_or(Value::Null)
+}
+
+/// Ledger row `id` as it is stored, for an audit image.
+pub fn ledger_image(conn: &Connection, id: i32) -> rusqlite::Result<Option<Value>> {
+    let txn = conn.query_row("SELECT * FROM all_transactions WHERE id = ?1", params![id], transaction_from_row).optional()?;
+    Ok(txn.as_ref().map(transaction_image))
+}
+
+/// Every ledger row by id, for commands that change many rows at once; see record_ledger_changes.
+pub fn ledger_images(conn: &Connection) -> rusqlite::Result<BTreeMap<i32, Value>> {
+    let mut stmt = conn.prepare("SELECT * FROM all_transactions ORDER BY id")?;
+    let rows = stmt.query_map([], transaction_from_row)?;
+    let mut images = BTreeMap::new();
+    for txn in rows {
+        let txn = txn?;
+        images.insert(txn.id, transaction_image(&txn));
+    }
+    Ok(images)
+}
+
+/// Records every ledger row that was added, edited or removed since `before` was taken.
+pub fn record_ledger_changes(conn: &Connection, command: &str, before: &BTreeMap<i32, Value>) -> rusqlite::Result<()> {
+    let after = ledger_images(conn)?;
+    let mut ids: Vec<i32> = before.keys().chain(after.keys()).copied().collect();
+    ids.sort_unstable();
+    ids.dedup();
+    for id in ids {
+        record_change(conn, command, "all_transactions", Some(id as i64), before.get(&id), after.get(&id))?;
+    }
+    Ok(())
+}
+
+/// The settings row, for an audit image.
+pub fn settings_image(conn: &Connection) -> rusqlite::Result<Option<Value>> {
+    Ok(dump_table(conn, "app_settings")?.pop().map(|mut settings| {
+        for column in UNAUDITED_SETTINGS {
+            settings.remove(column);
+        }
+        Value::Object(settings)
+    }))
+}
+
+/// Records a settings change, given the settings as they were before it.
+pub fn record_settings_change(conn: &Connection, command: &str, before: Option<Value>) -> rusqlite::Result<()> {
+    record_change(conn, command, "app_settings", Some(1), before.as_ref(), settings_image(conn)?.as_ref())
+}
+
+/// Closed period `year`, for an audit image.
+pub fn closed_period_image(conn: &Connection, year: i32) -> rusqlite::Result<Option<Value>> {
+    Ok(dump_table(conn, "closed_periods")?
+        .into_iter()
+        .find(|period| period.get("year").and_then(Value::as_i64) == Some(year as i64))
+        .map(Value::Object))
+}
+
+/// Price history row `id`, for an audit image.
+pub fn price_image(conn: &Connection, id: i64) -> rusqlite::Result<Option<Value>> {
+    conn.query_row("SELECT id, price_date, price FROM price_history WHERE id = ?1", params![id], |row| {
+        Ok(serde_json::json!({ "id": row.get::<_, i64>(0)?, "price_date": row.get::<_, String>(1)?, "price": row.get::<_, f64>(2)? }))
+    })
+    .optional()
+}
+
+fn parse_image(image: Option<String>) -> Option<Value> {
+    image.and_then(|image| serde_json::from_str(&image).ok())
+}
+
+/// Audit entries, newest first, optionally only those for one table or one row of it.
+#[tauri::command]
+pub fn list_audit_log(table_name: Option<String>, row_id: Option<i64>, limit: Option<i64>, offset: Option<i64>) -> Result<Vec<AuditEntry>, AppError> {
+    let conn = connect_and_setup_db()?;
+    let mut stmt = conn.prepare(
+        "SELECT id, recorded_at, actor, command, action, table_name, row_id, before_image, after_image, imported_from FROM audit_log
+         WHERE (?1 IS NULL OR table_name = ?1) AND (?2 IS NULL OR row_id = ?2)
+         ORDER BY id DESC LIMIT ?3 OFFSET ?4",
+    )?;
+    let entries = stmt.query_map(params![table_name, row_id, limit.unwrap_or(-1), offset.unwrap_or(0)], |row| {
+        let action = match row.get::<_, String>(4)?.as_str() {
+            "Add" => TransactionType::Add,
+            "Remove" => TransactionType::Remove,
+            _ => TransactionType::Edit,
+        };
+        Ok(AuditEntry {
+            id: row.get(0)?,
+            recorded_at: row.get(1)?,
+            actor: row.get(2)?,
+            command: row.get(3)?,
+            action,
+            table_name: row.get(5)?,
+            row_id: row.get(6)?,
+            before: parse_image(row.get(7)?),
+            after: parse_image(row.get(8)?),
+            imported_from: row.get(9)?,
+        })
+    })?;
+    Ok(entries.collect::<rusqlite::Result<Vec<_>>>()?)
+}
+
+/// Puts a ledger row back the way audit entry `audit_id` left it, or, when that entry removed the
+/// row, the way it was just before. A removed row comes back under its old id. Like an edit, this
+/// only changes the ledger; redo_transactions brings the lots up to date.
+#[tauri::command]
+pub fn restore_transaction_version(audit_id: i64, override_closed_period: Option<bool>) -> Result<(), AppError> {
+    let conn = connect_and_setup_db()?;
+    let (table_name, row_id, before, after): (String, Option<i64>, Option<String>, Option<String>) = conn
+        .query_row(
+            "SELECT table_name, row_id, before_image, after_image FROM audit_log WHERE id = ?1",
+            params![audit_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )
+        .optional()?
+        .ok_or_else(|| AppError::AuditError(format!("no entry {}", audit_id)))?;
+    let version = match (table_name.as_str(), row_id, after.or(before)) {
+        ("all_transactions", Some(_), Some(image)) => serde_json::from_str::<AllTransactions>(&image)?,
+        _ => return Err(AppError::AuditError(format!("entry {} does not hold a ledger row", audit_id))),
+    };
+
+    // The row as it is and as it will be must both be clear of closed years.
+    let override_lock = override_closed_period.unwrap_or(false);
+    periods::ensure_row_open(&conn, version.id, override_lock)?;
+    periods::ensure_open(&conn, effective_date(&version).unwrap_or(""), override_lock)?;
+    if let Some(external_ref) = &version.external_ref {
+        let taken_by: Option<i32> = conn
+            .query_row("SELECT id FROM all_transactions WHERE external_ref = ?1 AND id != ?2", params![external_ref, version.id], |row| row.get(0))
+            .optional()?;
+        if let Some(other) = taken_by {
+            return Err(AppError::DuplicateTransaction(format!("{} is now used by transaction {}", external_ref, other)));
+        }
+    }
+
+    snapshots::take_snapshot("restore_transaction_version")?;
+    let tx = conn.unchecked_transaction()?;
+    let current = ledger_image(&tx, version.id)?;
+    mark_changed_at(&tx, version.id)?;
+    tx.execute(
+        "INSERT INTO all_transactions (id, quantity, price_per_ton, orig_price, sell_price, liquidation_date, purchase_date, is_used, external_ref, is_reward)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+         ON CONFLICT (id) DO UPDATE SET quantity = excluded.quantity, price_per_ton = excluded.price_per_ton, orig_price = excluded.orig_price,
+            sell_price = excluded.sell_price, liquidation_date = excluded.liquidation_date, purchase_date = excluded.purchase_date,
+            is_used = excluded.is_used, external_ref = excluded.external_ref, is_reward = excluded.is_reward",
+        params![
+            version.id, version.quantity, version.price_per_ton, version.orig_price, version.sell_price,
+            version.liquidation_date, version.purchase_date, version.is_used, version.external_ref, version.is_reward
+        ],
+    )?;
+    mark_changed_at(&tx, version.id)?;
+    record_change(&tx, "restore_transaction_version", "all_transactions", Some(version.id as i64), current.as_ref(), ledger_image(&tx, version.id)?.as_ref())?;
+    tx.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prices::{record_price, remove_price};
+    use crate::snapshots::list_snapshots;
+    use crate::test_support::{day, test_db};
+    use crate::{edit_transaction_via_id, record_purchase, remove_transaction_via_id, show_all_transactions};
+
+    fn entries(table_name: &str) -> Vec<Value> {
+        let entries = list_audit_log(Some(table_name.to_string()), None, None, None).unwrap();
+        entries.iter().rev().map(|entry| serde_json::to_value(entry).unwrap()).collect()
+    }
+
+    #[test]
+    fn the_log_refuses_updates_and_deletes() {
+        let _db = test_db();
+        record_purchase(10.0, 5.0, day(2024, 1, 1), None, None, None, None).unwrap();
+        let conn = connect_and_setup_db().unwrap();
+        assert!(conn.execute("UPDATE audit_log SET actor = 'someone else'", []).is_err());
+        assert!(conn.execute("DELETE FROM audit_log", []).is_err());
+        assert_eq!(entries("all_transactions").len(), 1);
+    }
+
+    #[test]
+    fn versions_of_a_row_can_be_restored() {
+        let _db = test_db();
+        record_purchase(10.0, 5.0, day(2024, 1, 1), None, None, None, None).unwrap();
+        assert_eq!(edit_transaction_via_id(1, 8.0, 6.0, 0.0, 0.0, day(2024, 1, 2), day(2024, 1, 2), false, None, None), "Completed");
+        let added = entries("all_transactions")[0]["id"].as_i64().unwrap();
+        restore_transaction_version(added, None).unwrap();
+        let restored = show_all_transactions().unwrap().remove(0);
+        assert_eq!((restored.quantity, restored.price_per_ton, restored.purchase_date.as_deref()), (10.0, Some(5.0), Some("2024-01-01T00:00:00Z")));
+
+        // A removed row comes back under its old id.
+        assert_eq!(remove_transaction_via_id(1, None), "Completed");
+        let removed = entries("all_transactions").last().unwrap()["id"].as_i64().unwrap();
+        restore_transaction_version(removed, None).unwrap();
+        assert_eq!(show_all_transactions().unwrap().remove(0).id, 1);
+        let actions: Vec<Value> = entries("all_transactions").iter().map(|entry| entry["action"].clone()).collect();
+        assert_eq!(actions, ["Add", "Edit", "Edit", "Remove", "Add"]);
+        assert!(matches!(restore_transaction_version(9_999, None), Err(AppError::AuditError(_))));
+    }
+
+    #[test]
+    fn price_changes_are_logged_and_snapshotted() {
+        let _db = test_db();
+        record_price(day(2024, 1, 1), 300.0).unwrap();
+        assert!(list_snapshots().unwrap().is_empty());
+        record_price(day(2024, 1, 1), 320.0).unwrap();
+        remove_price(1).unwrap();
+
+        let logged = entries("price_history");
+        let images: Vec<(Value, Value, Value)> = logged
+            .iter()
+            .map(|entry| (entry["action"].clone(), entry["before"]["price"].clone(), entry["after"]["price"].clone()))
+            .collect();
+        assert_eq!(
+            images,
+            [
+                ("Add".into(), Value::Null, 300.0.into()),
+                ("Edit".into(), 300.0.into(), 320.0.into()),
+                ("Remove".into(), 320.0.into(), Value::Null)
+            ]
+        );
+        let reasons: Vec<Value> = list_snapshots().unwrap().iter().map(|snapshot| serde_json::to_value(snapshot).unwrap()["reason"].clone()).collect();
+        assert_eq!(reasons, ["remove_price", "record_price"]);
+    }
+}
@@ -0,0 +1,243 @@
+use rusqlite::Connection;
+
+use crate::reports::{
+    disposals, effective_date, fiscal_year, fiscal_year_bounds, held_more_than_years, reward_income, with_lot_links, Disposal, FiscalYearStart,
+};
+use crate::tax::{bracket_tax, load_tax_profile, TaxEstimate, TaxProfile};
+use crate::timestamp::load_display_offset;
+use crate::{connect_and_setup_db, transaction_from_row, AppError};
+
+// Australian financial years run from 1 July to 30 June, whatever fiscal year the portfolio
+// reports in.
+pub const FINANCIAL_YEAR_START: FiscalYearStart = FiscalYearStart { month: 7, day: 1 };
+const CGT_DISCOUNT: f64 = 0.5;
+
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+pub struct CgtParcel {
+    disposal: Disposal,
+    cost_base: f64,
+    capital_proceeds: f64,
+    gain: f64,
+    discount_eligible: bool, // held for more than 12 months
+}
+
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+pub struct AustraliaCgtReport {
+    financial_year: i32,
+    period_start: String,
+    period_end: String, // exclusive
+    parcels: Vec<CgtParcel>,
+    discountable_gains: f64,
+    other_gains: f64,
+    capital_losses: f64,
+    carried_forward_losses: f64,
+    discount: f64,
+    net_capital_gain: f64,
+    net_capital_loss: f64, // carried forward to the next year
+    pub estimate: TaxEstimate,
+}
+
+/// Builds the CGT schedule for one financial year. Current-year losses, then losses carried
+/// forward from earlier years, are offset against gains that do not get the discount first and
+/// only then against discountable gains, which leaves the most gain for the 50% discount.
+pub fn build_australia_report(conn: &Connection, profile: &TaxProfile, year: i32, carried_forward_losses: f64) -> Result<AustraliaCgtReport, AppError> {
+    let linked = with_lot_links(conn)?;
+    let conn = linked.as_ref().unwrap_or(conn);
+    let display_offset = load_display_offset(conn)?;
+    let (period_start, period_end) = fiscal_year_bounds(year, &FINANCIAL_YEAR_START, display_offset);
+
+    let mut parcels = Vec::new();
+    let (mut discountable_gains, mut other_gains, mut capital_losses) = (0.0, 0.0, 0.0);
+    for disposal in disposals(conn)?.into_iter().filter(|d| fiscal_year(&d.liquidation_date, &FINANCIAL_YEAR_START, display_offset) == Some(year)) {
+        let discount_eligible = disposal
+            .purchase_date
+            .as_deref()
+            .map(|acquired| held_more_than_years(acquired, &disposal.liquidation_date, 1, display_offset))
+            .unwrap_or(false);
+        let gain = disposal.gain();
+        if gain < 0.0 {
+            capital_losses -= gain;
+        } else if discount_eligible {
+            discountable_gains += gain;
+        } else {
+            other_gains += gain;
+        }
+        parcels.push(CgtParcel {
+            cost_base: disposal.cost_basis(),
+            capital_proceeds: disposal.proceeds(),
+            disposal,
+            gain,
+            discount_eligible,
+        });
+    }
+
+    let mut losses = capital_losses + carried_forward_losses.max(0.0);
+    let other_after_losses = (other_gains - losses).max(0.0);
+    losses = (losses - other_gains).max(0.0);
+    let discountable_after_losses = (discountable_gains - losses).max(0.0);
+    let net_capital_loss = (losses - discountable_gains).max(0.0);
+    let discount = discountable_after_losses * CGT_DISCOUNT;
+    let net_capital_gain = other_after_losses + discountable_after_losses - discount;
+
+    let mut stmt = conn.prepare("SELECT * FROM all_transactions WHERE is_reward AND NOT is_used")?;
+    let mut reward_total = 0.0;
+    for txn in stmt.query_map([], transaction_from_row)? {
+        let txn = txn?;
+        if effective_date(&txn).and_then(|date| fiscal_year(date, &FINANCIAL_YEAR_START, display_offset)) == Some(year) {
+            reward_total += reward_income(&txn);
+        }
+    }
+
+    // The net capital gain is assessable income, taxed at the marginal rate in the profile. The
+    // tax is split between the discounted and undiscounted parts pro rata.
+    let capital_gains_tax = bracket_tax(net_capital_gain, &profile.short_term_brackets);
+    let short_term_tax = if net_capital_gain > 0.0 { capital_gains_tax * other_after_losses / net_capital_gain } else { 0.0 };
+    let reward_income_tax = reward_total * profile.reward_income_rate;
+    let estimate = TaxEstimate {
+        year,
+        short_term_gain: other_gains,
+        long_term_gain: discountable_gains,
+        exemption_used: discount,
+        taxable_short_term: other_after_losses,
+        taxable_long_term: discountable_after_losses - discount,
+        short_term_tax,
+        long_term_tax: capital_gains_tax - short_term_tax,
+        reward_income: reward_total,
+        reward_income_tax,
+        total_tax: capital_gains_tax + reward_income_tax,
+        loss_carryforward: net_capital_loss,
+    };
+
+    Ok(AustraliaCgtReport {
+        financial_year: year,
+        period_start,
+        period_end,
+        parcels,
+        discountable_gains,
+        other_gains,
+        capital_losses,
+        carried_forward_losses: carried_forward_losses.max(0.0),
+        discount,
+        net_capital_gain,
+        net_capital_loss,
+        estimate,
+    })
+}
+
+/// Per-parcel CGT schedule and net capital gain for the financial year ending 30 June `year`.
+#[tauri::command]
+pub fn australia_cgt_report(year: i32, carried_forward_losses: Option<f64>) -> Result<AustraliaCgtReport, AppError> {
+    let conn = connect_and_setup_db()?;
+    let profile = load_tax_profile(&conn)?;
+    build_australia_report(&conn, &profile, year, carried_forward_losses.unwrap_or(0.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tax::Jurisdiction;
+    use crate::test_support::{day, test_db};
+    use crate::{record_purchase, use_tao};
+
+    // Purchases and sales of `quantity` at `price` on the given day.
+    enum Entry {
+        Buy(f32, f64, (i32, u32, u32)),
+        Sell(f32, f64, (i32, u32, u32)),
+    }
+
+    #[test]
+    fn cgt_schedules_against_known_figures() {
+        use Entry::{Buy, Sell};
+        let profile = TaxProfile::preset(Jurisdiction::Australia);
+        // (case, ledger, financial year, losses carried in, discountable gains, other gains,
+        //  capital losses, discount, net capital gain, net capital loss, total tax at 37%)
+        let cases = [
+            (
+                "a parcel held over 12 months gets the discount",
+                vec![Buy(1.0, 1_000.0, (2023, 7, 1)), Sell(1.0, 3_000.0, (2024, 8, 1))],
+                2025, 0.0, 2_000.0, 0.0, 0.0, 1_000.0, 1_000.0, 0.0, 370.0,
+            ),
+            (
+                "a parcel sold within 12 months does not",
+                vec![Buy(1.0, 1_000.0, (2024, 7, 1)), Sell(1.0, 3_000.0, (2024, 12, 1))],
+                2025, 0.0, 0.0, 2_000.0, 0.0, 0.0, 2_000.0, 0.0, 740.0,
+            ),
+            (
+                "sold on the anniversary is not over 12 months",
+                vec![Buy(1.0, 1_000.0, (2023, 8, 1)), Sell(1.0, 3_000.0, (2024, 8, 1))],
+                2025, 0.0, 0.0, 2_000.0, 0.0, 0.0, 2_000.0, 0.0, 740.0,
+            ),
+            (
+                "sold the day after it is",
+                vec![Buy(1.0, 1_000.0, (2023, 8, 1)), Sell(1.0, 3_000.0, (2024, 8, 2))],
+                2025, 0.0, 2_000.0, 0.0, 0.0, 1_000.0, 1_000.0, 0.0, 370.0,
+            ),
+            (
+                "losses go against undiscounted gains first",
+                vec![
+                    Buy(1.0, 1_000.0, (2023, 1, 1)),
+                    Buy(1.0, 1_000.0, (2024, 8, 1)),
+                    Buy(1.0, 3_000.0, (2024, 8, 2)),
+                    Sell(1.0, 3_000.0, (2024, 9, 1)),
+                    Sell(1.0, 1_500.0, (2024, 9, 2)),
+                    Sell(1.0, 2_000.0, (2024, 9, 3)),
+                ],
+                2025, 0.0, 2_000.0, 500.0, 1_000.0, 750.0, 750.0, 0.0, 277.5,
+            ),
+            (
+                "carried-forward losses are used before the discount",
+                vec![Buy(1.0, 1_000.0, (2023, 7, 1)), Sell(1.0, 3_000.0, (2024, 8, 1))],
+                2025, 500.0, 2_000.0, 0.0, 0.0, 750.0, 750.0, 0.0, 277.5,
+            ),
+            (
+                "carried-forward losses larger than the gains carry on",
+                vec![Buy(1.0, 1_000.0, (2023, 7, 1)), Sell(1.0, 3_000.0, (2024, 8, 1))],
+                2025, 5_000.0, 2_000.0, 0.0, 0.0, 0.0, 0.0, 3_000.0, 0.0,
+            ),
+            (
+                "30 June closes the financial year",
+                vec![Buy(2.0, 1_000.0, (2023, 1, 1)), Sell(1.0, 2_000.0, (2024, 6, 30)), Sell(1.0, 4_000.0, (2024, 7, 1))],
+                2024, 0.0, 1_000.0, 0.0, 0.0, 500.0, 500.0, 0.0, 185.0,
+            ),
+            (
+                "1 July opens the next",
+                vec![Buy(2.0, 1_000.0, (2023, 1, 1)), Sell(1.0, 2_000.0, (2024, 6, 30)), Sell(1.0, 4_000.0, (2024, 7, 1))],
+                2025, 0.0, 3_000.0, 0.0, 0.0, 1_500.0, 1_500.0, 0.0, 555.0,
+            ),
+        ];
+        for (case, ledger, year, carried_in, discountable_gains, other_gains, capital_losses, discount, net_capital_gain, net_capital_loss, total_tax) in cases {
+            let _db = test_db();
+            for entry in ledger {
+                match entry {
+                    Buy(quantity, price, (y, m, d)) => record_purchase(quantity, price, day(y, m, d), None, None, None, None).unwrap(),
+                    Sell(quantity, price, (y, m, d)) => {
+                        use_tao(quantity, day(y, m, d), price, None, None, None).unwrap();
+                    }
+                }
+            }
+            let conn = connect_and_setup_db().unwrap();
+            let report = build_australia_report(&conn, &profile, year, carried_in).unwrap();
+            let figures = [
+                ("discountable gains", report.discountable_gains, discountable_gains),
+                ("other gains", report.other_gains, other_gains),
+                ("capital losses", report.capital_losses, capital_losses),
+                ("discount", report.discount, discount),
+                ("net capital gain", report.net_capital_gain, net_capital_gain),
+                ("net capital loss", report.net_capital_loss, net_capital_loss),
+                ("total tax", report.estimate.total_tax, total_tax),
+            ];
+            for (figure, actual, expected) in figures {
+                assert!((actual - expected).abs() < 1e-6, "{}: {} is {}, expected {}", case, figure, actual, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn the_financial_year_runs_from_july_to_june() {
+        let _db = test_db();
+        let conn = connect_and_setup_db().unwrap();
+        let report = build_australia_report(&conn, &TaxProfile::preset(Jurisdiction::Australia), 2025, 0.0).unwrap();
+        assert_eq!((report.period_start.as_str(), report.period_end.as_str()), ("2024-07-01T00:00:00Z", "2025-07-01T00:00:00Z"));
+        assert!(report.parcels.is_empty());
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::{BTreeMap, HashMap};
+
+use rusqlite::Connection;
+use time::UtcOffset;
+
+use crate::holdings::scratch_db;
+use crate::reports::{effective_date, fiscal_year, fiscal_year_bounds, load_fiscal_year_start, FiscalYearStart};
+use crate::timestamp::{load_display_offset, now_stored};
+use crate::{connect_and_setup_db, replay_into, transaction_from_row, AllTransactions, AppError};
+
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+pub struct ClosedYearChange {
+    year: i32,
+    reported_gain: f64, // from the lot tables as they are
+    replayed_gain: f64, // after replaying the ledger in date order
+    difference: f64,
+}
+
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+pub struct BackdatingReport {
+    backdated: Vec<AllTransactions>, // entries dated before an entry recorded ahead of them
+    changed_years: Vec<ClosedYearChange>,
+}
+
+// Realized gain per fiscal year from a connection's used_timber.
+fn gains_by_year(conn: &Connection, start: &FiscalYearStart, display_offset: UtcOffset) -> Result<BTreeMap<i32, f64>, AppError> {
+    let mut stmt = conn.prepare("SELECT liquidation_date, sell_value - orig_value FROM used_timber")?;
+    let mut gains = BTreeMap::new();
+    for row in stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, f64>(1)?)))? {
+        let (date, gain) = row?;
+        let year = fiscal_year(&date, start, display_offset).ok_or(AppError::InvalidDate(date))?;
+        *gains.entry(year).or_insert(0.0) += gain;
+    }
+    Ok(gains)
+}
+
+// When each ledger row took its current date: the position, in the order the log was recorded,
+// of the audit entry that added it or last changed its effective date. Entries imported from
+// another database's backup have ids after the local ones, so the order is taken from
+// recorded_at. Rows that predate the audit log have no entry and count as recorded first.
+fn dated_at(conn: &Connection) -> Result<HashMap<i32, usize>, AppError> {
+    let mut stmt = conn.prepare(
+        "SELECT row_id, before_image, after_image FROM audit_log
+         WHERE table_name = 'all_transactions' AND row_id IS NOT NULL AND after_image IS NOT NULL
+         ORDER BY recorded_at, id",
+    )?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<_, i32>(0)?, row.get::<_, Option<String>>(1)?, row.get::<_, String>(2)?)))?;
+    let date = |image: &str| serde_json::from_str::<AllTransactions>(image).ok().and_then(|txn| effective_date(&txn).map(str::to_string));
+    let mut dated_at = HashMap::new();
+    for (position, row) in rows.enumerate() {
+        let (txn_id, before, after) = row?;
+        if before.map(|before| date(&before)) != Some(date(&after)) {
+            dated_at.insert(txn_id, position + 1);
+        }
+    }
+    Ok(dated_at)
+}
+
+/// Back-dated ledger entries, and the closed fiscal years whose realized gains would change when
+/// the ledger is replayed in date order. A year is closed once it has ended. The replay runs in a
+/// scratch database, so nothing is changed until redo_transactions is run.
+#[tauri::command]
+pub fn check_backdated_entries() -> Result<BackdatingReport, AppError> {
+    let conn = connect_and_setup_db()?;
+    let start = load_fiscal_year_start(&conn)?;
+    let display_offset = load_display_offset(&conn)?;
+
+    // Rows are taken in the order they were given their dates, so a row edited to a date before
+    // rows recorded ahead of it counts as back-dated just as a back-dated insert does.
+    let dated_at = dated_at(&conn)?;
+    let mut stmt = conn.prepare("SELECT * FROM all_transactions ORDER BY id")?;
+    let mut ledger = stmt.query_map([], transaction_from_row)?.collect::<rusqlite::Result<Vec<_>>>()?;
+    ledger.sort_by_key(|txn| (dated_at.get(&txn.id).copied().unwrap_or(0), txn.id));
+    let mut backdated = Vec::new();
+    let mut latest: Option<String> = None;
+    for txn in ledger {
+        match (effective_date(&txn).map(str::to_string), &latest) {
+            (Some(date), Some(latest)) if date < *latest => backdated.push(txn),
+            (Some(date), _) => latest = Some(date),
+            (None, _) => {}
+        }
+    }
+
+    let scratch = scratch_db(&conn)?;
+    replay_into(&scratch, &conn, None, None)?;
+    let reported = gains_by_year(&conn, &start, display_offset)?;
+    let replayed = gains_by_year(&scratch, &start, display_offset)?;
+
+    let now = now_stored();
+    let round = |value: f64| (value * 100.0).round() / 100.0;
+    let mut years: Vec<i32> = reported.keys().chain(replayed.keys()).copied().collect();
+    years.sort_unstable();
+    years.dedup();
+    let changed_years = years
+        .into_iter()
+        .filter(|&year| fiscal_year_bounds(year, &start, display_offset).1 <= now)
+        .map(|year| {
+            let reported_gain = round(reported.get(&year).copied().unwrap_or(0.0));
+            let replayed_gain = round(replayed.get(&year).copied().unwrap_or(0.0));
+            ClosedYearChange { year, reported_gain, replayed_gain, difference: round(replayed_gain - reported_gain) }
+        })
+        .filter(|change| change.difference != 0.0)
+        .collect();
+
+    Ok(BackdatingReport { backdated, changed_years })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{day, test_db};
+    use crate::{add_transaction, audit, edit_transaction_via_id, record_purchase, redo_transactions, use_tao};
+
+    // Two lots in 2022 and a FIFO sale of half the first, for a 2022 gain of 5 x (10 - 5).
+    fn ledger_for_2022() {
+        record_purchase(10.0, 5.0, day(2022, 1, 1), None, None, None, None).unwrap();
+        record_purchase(10.0, 8.0, day(2022, 6, 1), None, None, None, None).unwrap();
+        use_tao(5.0, day(2022, 7, 1), 10.0, None, None, None).unwrap();
+    }
+
+    fn backdated_ids(report: &BackdatingReport) -> Vec<i32> {
+        report.backdated.iter().map(|txn| txn.id).collect()
+    }
+
+    #[test]
+    fn a_backdated_insert_changes_a_closed_years_gain() {
+        let _db = test_db();
+        ledger_for_2022();
+        assert_eq!(add_transaction(10.0, 9.0, 0.0, 0.0, day(2021, 1, 1), day(2021, 1, 1), false, None, None, None, None), "Completed");
+
+        let report = check_backdated_entries().unwrap();
+        assert_eq!(backdated_ids(&report), [4]);
+        assert_eq!(report.changed_years.len(), 1);
+        let change = &report.changed_years[0];
+        assert_eq!((change.year, change.reported_gain, change.replayed_gain, change.difference), (2022, 25.0, 5.0, -20.0));
+
+        redo_transactions(None).unwrap();
+        assert!(check_backdated_entries().unwrap().changed_years.is_empty());
+    }
+
+    #[test]
+    fn a_backdated_edit_changes_a_closed_years_gain() {
+        let _db = test_db();
+        ledger_for_2022();
+        // Editing the first lot without moving it earlier does not make it back-dated.
+        assert_eq!(edit_transaction_via_id(1, 10.0, 4.0, 0.0, 0.0, day(2022, 1, 1), day(2022, 1, 1), false, None, None), "Completed");
+        assert!(backdated_ids(&check_backdated_entries().unwrap()).is_empty());
+        redo_transactions(None).unwrap();
+
+        // Moving the second lot ahead of the first, in place, does.
+        assert_eq!(edit_transaction_via_id(2, 10.0, 2.0, 0.0, 0.0, day(2021, 6, 1), day(2021, 6, 1), false, None, None), "Completed");
+        let report = check_backdated_entries().unwrap();
+        assert_eq!(backdated_ids(&report), [2]);
+        let change = &report.changed_years[0];
+        assert_eq!((change.year, change.reported_gain, change.replayed_gain, change.difference), (2022, 30.0, 40.0, 10.0));
+    }
+
+    #[test]
+    fn replays_take_rows_by_effective_date_then_id() {
+        let _db = test_db();
+        record_purchase(1.0, 5.0, day(2024, 1, 10), None, None, None, None).unwrap();
+        record_purchase(1.0, 8.0, day(2024, 1, 10), None, None, None, None).unwrap();
+        use_tao(1.0, day(2024, 2, 1), 10.0, None, None, None).unwrap();
+        let consumed = || {
+            let conn = connect_and_setup_db().unwrap();
+            let mut stmt = conn.prepare("SELECT purchase_txn_id FROM used_timber ORDER BY id").unwrap();
+            let ids = stmt.query_map([], |row| row.get::<_, i32>(0)).unwrap();
+            ids.collect::<rusqlite::Result<Vec<_>>>().unwrap()
+        };
+        assert_eq!(consumed(), [1]);
+
+        // Recorded later on the same date: after both earlier lots, however cheap.
+        add_transaction(1.0, 1.0, 0.0, 0.0, day(2024, 1, 10), day(2024, 1, 10), false, None, None, None, None);
+        redo_transactions(None).unwrap();
+        assert_eq!(consumed(), [1]);
+
+        // Recorded last but dated first: ahead of them all.
+        add_transaction(1.0, 2.0, 0.0, 0.0, day(2024, 1, 9), day(2024, 1, 9), false, None, None, None, None);
+        redo_transactions(None).unwrap();
+        assert_eq!(consumed(), [5]);
+        assert_eq!(backdated_ids(&check_backdated_entries().unwrap()), [4, 5]);
+    }
+
+    #[test]
+    fn rows_are_taken_in_the_order_their_audit_entries_were_recorded() {
+        let _db = test_db();
+        let conn = connect_and_setup_db().unwrap();
+        for date in ["2024-02-01T00:00:00Z", "2024-03-01T00:00:00Z"] {
+            conn.execute(
+                "INSERT INTO all_transactions (quantity, price_per_ton, orig_price, sell_price, liquidation_date, purchase_date, is_used)
+                 VALUES (1.0, 5.0, 5.0, 0.0, ?1, ?1, 0)",
+                [date],
+            )
+            .unwrap();
+        }
+        // Row 2's entry was logged first, but recorded after row 1's, which came in with a backup
+        // from another database.
+        for (row_id, recorded_at) in [(2, "2024-03-02T00:00:00Z"), (1, "2024-02-02T00:00:00Z")] {
+            let image = audit::ledger_image(&conn, row_id).unwrap().unwrap().to_string();
+            conn.execute(
+                "INSERT INTO audit_log (recorded_at, actor, command, action, table_name, row_id, after_image)
+                 VALUES (?1, '', 'record_purchase', 'Add', 'all_transactions', ?2, ?3)",
+                rusqlite::params![recorded_at, row_id, image],
+            )
+            .unwrap();
+        }
+        assert!(backdated_ids(&check_backdated_entries().unwrap()).is_empty());
+    }
+}
@@ -0,0 +1,375 @@
+use std::collections::BTreeMap;
+
+use rusqlite::types::{Value, ValueRef};
+use rusqlite::{params_from_iter, Connection, Result};
+use serde_json::{Map, Number};
+
+use crate::{audit, connect_and_setup_db, migrate_ledger_format, periods, replay_ledger, setup_db, snapshots, timestamp, AppError};
+
+// Version 3 backups hold references generated by the current content_hash. Version 2 backups may
+// hold older references for rewards, and version 1 backups the old "YYYY-MM-DD HH:MM:SS" dates
+// and the references generated from them.
+const BACKUP_VERSION: u32 = 3;
+
+// Tables that hold user input, and its history, rather than state derived from the ledger.
+// timber_purchases and used_timber are left out on purpose: replay_ledger rebuilds them after a
+// restore. The flag says whether a table's rows can be appended to an existing database in merge
+// mode; tables whose rows refer to local ids (or app_settings, which is a single row) are only
+// restored on replace.
+const BACKUP_TABLES: &[(&str, bool)] = &[
+    ("app_settings", false),
+    ("all_transactions", true),
+    ("duplicate_candidates", false),
+    ("price_history", true),
+    ("closed_periods", false),
+    ("audit_log", false),
+];
+
+// audit_log is append-only, so a replace adds the entries it is missing and keeps the rest; see
+// append_audit_entries.
+const APPEND_ONLY_TABLE: &str = "audit_log";
+
+// The audit_log columns that make up an entry, leaving out its id and where it was imported from.
+const AUDIT_ENTRY_COLUMNS: [&str; 8] =
+    ["recorded_at", "actor", "command", "action", "table_name", "row_id", "before_image", "after_image"];
+
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+pub struct Backup {
+    version: u32,
+    exported_at: String,
+    tables: BTreeMap<String, Vec<Map<String, serde_json::Value>>>,
+}
+
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+pub enum RestoreMode {
+    Replace, // wipe the backed up tables and restore them exactly
+    Merge,   // append ledger rows that are not already present, keep everything else
+}
+
+fn to_json(value: ValueRef) -> serde_json::Value {
+    match value {
+        ValueRef::Null => serde_json::Value::Null,
+        ValueRef::Integer(i) => serde_json::Value::from(i),
+        ValueRef::Real(f) => Number::from_f64(f).map(serde_json::Value::Number).unwrap_or(serde_json::Value::Null),
+        ValueRef::Text(t) => serde_json::Value::String(String::from_utf8_lossy(t).into_owned()),
+        ValueRef::Blob(b) => serde_json::Value::from(b.to_vec()),
+    }
+}
+
+fn from_json(value: &serde_json::Value) -> Value {
+    match value {
+        serde_json::Value::Null => Value::Null,
+        serde_json::Value::Bool(b) => Value::Integer(*b as i64),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Value::Integer(i),
+            None => Value::Real(n.as_f64().unwrap_or(0.0)),
+        },
+        serde_json::Value::String(s) => Value::Text(s.clone()),
+        other => Value::Text(other.to_string()),
+    }
+}
+
+// Column names as reported by PRAGMA table_info, which leaves out generated columns.
+fn table_columns(conn: &Connection, table: &str) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let names = stmt.query_map([], |row| row.get::<_, String>(1))?;
+    names.collect()
+}
+
+pub fn dump_table(conn: &Connection, table: &str) -> Result<Vec<Map<String, serde_json::Value>>> {
+    let columns = table_columns(conn, table)?;
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM {} ORDER BY rowid", columns.join(", "), table))?;
+    let mut rows = stmt.query([])?;
+
+    let mut dumped = Vec::new();
+    while let Some(row) = rows.next()? {
+        let mut record = Map::new();
+        for (index, column) in columns.iter().enumerate() {
+            record.insert(column.clone(), to_json(row.get_ref(index)?));
+        }
+        dumped.push(record);
+    }
+    Ok(dumped)
+}
+
+// Inserts backed up rows, skipping keys the current schema does not know about so that a
+// backup taken by an older or newer build can still be restored.
+pub fn restore_rows(conn: &Connection, table: &str, rows: &[Map<String, serde_json::Value>], keep_ids: bool) -> Result<()> {
+    let columns: Vec<String> = table_columns(conn, table)?
+        .into_iter()
+        .filter(|column| keep_ids || column != "id")
+        .collect();
+
+    for row in rows {
+        let present: Vec<&String> = columns.iter().filter(|column| row.contains_key(*column)).collect();
+        if present.is_empty() {
+            continue;
+        }
+        let placeholders: Vec<String> = (1..=present.len()).map(|i| format!("?{}", i)).collect();
+        let sql = format!(
+            "INSERT OR IGNORE INTO {} ({}) VALUES ({})",
+            table,
+            present.iter().map(|column| column.as_str()).collect::<Vec<_>>().join(", "),
+            placeholders.join(", ")
+        );
+        let values: Vec<Value> = present.iter().map(|column| from_json(&row[column.as_str()])).collect();
+        conn.execute(&sql, params_from_iter(values))?;
+    }
+    Ok(())
+}
+
+// Appends the backup's audit entries that the log does not hold yet, compared on everything but
+// their ids. The ids in a backup taken from another database belong to that database's log, so
+// the entries get new ones here and note the backup and id they came from in imported_from.
+fn append_audit_entries(conn: &Connection, rows: &[Map<String, serde_json::Value>], exported_at: &str) -> Result<()> {
+    let matches = AUDIT_ENTRY_COLUMNS
+        .iter()
+        .enumerate()
+        .map(|(i, column)| format!("{} IS ?{}", column, i + 1))
+        .collect::<Vec<_>>()
+        .join(" AND ");
+    let mut present = conn.prepare(&format!("SELECT EXISTS (SELECT 1 FROM audit_log WHERE {})", matches))?;
+    let placeholders: Vec<String> = (1..=AUDIT_ENTRY_COLUMNS.len() + 1).map(|i| format!("?{}", i)).collect();
+    let mut insert = conn.prepare(&format!(
+        "INSERT INTO audit_log ({}, imported_from) VALUES ({})",
+        AUDIT_ENTRY_COLUMNS.join(", "),
+        placeholders.join(", ")
+    ))?;
+
+    for row in rows {
+        let entry: Vec<Value> = AUDIT_ENTRY_COLUMNS
+            .iter()
+            .map(|column| row.get(*column).map(from_json).unwrap_or(Value::Null))
+            .collect();
+        if present.query_row(params_from_iter(entry.iter()), |found| found.get(0))? {
+            continue;
+        }
+        let origin = match row.get("id").and_then(|id| id.as_i64()) {
+            Some(id) => format!("backup exported {}, entry {}", exported_at, id),
+            None => format!("backup exported {}", exported_at),
+        };
+        insert.execute(params_from_iter(entry.into_iter().chain(std::iter::once(Value::Text(origin)))))?;
+    }
+    Ok(())
+}
+
+// Loads a backup taken by an older version into a scratch database and migrates it there the way
+// setup_db migrates a live database, so its dates and generated references compare equal to the
+// ones in the ledger and a merge does not add its rows a second time.
+fn upgrade(backup: Backup) -> Result<Backup, AppError> {
+    if backup.version >= BACKUP_VERSION {
+        return Ok(backup);
+    }
+    // The audit log is carried over as it is; the entries the migration writes in the scratch
+    // database are not part of the backup.
+    let migrated = |table: &String| table != APPEND_ONLY_TABLE && BACKUP_TABLES.iter().any(|(known, _)| known == table);
+    let scratch = setup_db(Connection::open_in_memory()?)?;
+    // Settings without a ledger_format (version 1) read as the oldest format.
+    scratch.execute("UPDATE app_settings SET ledger_format = 0 WHERE id = 1", [])?;
+    for (table, rows) in backup.tables.iter().filter(|(table, _)| migrated(table)) {
+        scratch.execute(&format!("DELETE FROM {}", table), [])?;
+        restore_rows(&scratch, table, rows, true)?;
+    }
+    migrate_ledger_format(&scratch)?;
+
+    let mut tables = BTreeMap::new();
+    for (table, rows) in backup.tables {
+        if migrated(&table) {
+            let rows = dump_table(&scratch, &table)?;
+            tables.insert(table, rows);
+        } else if table == APPEND_ONLY_TABLE {
+            tables.insert(table, rows);
+        }
+    }
+    Ok(Backup { version: BACKUP_VERSION, exported_at: backup.exported_at, tables })
+}
+
+#[tauri::command]
+pub fn export_backup(path: String) -> Result<String, AppError> {
+    let conn = connect_and_setup_db()?;
+
+    let mut tables = BTreeMap::new();
+    for (table, _) in BACKUP_TABLES {
+        tables.insert(table.to_string(), dump_table(&conn, table)?);
+    }
+
+    let backup = Backup {
+        version: BACKUP_VERSION,
+        exported_at: timestamp::now_stored(),
+        tables,
+    };
+
+    std::fs::write(&path, serde_json::to_string_pretty(&backup)?)?;
+    Ok(path)
+}
+
+/// Restores a backup. An import that would reopen a closed fiscal year, or add, change or remove
+/// ledger rows that could alter one, is refused unless `override_closed_period` is set.
+#[tauri::command]
+pub fn import_backup(path: String, mode: RestoreMode, override_closed_period: Option<bool>) -> Result<(), AppError> {
+    let backup: Backup = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+    if backup.version > BACKUP_VERSION {
+        return Err(AppError::BackupError(format!(
+            "backup version {} is newer than the supported version {}",
+            backup.version, BACKUP_VERSION
+        )));
+    }
+    let backup = upgrade(backup)?;
+
+    snapshots::take_snapshot("import_backup")?;
+    let mut conn = connect_and_setup_db()?;
+    let tx = conn.transaction()?;
+    let guard = periods::RestoreGuard::new(&tx)?;
+    let ledger_before = audit::ledger_images(&tx)?;
+    let settings_before = audit::settings_image(&tx)?;
+    for (table, mergeable) in BACKUP_TABLES {
+        let rows = match backup.tables.get(*table) {
+            Some(rows) => rows,
+            None => continue,
+        };
+        match mode {
+            RestoreMode::Replace if *table == APPEND_ONLY_TABLE => append_audit_entries(&tx, rows, &backup.exported_at)?,
+            RestoreMode::Replace => {
+                tx.execute(&format!("DELETE FROM {}", table), [])?;
+                restore_rows(&tx, table, rows, true)?;
+            }
+            RestoreMode::Merge if *mergeable => restore_rows(&tx, table, rows, false)?,
+            RestoreMode::Merge => {}
+        }
+    }
+    guard.check(&tx, override_closed_period.unwrap_or(false))?;
+    audit::record_ledger_changes(&tx, "import_backup", &ledger_before)?;
+    audit::record_settings_change(&tx, "import_backup", settings_before)?;
+    tx.commit()?;
+    drop(conn);
+
+    // The guard has checked the years that were closed before the import. The lots still follow
+    // the ledger that was there before, so the replay is not checked against them: a replace can
+    // bring in years that were closed on the restored ledger, which the replay rebuilds as filed.
+    replay_ledger(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{day, test_db};
+    use crate::{db_path, record_purchase, show_all_transactions, use_tao};
+
+    #[test]
+    fn version_1_backups_merge_without_duplicating_the_ledger() {
+        let _db = test_db();
+        record_purchase(10.0, 5.0, day(2024, 1, 1), None, None, None, None).unwrap();
+        let path = db_path().with_file_name("v1.json");
+        let v1 = serde_json::json!({
+            "version": 1,
+            "exported_at": "2024-06-01 00:00:00",
+            "tables": {
+                "app_settings": [{"id": 1, "usage_type": 1}],
+                "all_transactions": [
+                    {"id": 1, "quantity": 10.0, "price_per_ton": 5.0, "purchase_date": "2024-01-01 00:00:00", "is_used": 0, "external_ref": "hash:0123456789abcdef"},
+                    {"id": 2, "quantity": 4.0, "sell_price": 9.0, "liquidation_date": "2024-03-01 12:00:00", "is_used": 1, "external_ref": "hash:fedcba9876543210"}
+                ]
+            }
+        });
+        std::fs::write(&path, v1.to_string()).unwrap();
+
+        import_backup(path.to_string_lossy().into_owned(), RestoreMode::Merge, None).unwrap();
+        let all = show_all_transactions().unwrap();
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[1].liquidation_date.as_deref(), Some("2024-03-01T12:00:00Z"));
+        import_backup(path.to_string_lossy().into_owned(), RestoreMode::Merge, None).unwrap();
+        assert_eq!(show_all_transactions().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn imports_that_could_alter_a_closed_year_need_an_override() {
+        let _db = test_db();
+        let backup = |name: &str| export_backup(db_path().with_file_name(name).to_string_lossy().into_owned()).unwrap();
+        record_purchase(10.0, 5.0, day(2022, 1, 1), None, None, None, None).unwrap();
+        use_tao(5.0, day(2022, 7, 1), 10.0, None, None, None).unwrap();
+        let before_closing = backup("before_closing.json");
+        periods::close_period(2022).unwrap();
+        record_purchase(1.0, 6.0, day(2024, 1, 1), None, None, None, None).unwrap();
+        let after_closing = backup("after_closing.json");
+
+        record_purchase(2.0, 7.0, day(2024, 2, 1), None, None, None, None).unwrap();
+        import_backup(after_closing, RestoreMode::Replace, None).unwrap();
+        assert_eq!(show_all_transactions().unwrap().len(), 3);
+
+        assert!(matches!(import_backup(before_closing.clone(), RestoreMode::Replace, None), Err(AppError::PeriodLock(_))));
+        assert_eq!(show_all_transactions().unwrap().len(), 3);
+        assert_eq!(periods::list_closed_periods().unwrap().len(), 1);
+        import_backup(before_closing, RestoreMode::Replace, Some(true)).unwrap();
+        assert_eq!(show_all_transactions().unwrap().len(), 2);
+        assert!(periods::list_closed_periods().unwrap().is_empty());
+    }
+
+    #[test]
+    fn backups_carry_the_audit_log_and_imports_only_add_to_it() {
+        let _db = test_db();
+        record_purchase(10.0, 5.0, day(2024, 1, 1), None, None, None, None).unwrap();
+        let path = export_backup(db_path().with_file_name("backup.json").to_string_lossy().into_owned()).unwrap();
+        let backup: Backup = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(backup.tables["audit_log"].len(), 1);
+
+        record_purchase(1.0, 6.0, day(2024, 2, 1), None, None, None, None).unwrap();
+        let conn = connect_and_setup_db().unwrap();
+        let logged = || dump_table(&conn, "audit_log").unwrap();
+        let before = logged();
+        import_backup(path.clone(), RestoreMode::Replace, None).unwrap();
+        let after = logged();
+        // Nothing is taken out of the log; the replace is recorded after what was there.
+        assert_eq!(after[..before.len()], before[..]);
+        assert_eq!(after[before.len()]["command"], "import_backup");
+        assert_eq!(after[before.len()]["action"], "Remove");
+
+        // A merge brings in ledger rows, not the other database's history of them.
+        let logged_before_merge = logged().len();
+        import_backup(path, RestoreMode::Merge, None).unwrap();
+        assert_eq!(logged().len(), logged_before_merge);
+    }
+
+    #[test]
+    fn audit_entries_from_another_database_get_new_ids_and_are_added_once() {
+        let other = {
+            let _other_db = test_db();
+            record_purchase(10.0, 5.0, day(2024, 1, 1), None, None, None, None).unwrap();
+            record_purchase(2.0, 7.0, day(2024, 3, 1), None, None, None, None).unwrap();
+            let path = export_backup(db_path().with_file_name("backup.json").to_string_lossy().into_owned()).unwrap();
+            std::fs::read_to_string(path).unwrap()
+        };
+        let other_log: Backup = serde_json::from_str(&other).unwrap();
+        let other_log = &other_log.tables["audit_log"];
+
+        let _db = test_db();
+        record_purchase(1.0, 6.0, day(2023, 6, 1), None, None, None, None).unwrap();
+        let path = db_path().with_file_name("other.json").to_string_lossy().into_owned();
+        std::fs::write(&path, &other).unwrap();
+        let conn = connect_and_setup_db().unwrap();
+        let logged = || dump_table(&conn, "audit_log").unwrap();
+        let before = logged();
+        import_backup(path.clone(), RestoreMode::Replace, None).unwrap();
+        let after = logged();
+
+        // The local history stays as it was, and every entry of the other log follows it under a
+        // new id, noting the id it had there.
+        assert_eq!(after[..before.len()], before[..]);
+        for entry in other_log {
+            let imported: Vec<_> = after
+                .iter()
+                .filter(|logged| AUDIT_ENTRY_COLUMNS.iter().all(|column| logged[*column] == entry[*column]))
+                .collect();
+            assert_eq!(imported.len(), 1);
+            assert!(imported[0]["id"].as_i64().unwrap() > before.len() as i64);
+            let origin = imported[0]["imported_from"].as_str().unwrap();
+            assert!(origin.ends_with(&format!("entry {}", entry["id"])), "{}", origin);
+        }
+
+        // Importing the same backup again finds those entries already in the log.
+        let logged_before_again = logged().len();
+        import_backup(path, RestoreMode::Replace, None).unwrap();
+        let again = logged();
+        assert!(again[logged_before_again..].iter().all(|entry| entry["command"] == "import_backup"));
+        assert!(again[logged_before_again..].iter().all(|entry| entry["imported_from"].is_null()));
+    }
+}
@@ -0,0 +1,384 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use rusqlite::Connection;
+use time::UtcOffset;
+
+use crate::reports::{fiscal_year, load_fiscal_year_start};
+use crate::timestamp::{load_display_offset, parse_stored};
+use crate::{connect_and_setup_db, transaction_from_row, AppError};
+
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+pub enum CsvReport {
+    OpenLots,
+    Disposals,
+    Ledger,
+    Holdings,
+    Summary,
+    TaxYear(i32),
+}
+
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+#[serde(default)]
+pub struct CsvOptions {
+    pub delimiter: char,
+    pub decimal_separator: char,
+    pub date_format: String, // %Y, %m, %d, %H, %M and %S are replaced, anything else is kept
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        CsvOptions {
+            delimiter: ',',
+            decimal_separator: '.',
+            date_format: "%Y-%m-%d %H:%M:%S".to_string(),
+        }
+    }
+}
+
+/// Writes delimited records one at a time, so callers can stream rows straight from a query.
+pub struct CsvWriter<W: Write> {
+    out: W,
+    options: CsvOptions,
+    display_offset: UtcOffset, // dates are written in the portfolio's display timezone
+}
+
+impl<W: Write> CsvWriter<W> {
+    pub fn new(out: W, options: CsvOptions, display_offset: UtcOffset) -> Self {
+        CsvWriter { out, options, display_offset }
+    }
+
+    pub fn number(&self, value: f64) -> String {
+        let formatted = value.to_string();
+        if self.options.decimal_separator == '.' {
+            formatted
+        } else {
+            formatted.replace('.', &self.options.decimal_separator.to_string())
+        }
+    }
+
+    pub fn optional_number(&self, value: Option<f64>) -> String {
+        value.map(|v| self.number(v)).unwrap_or_default()
+    }
+
+    // Stored dates that do not parse are written unchanged rather than dropped.
+    pub fn date(&self, value: &str) -> String {
+        match parse_stored(value).map(|instant| instant.to_offset(self.display_offset)) {
+            Ok(date) => self
+                .options
+                .date_format
+                .replace("%Y", &format!("{:04}", date.year()))
+                .replace("%m", &format!("{:02}", date.month() as u8))
+                .replace("%d", &format!("{:02}", date.day()))
+                .replace("%H", &format!("{:02}", date.hour()))
+                .replace("%M", &format!("{:02}", date.minute()))
+                .replace("%S", &format!("{:02}", date.second())),
+            Err(_) => value.to_string(),
+        }
+    }
+
+    pub fn optional_date(&self, value: Option<&str>) -> String {
+        value.map(|v| self.date(v)).unwrap_or_default()
+    }
+
+    pub fn write_record<S: AsRef<str>>(&mut self, fields: &[S]) -> std::io::Result<()> {
+        let delimiter = self.options.delimiter;
+        for (index, field) in fields.iter().enumerate() {
+            if index > 0 {
+                write!(self.out, "{}", delimiter)?;
+            }
+            let field = field.as_ref();
+            if field.contains(delimiter) || field.contains(['"', '\n', '\r']) {
+                write!(self.out, "\"{}\"", field.replace('"', "\"\""))?;
+            } else {
+                write!(self.out, "{}", field)?;
+            }
+        }
+        write!(self.out, "\r\n")
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+}
+
+fn write_open_lots<W: Write>(conn: &Connection, csv: &mut CsvWriter<W>) -> Result<(), AppError> {
+    csv.write_record(&["ID", "Quantity", "Price", "Purchase Date", "Cost Basis"])?;
+    let mut stmt = conn.prepare("SELECT id, quantity, price_per_ton, purchase_date, acquisition_value FROM timber_purchases ORDER BY purchase_date, id")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let record = [
+            row.get::<_, i64>(0)?.to_string(),
+            csv.number(row.get(1)?),
+            csv.number(row.get(2)?),
+            csv.date(&row.get::<_, String>(3)?),
+            csv.number(row.get(4)?),
+        ];
+        csv.write_record(&record)?;
+    }
+    Ok(())
+}
+
+// `year` restricts the export to disposals whose liquidation date falls in that fiscal year.
+fn write_disposals<W: Write>(conn: &Connection, csv: &mut CsvWriter<W>, year: Option<i32>) -> Result<(), AppError> {
+    let fiscal_year_start = load_fiscal_year_start(conn)?;
+    csv.write_record(&["ID", "Quantity", "Orig Price", "Selling Price", "Liquidation Date", "Cost Basis", "Proceeds", "Gain/Loss"])?;
+    let mut stmt = conn.prepare(
+        "SELECT id, quantity, orig_price, sell_price, liquidation_date, orig_value, sell_value FROM used_timber ORDER BY liquidation_date, id",
+    )?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let liquidation_date: String = row.get(4)?;
+        if year.is_some() && fiscal_year(&liquidation_date, &fiscal_year_start, csv.display_offset) != year {
+            continue;
+        }
+        let orig_value: f64 = row.get(5)?;
+        let sell_value: f64 = row.get(6)?;
+        let record = [
+            row.get::<_, i64>(0)?.to_string(),
+            csv.number(row.get(1)?),
+            csv.number(row.get(2)?),
+            csv.number(row.get(3)?),
+            csv.date(&liquidation_date),
+            csv.number(orig_value),
+            csv.number(sell_value),
+            csv.number(sell_value - orig_value),
+        ];
+        csv.write_record(&record)?;
+    }
+    Ok(())
+}
+
+fn write_ledger<W: Write>(conn: &Connection, csv: &mut CsvWriter<W>) -> Result<(), AppError> {
+    csv.write_record(&["ID", "Type", "Quantity", "Price Per Ton", "Orig Price", "Sell Price", "Purchase Date", "Liquidation Date", "External Ref"])?;
+    let mut stmt = conn.prepare("SELECT * FROM all_transactions ORDER BY id")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let txn = transaction_from_row(row)?;
+        let kind = if txn.is_used { "Sale" } else if txn.is_reward { "Reward" } else { "Purchase" };
+        let record = [
+            txn.id.to_string(),
+            kind.to_string(),
+            csv.number(txn.quantity),
+            csv.optional_number(txn.price_per_ton),
+            csv.optional_number(txn.orig_price),
+            csv.optional_number(txn.sell_price),
+            csv.optional_date(txn.purchase_date.as_deref()),
+            csv.optional_date(txn.liquidation_date.as_deref()),
+            txn.external_ref.unwrap_or_default(),
+        ];
+        csv.write_record(&record)?;
+    }
+    Ok(())
+}
+
+// Running balance after every ledger row, computed while reading so the ledger is never held in
+// memory as a whole.
+fn write_holdings<W: Write>(conn: &Connection, csv: &mut CsvWriter<W>) -> Result<(), AppError> {
+    csv.write_record(&["Date", "Quantity Held"])?;
+    let mut stmt = conn.prepare(
+        "SELECT CASE WHEN is_used THEN liquidation_date ELSE purchase_date END AS effective_date, quantity, is_used
+         FROM all_transactions WHERE effective_date IS NOT NULL ORDER BY effective_date, id",
+    )?;
+    let mut rows = stmt.query([])?;
+    let mut held = 0.0;
+    while let Some(row) = rows.next()? {
+        let quantity: f64 = row.get(1)?;
+        held = if row.get::<_, bool>(2)? { (held - quantity).max(0.0) } else { held + quantity };
+        let record = [csv.date(&row.get::<_, String>(0)?), csv.number(held)];
+        csv.write_record(&record)?;
+    }
+    Ok(())
+}
+
+// Aggregated in SQL for the same reason.
+fn write_summary<W: Write>(conn: &Connection, csv: &mut CsvWriter<W>) -> Result<(), AppError> {
+    let (open_quantity, open_cost_basis): (f64, f64) = conn.query_row(
+        "SELECT COALESCE(SUM(quantity), 0), COALESCE(SUM(acquisition_value), 0) FROM timber_purchases",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    let (proceeds, realized_cost_basis): (f64, f64) = conn.query_row(
+        "SELECT COALESCE(SUM(sell_value), 0), COALESCE(SUM(orig_value), 0) FROM used_timber",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    let reward_income: f64 = conn.query_row(
+        "SELECT COALESCE(SUM(quantity * price_per_ton), 0) FROM all_transactions WHERE is_reward AND NOT is_used",
+        [],
+        |row| row.get(0),
+    )?;
+
+    csv.write_record(&["Measure", "Value"])?;
+    for (label, value) in [
+        ("Quantity Held", open_quantity),
+        ("Cost Basis of Holdings", open_cost_basis),
+        ("Proceeds", proceeds),
+        ("Cost Basis of Disposals", realized_cost_basis),
+        ("Realized Gain/Loss", proceeds - realized_cost_basis),
+        ("Reward Income", reward_income),
+    ] {
+        let record = [label.to_string(), csv.number(value)];
+        csv.write_record(&record)?;
+    }
+    Ok(())
+}
+
+/// Exports one table or report to `path` and returns the path. Table exports stream rows from
+/// SQLite into a buffered file writer.
+#[tauri::command]
+pub fn export_csv(report: CsvReport, path: String, options: Option<CsvOptions>) -> Result<String, AppError> {
+    let conn = connect_and_setup_db()?;
+    let mut csv = CsvWriter::new(BufWriter::new(File::create(&path)?), options.unwrap_or_default(), load_display_offset(&conn)?);
+
+    match report {
+        CsvReport::OpenLots => write_open_lots(&conn, &mut csv)?,
+        CsvReport::Disposals => write_disposals(&conn, &mut csv, None)?,
+        CsvReport::Ledger => write_ledger(&conn, &mut csv)?,
+        CsvReport::Holdings => write_holdings(&conn, &mut csv)?,
+        CsvReport::Summary => write_summary(&conn, &mut csv)?,
+        CsvReport::TaxYear(year) => write_disposals(&conn, &mut csv, Some(year))?,
+    }
+    csv.flush()?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{day, test_db};
+    use crate::timestamp::DateTime;
+    use crate::{db_path, record_purchase, use_tao};
+
+    const REFERENCE: &str = r#"INV;7, "b""#;
+
+    // Splits CSV text back into fields, undoing the quoting write_record applies.
+    fn parse(text: &str, delimiter: char) -> Vec<Vec<String>> {
+        let mut records = Vec::new();
+        let (mut record, mut field) = (Vec::new(), String::new());
+        let mut chars = text.chars().peekable();
+        let mut quoted = false;
+        while let Some(c) = chars.next() {
+            match c {
+                '"' if quoted && chars.peek() == Some(&'"') => {
+                    field.push('"');
+                    chars.next();
+                }
+                '"' => quoted = !quoted,
+                c if quoted => field.push(c),
+                c if c == delimiter => record.push(std::mem::take(&mut field)),
+                '\r' => {}
+                '\n' => {
+                    record.push(std::mem::take(&mut field));
+                    records.push(std::mem::take(&mut record));
+                }
+                c => field.push(c),
+            }
+        }
+        records
+    }
+
+    fn options(delimiter: char, decimal_separator: char, date_format: &str) -> CsvOptions {
+        CsvOptions { delimiter, decimal_separator, date_format: date_format.to_string() }
+    }
+
+    // The expected figures below use a decimal point and stored dates; this writes them the way
+    // the given options do. "*" stands for a field the test does not check.
+    fn localize(field: &str, options: &CsvOptions) -> String {
+        let csv = CsvWriter::new(Vec::new(), options.clone(), UtcOffset::UTC);
+        if field == "*" || field == REFERENCE {
+            field.to_string()
+        } else if parse_stored(field).is_ok() {
+            csv.date(field)
+        } else if let Ok(value) = field.parse::<f64>() {
+            csv.number(value)
+        } else {
+            field.to_string()
+        }
+    }
+
+    #[test]
+    fn every_report_reads_back_with_each_format() {
+        let _db = test_db();
+        record_purchase(10.0, 5.5, day(2024, 1, 1), Some(REFERENCE.to_string()), None, None, None).unwrap();
+        record_purchase(4.0, 8.25, day(2024, 3, 1), None, None, None, None).unwrap();
+        let sold_at = DateTime::Fields { year: 2024, month: 6, day: 15, hour: 9, minute: 30, second: 5 };
+        use_tao(5.0, sold_at, 10.5, None, None, None).unwrap();
+
+        let disposals: &[&[&str]] = &[
+            &["ID", "Quantity", "Orig Price", "Selling Price", "Liquidation Date", "Cost Basis", "Proceeds", "Gain/Loss"],
+            &["*", "5", "5.5", "10.5", "2024-06-15T09:30:05Z", "27.5", "52.5", "25"],
+        ];
+        let reports: Vec<(CsvReport, &[&[&str]])> = vec![
+            (CsvReport::OpenLots, &[
+                &["ID", "Quantity", "Price", "Purchase Date", "Cost Basis"],
+                &["*", "5", "5.5", "2024-01-01T00:00:00Z", "27.5"],
+                &["*", "4", "8.25", "2024-03-01T00:00:00Z", "33"],
+            ]),
+            (CsvReport::Disposals, disposals),
+            (CsvReport::TaxYear(2024), disposals),
+            (CsvReport::TaxYear(2023), &disposals[..1]),
+            (CsvReport::Ledger, &[
+                &["ID", "Type", "Quantity", "Price Per Ton", "Orig Price", "Sell Price", "Purchase Date", "Liquidation Date", "External Ref"],
+                &["1", "Purchase", "10", "5.5", "", "", "2024-01-01T00:00:00Z", "", REFERENCE],
+                &["2", "Purchase", "4", "8.25", "", "", "2024-03-01T00:00:00Z", "", "*"],
+                &["3", "Sale", "5", "", "", "10.5", "", "2024-06-15T09:30:05Z", "*"],
+            ]),
+            (CsvReport::Holdings, &[
+                &["Date", "Quantity Held"],
+                &["2024-01-01T00:00:00Z", "10"],
+                &["2024-03-01T00:00:00Z", "14"],
+                &["2024-06-15T09:30:05Z", "9"],
+            ]),
+            (CsvReport::Summary, &[
+                &["Measure", "Value"],
+                &["Quantity Held", "9"],
+                &["Cost Basis of Holdings", "60.5"],
+                &["Proceeds", "52.5"],
+                &["Cost Basis of Disposals", "27.5"],
+                &["Realized Gain/Loss", "25"],
+                &["Reward Income", "0"],
+            ]),
+        ];
+        let formats = [
+            CsvOptions::default(),
+            options(';', ',', "%d.%m.%Y %H:%M"),
+            // A decimal comma in a comma-delimited file has to be quoted.
+            options(',', ',', "%m/%d/%Y"),
+            options('\t', '.', "%Y%m%d"),
+        ];
+
+        let path = db_path().with_file_name("report.csv").to_string_lossy().into_owned();
+        for format in &formats {
+            for (report, expected) in &reports {
+                export_csv(report.clone(), path.clone(), Some(format.clone())).unwrap();
+                let read = parse(&std::fs::read_to_string(&path).unwrap(), format.delimiter);
+                assert_eq!(read.len(), expected.len(), "{:?} with {:?}", report, format);
+                for (row, expected) in read.iter().zip(expected.iter()) {
+                    assert_eq!(row.len(), expected.len(), "{:?} with {:?}", report, format);
+                    for (field, expected) in row.iter().zip(expected.iter()) {
+                        if *expected != "*" {
+                            assert_eq!(*field, localize(expected, format), "{:?} with {:?}", report, format);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fields_are_formatted_as_configured() {
+        let european = CsvWriter::new(Vec::new(), options(';', ',', "%d.%m.%Y %H:%M:%S"), UtcOffset::UTC);
+        assert_eq!(european.number(1234.5), "1234,5");
+        assert_eq!(european.date("2024-06-15T09:30:05Z"), "15.06.2024 09:30:05");
+        assert_eq!(european.date("not a date"), "not a date");
+
+        // Dates are written in the display timezone, which can move them to another day.
+        let ahead = CsvWriter::new(Vec::new(), options(',', '.', "%Y-%m-%d %H:%M"), UtcOffset::from_hms(2, 0, 0).unwrap());
+        assert_eq!(ahead.date("2024-12-31T23:30:00Z"), "2025-01-01 01:30");
+
+        let mut out = CsvWriter::new(Vec::new(), options(';', ',', "%Y"), UtcOffset::UTC);
+        out.write_record(&["a;b", "1,5", "say \"hi\"", "two\nlines"]).unwrap();
+        assert_eq!(String::from_utf8(out.out).unwrap(), "\"a;b\";1,5;\"say \"\"hi\"\"\";\"two\nlines\"\r\n");
+    }
+}
@@ -0,0 +1,143 @@
+use rusqlite::{params, Connection, OptionalExtension, Result};
+
+use crate::{audit, connect_and_setup_db, mark_changed_at, periods, replay_changes, snapshots, transaction_from_row, AllTransactions, AppError};
+
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+pub struct DuplicateCandidate {
+    id: i32,
+    transaction: AllTransactions,
+    duplicate_of: AllTransactions,
+}
+
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+pub enum DuplicateResolution {
+    Merge,   // the flagged row is a duplicate: delete it and keep the earlier one
+    Discard, // the rows are genuinely separate transactions: drop the flag and keep both
+}
+
+fn fetch_transaction(conn: &Connection, id: i32) -> Result<AllTransactions> {
+    conn.query_row("SELECT * FROM all_transactions WHERE id = ?1", params![id], transaction_from_row)
+}
+
+// Flags pairs of ledger rows of the same kind (purchase, reward or sale) with equal quantity and
+// price whose effective timestamps (purchase_date for purchases, liquidation_date for sales) are
+// within tolerance_seconds of each other, then returns every candidate still awaiting review.
+// Pairs that were previously discarded stay discarded.
+#[tauri::command]
+pub fn find_duplicate_transactions(tolerance_seconds: i64) -> Result<Vec<DuplicateCandidate>, AppError> {
+    let conn = connect_and_setup_db()?;
+
+    conn.execute(
+        "DELETE FROM duplicate_candidates
+         WHERE transaction_id NOT IN (SELECT id FROM all_transactions)
+            OR duplicate_of_id NOT IN (SELECT id FROM all_transactions)",
+        [],
+    )?;
+    conn.execute(
+        "INSERT OR IGNORE INTO duplicate_candidates (transaction_id, duplicate_of_id)
+         SELECT b.id, a.id
+         FROM all_transactions a
+         JOIN all_transactions b ON a.id < b.id AND a.is_used = b.is_used AND a.is_reward = b.is_reward
+         WHERE ABS(a.quantity - b.quantity) <= 1e-9
+           AND ABS(COALESCE(CASE WHEN a.is_used THEN a.sell_price ELSE a.price_per_ton END, 0)
+                 - COALESCE(CASE WHEN b.is_used THEN b.sell_price ELSE b.price_per_ton END, 0)) <= 1e-9
+           AND ABS(strftime('%s', CASE WHEN a.is_used THEN a.liquidation_date ELSE a.purchase_date END)
+                 - strftime('%s', CASE WHEN b.is_used THEN b.liquidation_date ELSE b.purchase_date END)) <= ?1",
+        params![tolerance_seconds],
+    )?;
+
+    let mut stmt = conn.prepare(
+        "SELECT id, transaction_id, duplicate_of_id FROM duplicate_candidates WHERE status = 'pending' ORDER BY duplicate_of_id, transaction_id",
+    )?;
+    let candidate_iter = stmt.query_map([], |row| {
+        Ok((row.get::<_, i32>(0)?, row.get::<_, i32>(1)?, row.get::<_, i32>(2)?))
+    })?;
+
+    let mut candidates = Vec::new();
+    for candidate in candidate_iter {
+        let (id, transaction_id, duplicate_of_id) = candidate?;
+        candidates.push(DuplicateCandidate {
+            id,
+            transaction: fetch_transaction(&conn, transaction_id)?,
+            duplicate_of: fetch_transaction(&conn, duplicate_of_id)?,
+        });
+    }
+    Ok(candidates)
+}
+
+#[tauri::command]
+pub fn resolve_duplicate(candidate_id: i32, resolution: DuplicateResolution, override_closed_period: Option<bool>) -> Result<(), AppError> {
+    let conn = connect_and_setup_db()?;
+    let (transaction_id, duplicate_of_id): (i32, i32) = conn.query_row(
+        "SELECT transaction_id, duplicate_of_id FROM duplicate_candidates WHERE id = ?1 AND status = 'pending'",
+        params![candidate_id],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+
+    match resolution {
+        DuplicateResolution::Discard => {
+            conn.execute("UPDATE duplicate_candidates SET status = 'discarded' WHERE id = ?1", params![candidate_id])?;
+        }
+        DuplicateResolution::Merge => {
+            let override_lock = override_closed_period.unwrap_or(false);
+            periods::ensure_row_open(&conn, transaction_id, override_lock)?;
+            snapshots::take_snapshot("resolve_duplicate")?;
+            let dropped_ref: Option<String> = conn
+                .query_row("SELECT external_ref FROM all_transactions WHERE id = ?1", params![transaction_id], |row| row.get(0))
+                .optional()?
+                .flatten();
+            let dropped = audit::ledger_image(&conn, transaction_id)?;
+            let survivor = audit::ledger_image(&conn, duplicate_of_id)?;
+            mark_changed_at(&conn, transaction_id)?;
+            conn.execute("DELETE FROM all_transactions WHERE id = ?1", params![transaction_id])?;
+            audit::record_change(&conn, "resolve_duplicate", "all_transactions", Some(transaction_id as i64), dropped.as_ref(), None)?;
+
+            // Keep an import's reference on the surviving row so re-running that import stays a no-op.
+            if let Some(external_ref) = dropped_ref.filter(|r| !r.starts_with("hash:")) {
+                conn.execute(
+                    "UPDATE all_transactions SET external_ref = ?1 WHERE id = ?2 AND (external_ref IS NULL OR external_ref LIKE 'hash:%')",
+                    params![external_ref, duplicate_of_id],
+                )?;
+            }
+            audit::record_change(
+                &conn,
+                "resolve_duplicate",
+                "all_transactions",
+                Some(duplicate_of_id as i64),
+                survivor.as_ref(),
+                audit::ledger_image(&conn, duplicate_of_id)?.as_ref(),
+            )?;
+
+            conn.execute("UPDATE duplicate_candidates SET status = 'merged' WHERE id = ?1", params![candidate_id])?;
+            conn.execute(
+                "DELETE FROM duplicate_candidates WHERE status = 'pending' AND (transaction_id = ?1 OR duplicate_of_id = ?1)",
+                params![transaction_id],
+            )?;
+            drop(conn);
+            replay_changes(override_lock)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record_purchase;
+    use crate::test_support::{day, test_db};
+    use crate::timestamp::DateTime;
+
+    #[test]
+    fn near_matches_of_the_same_kind_are_flagged() {
+        let _db = test_db();
+        record_purchase(10.0, 5.0, day(2024, 1, 1), None, None, None, None).unwrap();
+        record_purchase(10.0, 5.0, DateTime::Text("2024-01-01T00:00:30Z".into()), None, None, None, None).unwrap();
+        // A reward with the same figures is a different transaction, as it is to content_hash.
+        record_purchase(10.0, 5.0, day(2024, 1, 1), None, Some(true), None, None).unwrap();
+
+        assert!(find_duplicate_transactions(10).unwrap().is_empty());
+        let flagged: Vec<(i32, i32)> =
+            find_duplicate_transactions(60).unwrap().iter().map(|candidate| (candidate.transaction.id, candidate.duplicate_of.id)).collect();
+        assert_eq!(flagged, [(2, 1)]);
+    }
+}
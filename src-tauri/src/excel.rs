@@ -0,0 +1,390 @@
+use xlsxwriter::*;
+
+use rusqlite::Connection;
+
+use crate::reports::{build_portfolio_report, effective_date, fiscal_year, reward_income, with_lot_links, Disposal, PortfolioReport};
+use crate::{connect_and_setup_db, AllTransactions, AppError};
+
+const MONEY_FORMAT: &str = "$#,##0.00";
+const QUANTITY_FORMAT: &str = "#,##0.0000";
+
+struct Formats {
+    header: Format,
+    label: Format,
+    money: Format,
+    quantity: Format,
+    total_money: Format,
+    total_quantity: Format,
+}
+
+impl Formats {
+    fn new() -> Formats {
+        let mut header = Format::new();
+        header.set_bold();
+        let mut label = Format::new();
+        label.set_bold();
+        let mut money = Format::new();
+        money.set_num_format(MONEY_FORMAT);
+        let mut quantity = Format::new();
+        quantity.set_num_format(QUANTITY_FORMAT);
+        let mut total_money = Format::new();
+        total_money.set_bold().set_num_format(MONEY_FORMAT);
+        let mut total_quantity = Format::new();
+        total_quantity.set_bold().set_num_format(QUANTITY_FORMAT);
+        Formats { header, label, money, quantity, total_money, total_quantity }
+    }
+}
+
+// Zero-based column index to its spreadsheet letters (0 -> "A", 26 -> "AA").
+fn column_name(col: u16) -> String {
+    let mut n = col as u32 + 1;
+    let mut name = String::new();
+    while n > 0 {
+        name.insert(0, (b'A' + ((n - 1) % 26) as u8) as char);
+        n = (n - 1) / 26;
+    }
+    name
+}
+
+fn cell(row: u32, col: u16) -> String {
+    format!("{}{}", column_name(col), row + 1)
+}
+
+fn sheet_cell(sheet: &str, row: u32, col: u16) -> String {
+    format!("'{}'!{}", sheet, cell(row, col))
+}
+
+// SUM over a column of data rows; an empty table sums to a literal zero rather than a reversed range.
+fn sum_formula(col: u16, first_row: u32, last_row: u32) -> String {
+    if last_row < first_row {
+        "=0".to_string()
+    } else {
+        format!("=SUM({}:{})", cell(first_row, col), cell(last_row, col))
+    }
+}
+
+fn write_headers(sheet: &mut Worksheet, row: u32, headers: &[&str], formats: &Formats) -> Result<(), AppError> {
+    for (index, &header) in headers.iter().enumerate() {
+        sheet.write_string(row, index as u16, header, Some(&formats.header))?;
+    }
+    Ok(())
+}
+
+// Writes the open lots and returns the row holding the totals.
+fn write_open_lots(sheet: &mut Worksheet, report: &PortfolioReport, formats: &Formats) -> Result<u32, AppError> {
+    write_headers(sheet, 0, &["ID", "Quantity", "Price", "Purchase Date", "Cost Basis"], formats)?;
+    sheet.freeze_panes(1, 0);
+    sheet.set_column(3, 3, 20.0, None)?;
+    sheet.set_column(4, 4, 14.0, None)?;
+
+    let mut row = 0;
+    for lot in &report.open_lots {
+        row += 1;
+        sheet.write_number(row, 0, lot.id.into(), None)?;
+        sheet.write_number(row, 1, lot.quantity, Some(&formats.quantity))?;
+        sheet.write_number(row, 2, lot.price_per_ton, Some(&formats.money))?;
+        sheet.write_string(row, 3, &lot.purchase_date, None)?;
+        sheet.write_formula_num(row, 4, &format!("={}*{}", cell(row, 1), cell(row, 2)), Some(&formats.money), lot.quantity * lot.price_per_ton)?;
+    }
+
+    let total_row = row + 2;
+    sheet.write_string(total_row, 0, "Inventory Value", Some(&formats.label))?;
+    sheet.write_formula_num(total_row, 1, &sum_formula(1, 1, row), Some(&formats.total_quantity), report.summary.open_quantity)?;
+    sheet.write_formula_num(total_row, 4, &sum_formula(4, 1, row), Some(&formats.total_money), report.summary.open_cost_basis)?;
+    Ok(total_row)
+}
+
+// Writes a disposal table starting at `first_row` and returns the row holding the totals.
+fn write_disposals(sheet: &mut Worksheet, first_row: u32, disposals: &[&Disposal], formats: &Formats) -> Result<u32, AppError> {
+    write_headers(
+        sheet,
+        first_row,
+        &["ID", "Quantity", "Orig Price", "Selling Price", "Liquidation Date", "Cost Basis", "Proceeds", "Gain/Loss"],
+        formats,
+    )?;
+    sheet.set_column(4, 4, 20.0, None)?;
+    sheet.set_column(5, 7, 14.0, None)?;
+
+    let mut row = first_row;
+    for disposal in disposals {
+        row += 1;
+        sheet.write_number(row, 0, disposal.id.into(), None)?;
+        sheet.write_number(row, 1, disposal.quantity, Some(&formats.quantity))?;
+        sheet.write_number(row, 2, disposal.orig_price, Some(&formats.money))?;
+        sheet.write_number(row, 3, disposal.sell_price, Some(&formats.money))?;
+        sheet.write_string(row, 4, &disposal.liquidation_date, None)?;
+        sheet.write_formula_num(row, 5, &format!("={}*{}", cell(row, 1), cell(row, 2)), Some(&formats.money), disposal.cost_basis())?;
+        sheet.write_formula_num(row, 6, &format!("={}*{}", cell(row, 1), cell(row, 3)), Some(&formats.money), disposal.proceeds())?;
+        sheet.write_formula_num(row, 7, &format!("={}-{}", cell(row, 6), cell(row, 5)), Some(&formats.money), disposal.gain())?;
+    }
+
+    let total_row = row + 2;
+    let quantity: f64 = disposals.iter().map(|d| d.quantity).sum();
+    let cost_basis: f64 = disposals.iter().map(|d| d.cost_basis()).sum();
+    let proceeds: f64 = disposals.iter().map(|d| d.proceeds()).sum();
+    sheet.write_string(total_row, 0, "Total", Some(&formats.label))?;
+    sheet.write_formula_num(total_row, 1, &sum_formula(1, first_row + 1, row), Some(&formats.total_quantity), quantity)?;
+    sheet.write_formula_num(total_row, 5, &sum_formula(5, first_row + 1, row), Some(&formats.total_money), cost_basis)?;
+    sheet.write_formula_num(total_row, 6, &sum_formula(6, first_row + 1, row), Some(&formats.total_money), proceeds)?;
+    sheet.write_formula_num(total_row, 7, &sum_formula(7, first_row + 1, row), Some(&formats.total_money), proceeds - cost_basis)?;
+    Ok(total_row)
+}
+
+// Writes the raw ledger and returns the row holding the income total.
+fn write_all_transactions(sheet: &mut Worksheet, report: &PortfolioReport, formats: &Formats) -> Result<u32, AppError> {
+    write_headers(
+        sheet,
+        0,
+        &["ID", "Type", "Quantity", "Price Per Ton", "Orig Price", "Sell Price", "Purchase Date", "Liquidation Date", "External Ref", "Income"],
+        formats,
+    )?;
+    sheet.freeze_panes(1, 0);
+    sheet.set_column(6, 7, 20.0, None)?;
+    sheet.set_column(8, 8, 24.0, None)?;
+
+    let mut row = 0;
+    for txn in &report.transactions {
+        row += 1;
+        let kind = if txn.is_used { "Sale" } else if txn.is_reward { "Reward" } else { "Purchase" };
+        sheet.write_number(row, 0, txn.id.into(), None)?;
+        sheet.write_string(row, 1, kind, None)?;
+        sheet.write_number(row, 2, txn.quantity, Some(&formats.quantity))?;
+        for (col, price) in [(3, txn.price_per_ton), (4, txn.orig_price), (5, txn.sell_price)] {
+            if let Some(price) = price {
+                sheet.write_number(row, col, price, Some(&formats.money))?;
+            }
+        }
+        if let Some(date) = &txn.purchase_date {
+            sheet.write_string(row, 6, date, None)?;
+        }
+        if let Some(date) = &txn.liquidation_date {
+            sheet.write_string(row, 7, date, None)?;
+        }
+        if let Some(external_ref) = &txn.external_ref {
+            sheet.write_string(row, 8, external_ref, None)?;
+        }
+        if txn.is_reward && !txn.is_used {
+            sheet.write_formula_num(row, 9, &format!("={}*{}", cell(row, 2), cell(row, 3)), Some(&formats.money), reward_income(txn))?;
+        }
+    }
+
+    let total_row = row + 2;
+    sheet.write_string(total_row, 0, "Reward Income", Some(&formats.label))?;
+    sheet.write_formula_num(total_row, 9, &sum_formula(9, 1, row), Some(&formats.total_money), report.summary.reward_income)?;
+    Ok(total_row)
+}
+
+// The disposals and reward income that fall in fiscal year `year`, in report order.
+fn tax_year_rows(report: &PortfolioReport, year: i32) -> (Vec<&Disposal>, Vec<&AllTransactions>) {
+    let tax_year = |date: &str| fiscal_year(date, &report.fiscal_year_start, report.display_offset);
+    let disposals = report
+        .disposals
+        .iter()
+        .filter(|disposal| tax_year(&disposal.liquidation_date) == Some(year))
+        .collect();
+    let rewards = report
+        .transactions
+        .iter()
+        .filter(|txn| reward_income(txn) != 0.0 && effective_date(txn).and_then(tax_year) == Some(year))
+        .collect();
+    (disposals, rewards)
+}
+
+fn write_tax_year(sheet: &mut Worksheet, year: i32, report: &PortfolioReport, formats: &Formats) -> Result<(), AppError> {
+    let (disposals, rewards) = tax_year_rows(report, year);
+    sheet.write_string(0, 0, &format!("Disposals {}", year), Some(&formats.label))?;
+    let disposals_total = write_disposals(sheet, 1, &disposals, formats)?;
+    sheet.freeze_panes(2, 0);
+
+    let income_header = disposals_total + 3;
+    sheet.write_string(income_header, 0, &format!("Reward Income {}", year), Some(&formats.label))?;
+    write_headers(sheet, income_header + 1, &["ID", "Quantity", "Price", "Received", "Income"], formats)?;
+    let mut row = income_header + 1;
+    let mut income = 0.0;
+    for txn in rewards {
+        row += 1;
+        income += reward_income(txn);
+        sheet.write_number(row, 0, txn.id.into(), None)?;
+        sheet.write_number(row, 1, txn.quantity, Some(&formats.quantity))?;
+        sheet.write_number(row, 2, txn.price_per_ton.unwrap_or(0.0), Some(&formats.money))?;
+        sheet.write_string(row, 3, txn.purchase_date.as_deref().unwrap_or(""), None)?;
+        sheet.write_formula_num(row, 4, &format!("={}*{}", cell(row, 1), cell(row, 2)), Some(&formats.money), reward_income(txn))?;
+    }
+    let income_total = row + 2;
+    sheet.write_string(income_total, 0, "Total", Some(&formats.label))?;
+    sheet.write_formula_num(income_total, 4, &sum_formula(4, income_header + 2, row), Some(&formats.total_money), income)?;
+
+    let realized: f64 = disposals.iter().map(|d| d.gain()).sum();
+    let summary_row = income_total + 2;
+    sheet.write_string(summary_row, 0, "Realized Gain/Loss", Some(&formats.label))?;
+    sheet.write_formula_num(summary_row, 4, &format!("={}", cell(disposals_total, 7)), Some(&formats.total_money), realized)?;
+    sheet.write_string(summary_row + 1, 0, "Reward Income", Some(&formats.label))?;
+    sheet.write_formula_num(summary_row + 1, 4, &format!("={}", cell(income_total, 4)), Some(&formats.total_money), income)?;
+    Ok(())
+}
+
+// Writes the balance after every ledger row and charts it next to the data.
+fn write_holdings(workbook: &Workbook, sheet: &mut Worksheet, report: &PortfolioReport, formats: &Formats) -> Result<(), AppError> {
+    write_headers(sheet, 0, &["Date", "Quantity Held"], formats)?;
+    sheet.freeze_panes(1, 0);
+    sheet.set_column(0, 0, 20.0, None)?;
+
+    let mut row = 0;
+    for point in &report.holdings {
+        row += 1;
+        sheet.write_string(row, 0, &point.date, None)?;
+        sheet.write_number(row, 1, point.quantity, Some(&formats.quantity))?;
+    }
+
+    if row > 0 {
+        let mut chart = workbook.add_chart(ChartType::Line);
+        chart.add_series(
+            Some(&format!("='Holdings'!$A$2:$A${}", row + 1)),
+            Some(&format!("='Holdings'!$B$2:$B${}", row + 1)),
+        )?;
+        sheet.insert_chart(1, 3, &chart)?;
+    }
+    Ok(())
+}
+
+fn write_summary(sheet: &mut Worksheet, report: &PortfolioReport, totals: (u32, u32, u32), formats: &Formats) -> Result<(), AppError> {
+    let (open_total, used_total, ledger_total) = totals;
+    let summary = &report.summary;
+    sheet.set_column(0, 0, 28.0, None)?;
+    sheet.set_column(1, 1, 16.0, None)?;
+    write_headers(sheet, 0, &["Summary", ""], formats)?;
+
+    // Rows reference each other by position, so keep this list and the formulas below in step.
+    sheet.write_string(1, 0, "Quantity Held", Some(&formats.label))?;
+    sheet.write_formula_num(1, 1, &format!("={}", sheet_cell("Actual", open_total, 1)), Some(&formats.quantity), summary.open_quantity)?;
+    sheet.write_string(2, 0, "Cost Basis of Holdings", Some(&formats.label))?;
+    sheet.write_formula_num(2, 1, &format!("={}", sheet_cell("Actual", open_total, 4)), Some(&formats.money), summary.open_cost_basis)?;
+    sheet.write_string(3, 0, "Proceeds", Some(&formats.label))?;
+    sheet.write_formula_num(3, 1, &format!("={}", sheet_cell("Used", used_total, 6)), Some(&formats.money), summary.proceeds)?;
+    sheet.write_string(4, 0, "Cost Basis of Disposals", Some(&formats.label))?;
+    sheet.write_formula_num(4, 1, &format!("={}", sheet_cell("Used", used_total, 5)), Some(&formats.money), summary.realized_cost_basis)?;
+    sheet.write_string(5, 0, "Realized Gain/Loss", Some(&formats.label))?;
+    sheet.write_formula_num(5, 1, "=B4-B5", Some(&formats.total_money), summary.realized_gain)?;
+    sheet.write_string(6, 0, "Reward Income", Some(&formats.label))?;
+    sheet.write_formula_num(6, 1, &format!("={}", sheet_cell("All Transactions", ledger_total, 9)), Some(&formats.total_money), summary.reward_income)?;
+
+    sheet.write_string(8, 0, "Current Price", Some(&formats.label))?;
+    sheet.write_string(9, 0, "Market Value", Some(&formats.label))?;
+    sheet.write_string(10, 0, "Unrealized Gain/Loss", Some(&formats.label))?;
+    match (summary.current_price, summary.market_value, summary.unrealized_gain) {
+        (Some(price), Some(market_value), Some(unrealized_gain)) => {
+            sheet.write_number(8, 1, price, Some(&formats.money))?;
+            sheet.write_formula_num(9, 1, "=B2*B9", Some(&formats.money), market_value)?;
+            sheet.write_formula_num(10, 1, "=B10-B3", Some(&formats.total_money), unrealized_gain)?;
+        }
+        _ => {
+            sheet.write_string(8, 1, "n/a", None)?;
+            sheet.write_string(9, 1, "n/a", None)?;
+            sheet.write_string(10, 1, "n/a", None)?;
+        }
+    }
+    Ok(())
+}
+
+// The report the workbook is written from, with lots linked as for the PDF report.
+fn workbook_report(conn: &Connection, current_price: Option<f64>) -> Result<PortfolioReport, AppError> {
+    let linked = with_lot_links(conn)?;
+    Ok(build_portfolio_report(linked.as_ref().unwrap_or(conn), current_price)?)
+}
+
+// `path` comes from the frontend's save dialog; the written path is returned so it can be shown
+// to the user. Unrealized gains are only filled in when a current price is given.
+#[tauri::command]
+pub fn write_inventory_to_excel(path: String, current_price: Option<f64>) -> Result<String, AppError> {
+    let conn = connect_and_setup_db()?;
+    let report = workbook_report(&conn, current_price)?;
+    let formats = Formats::new();
+
+    // Create a new workbook. Nothing is written to disk until `close` is called.
+    let workbook = Workbook::new(&path)?;
+    let mut summary_sheet = workbook.add_worksheet(Some("Summary"))?;
+    let mut timber_sheet = workbook.add_worksheet(Some("Actual"))?;
+    let mut used_timber_sheet = workbook.add_worksheet(Some("Used"))?;
+    let mut ledger_sheet = workbook.add_worksheet(Some("All Transactions"))?;
+
+    let open_total = write_open_lots(&mut timber_sheet, &report, &formats)?;
+    let all_disposals: Vec<&Disposal> = report.disposals.iter().collect();
+    let used_total = write_disposals(&mut used_timber_sheet, 0, &all_disposals, &formats)?;
+    used_timber_sheet.freeze_panes(1, 0);
+    let ledger_total = write_all_transactions(&mut ledger_sheet, &report, &formats)?;
+    write_summary(&mut summary_sheet, &report, (open_total, used_total, ledger_total), &formats)?;
+
+    for &year in &report.tax_years {
+        let mut year_sheet = workbook.add_worksheet(Some(&format!("Tax Year {}", year)))?;
+        write_tax_year(&mut year_sheet, year, &report, &formats)?;
+    }
+
+    let mut holdings_sheet = workbook.add_worksheet(Some("Holdings"))?;
+    write_holdings(&workbook, &mut holdings_sheet, &report, &formats)?;
+
+    // Close the workbook. This is where the Excel file is actually written to disk.
+    workbook.close()?;
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reports::set_fiscal_year_start;
+    use crate::test_support::{day, test_db};
+    use crate::{record_purchase, use_tao};
+
+    #[test]
+    fn tax_year_sheets_take_disposals_and_rewards_by_fiscal_year() {
+        let _db = test_db();
+        // UK tax years run from 6 April, and are named after the year they end in.
+        set_fiscal_year_start(4, 6).unwrap();
+        record_purchase(10.0, 5.0, day(2023, 1, 1), None, None, None, None).unwrap();
+        record_purchase(1.0, 3.0, day(2024, 4, 5), None, Some(true), None, None).unwrap();
+        record_purchase(1.0, 4.0, day(2024, 4, 6), None, Some(true), None, None).unwrap();
+        use_tao(2.0, day(2024, 4, 5), 9.0, None, None, None).unwrap();
+        use_tao(3.0, day(2024, 4, 6), 9.0, None, None, None).unwrap();
+
+        let conn = connect_and_setup_db().unwrap();
+        let report = workbook_report(&conn, None).unwrap();
+        assert_eq!(report.tax_years, [2024, 2025]);
+        let rows = |year| {
+            let (disposals, rewards) = tax_year_rows(&report, year);
+            let sold: Vec<f64> = disposals.iter().map(|disposal| disposal.quantity).collect();
+            let rewarded: Vec<i32> = rewards.iter().map(|txn| txn.id).collect();
+            (sold, rewarded)
+        };
+        assert_eq!(rows(2024), (vec![2.0], vec![2]));
+        assert_eq!(rows(2025), (vec![3.0], vec![3]));
+        assert_eq!(rows(2023), (vec![], vec![]));
+    }
+
+    #[test]
+    fn workbook_figures_match_the_lot_tables() {
+        let _db = test_db();
+        record_purchase(10.0, 5.0, day(2024, 1, 1), None, None, None, None).unwrap();
+        record_purchase(4.0, 6.5, day(2024, 2, 1), None, None, None, None).unwrap();
+        record_purchase(0.5, 7.0, day(2024, 2, 15), None, Some(true), None, None).unwrap();
+        use_tao(12.0, day(2024, 3, 1), 9.0, None, None, None).unwrap();
+
+        let conn = connect_and_setup_db().unwrap();
+        let report = workbook_report(&conn, Some(8.0)).unwrap();
+        let lots: Vec<(i32, f64, f64)> = report.open_lots.iter().map(|lot| (lot.id, lot.quantity, lot.quantity * lot.price_per_ton)).collect();
+        let mut stmt = conn.prepare("SELECT id, quantity, acquisition_value FROM timber_purchases ORDER BY purchase_date, id").unwrap();
+        let live = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap();
+        assert_eq!(lots, live.collect::<rusqlite::Result<Vec<(i32, f64, f64)>>>().unwrap());
+
+        let sums = |sql: &str| conn.query_row(sql, [], |row| Ok((row.get::<_, f64>(0)?, row.get::<_, f64>(1)?))).unwrap();
+        let summary = &report.summary;
+        assert_eq!((summary.open_quantity, summary.open_cost_basis), sums("SELECT SUM(quantity), SUM(acquisition_value) FROM timber_purchases"));
+        assert_eq!((summary.proceeds, summary.realized_cost_basis), sums("SELECT SUM(sell_value), SUM(orig_value) FROM used_timber"));
+        assert_eq!((summary.open_quantity, summary.open_cost_basis), (2.5, 16.5));
+        assert_eq!((summary.market_value, summary.unrealized_gain), (Some(20.0), Some(3.5)));
+        assert_eq!(summary.reward_income, 3.5);
+
+        // The Holdings sheet ends on what is held.
+        let held: Vec<f64> = report.holdings.iter().map(|point| point.quantity).collect();
+        assert_eq!(held, [10.0, 14.0, 14.5, 2.5]);
+    }
+}
@@ -0,0 +1,242 @@
+use std::fs::File;
+use std::io::BufWriter;
+
+use rusqlite::Connection;
+use time::UtcOffset;
+
+use crate::csv_export::{CsvOptions, CsvWriter};
+use crate::reports::{disposals, fiscal_year, with_lot_links, Disposal, FiscalYearStart};
+use crate::timestamp::load_display_offset;
+use crate::{connect_and_setup_db, AppError};
+
+// Boxes for digital assets sold without an information return: from tax year 2025, when Form
+// 8949 gained boxes for digital assets, box I (short-term) and box L (long-term) for sales not
+// reported on a Form 1099-DA; before that, box C and box F for sales without a Form 1099-B.
+const DIGITAL_ASSET_BOXES_FROM: i32 = 2025;
+
+fn form_boxes(year: i32) -> (&'static str, &'static str) {
+    if year >= DIGITAL_ASSET_BOXES_FROM {
+        ("I", "L")
+    } else {
+        ("C", "F")
+    }
+}
+
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+pub struct Form8949Line {
+    description: String,
+    date_acquired: String,
+    date_sold: String,
+    proceeds: f64,
+    cost_basis: f64,
+    adjustment_code: String,
+    adjustment: f64,
+    gain_loss: f64,
+}
+
+#[derive(Debug, Clone, Default,serde::Serialize,serde::Deserialize)]
+pub struct Form8949Totals {
+    proceeds: f64,
+    cost_basis: f64,
+    adjustment: f64,
+    gain_loss: f64,
+}
+
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+pub struct Form8949 {
+    tax_year: i32,
+    short_term_box: String,
+    long_term_box: String,
+    short_term: Vec<Form8949Line>,
+    long_term: Vec<Form8949Line>,
+    short_term_totals: Form8949Totals, // Schedule D line 3
+    long_term_totals: Form8949Totals,  // Schedule D line 10
+    net_gain_loss: f64,                // Schedule D line 16
+}
+
+fn cents(value: f64) -> f64 {
+    (value * 100.0).round() / 100.0
+}
+
+// Dates go on the form as MM/DD/YYYY.
+fn form_date(date: &str) -> String {
+    match (date.get(0..4), date.get(5..7), date.get(8..10)) {
+        (Some(year), Some(month), Some(day)) => format!("{}/{}/{}", month, day, year),
+        _ => date.to_string(),
+    }
+}
+
+fn form_line(disposal: &Disposal) -> Form8949Line {
+    let proceeds = cents(disposal.proceeds());
+    let cost_basis = cents(disposal.cost_basis());
+    Form8949Line {
+        description: format!("{} TAO", disposal.quantity),
+        // Disposals whose lot is unknown are reported as acquired on various dates.
+        date_acquired: disposal.purchase_date.as_deref().map(form_date).unwrap_or_else(|| "VARIOUS".to_string()),
+        date_sold: form_date(&disposal.liquidation_date),
+        proceeds,
+        cost_basis,
+        adjustment_code: String::new(),
+        adjustment: 0.0,
+        gain_loss: cents(proceeds - cost_basis),
+    }
+}
+
+fn totals(lines: &[Form8949Line]) -> Form8949Totals {
+    lines.iter().fold(Form8949Totals::default(), |totals, line| Form8949Totals {
+        proceeds: cents(totals.proceeds + line.proceeds),
+        cost_basis: cents(totals.cost_basis + line.cost_basis),
+        adjustment: cents(totals.adjustment + line.adjustment),
+        gain_loss: cents(totals.gain_loss + line.gain_loss),
+    })
+}
+
+pub fn build_form_8949(conn: &Connection, year: i32) -> Result<Form8949, AppError> {
+    let linked = with_lot_links(conn)?;
+    let conn = linked.as_ref().unwrap_or(conn);
+
+    let mut short_term = Vec::new();
+    let mut long_term = Vec::new();
+    // Form 8949 always covers a calendar year, whatever fiscal year the portfolio reports in.
+    let calendar = FiscalYearStart::default();
+    let display_offset = load_display_offset(conn)?;
+    for disposal in disposals(conn)?.iter().filter(|d| fiscal_year(&d.liquidation_date, &calendar, display_offset) == Some(year)) {
+        // A disposal with an unknown lot cannot show a holding period over a year.
+        if disposal.is_long_term(display_offset).unwrap_or(false) {
+            long_term.push(form_line(disposal));
+        } else {
+            short_term.push(form_line(disposal));
+        }
+    }
+
+    let short_term_totals = totals(&short_term);
+    let long_term_totals = totals(&long_term);
+    let (short_term_box, long_term_box) = form_boxes(year);
+    Ok(Form8949 {
+        tax_year: year,
+        short_term_box: short_term_box.to_string(),
+        long_term_box: long_term_box.to_string(),
+        net_gain_loss: cents(short_term_totals.gain_loss + long_term_totals.gain_loss),
+        short_term,
+        long_term,
+        short_term_totals,
+        long_term_totals,
+    })
+}
+
+#[tauri::command]
+pub fn form_8949(year: i32) -> Result<Form8949, AppError> {
+    let conn = connect_and_setup_db()?;
+    build_form_8949(&conn, year)
+}
+
+/// Writes Form 8949 Parts I and II followed by the Schedule D lines they feed, as one CSV.
+#[tauri::command]
+pub fn export_form_8949(year: i32, path: String) -> Result<String, AppError> {
+    let conn = connect_and_setup_db()?;
+    let form = build_form_8949(&conn, year)?;
+    // The form's dates are already formatted, from the same UTC dates the tax year is taken from.
+    let mut csv = CsvWriter::new(BufWriter::new(File::create(&path)?), CsvOptions::default(), UtcOffset::UTC);
+
+    let header = [
+        "(a) Description of property",
+        "(b) Date acquired",
+        "(c) Date sold or disposed of",
+        "(d) Proceeds",
+        "(e) Cost or other basis",
+        "(f) Code",
+        "(g) Amount of adjustment",
+        "(h) Gain or (loss)",
+    ];
+    let parts = [
+        (format!("Part I - Short-Term (Box {})", form.short_term_box), &form.short_term, &form.short_term_totals),
+        (format!("Part II - Long-Term (Box {})", form.long_term_box), &form.long_term, &form.long_term_totals),
+    ];
+
+    csv.write_record(&[format!("Form 8949 - Tax Year {}", form.tax_year)])?;
+    for (title, lines, part_totals) in parts {
+        csv.write_record(&[""])?;
+        csv.write_record(&[title])?;
+        csv.write_record(&header)?;
+        for line in lines {
+            let record = [
+                line.description.clone(),
+                line.date_acquired.clone(),
+                line.date_sold.clone(),
+                csv.number(line.proceeds),
+                csv.number(line.cost_basis),
+                line.adjustment_code.clone(),
+                csv.number(line.adjustment),
+                csv.number(line.gain_loss),
+            ];
+            csv.write_record(&record)?;
+        }
+        let record = [
+            "Totals".to_string(),
+            String::new(),
+            String::new(),
+            csv.number(part_totals.proceeds),
+            csv.number(part_totals.cost_basis),
+            String::new(),
+            csv.number(part_totals.adjustment),
+            csv.number(part_totals.gain_loss),
+        ];
+        csv.write_record(&record)?;
+    }
+
+    csv.write_record(&[""])?;
+    csv.write_record(&["Schedule D"])?;
+    csv.write_record(&["Line", "Description", "(d) Proceeds", "(e) Cost or other basis", "(g) Adjustments", "(h) Gain or (loss)"])?;
+    for (line, description, part_totals) in [
+        ("3", format!("Short-term totals from Form 8949 box {}", form.short_term_box), &form.short_term_totals),
+        ("10", format!("Long-term totals from Form 8949 box {}", form.long_term_box), &form.long_term_totals),
+    ] {
+        let record = [
+            line.to_string(),
+            description,
+            csv.number(part_totals.proceeds),
+            csv.number(part_totals.cost_basis),
+            csv.number(part_totals.adjustment),
+            csv.number(part_totals.gain_loss),
+        ];
+        csv.write_record(&record)?;
+    }
+    for (line, description, value) in [
+        ("7", "Net short-term capital gain or (loss)", form.short_term_totals.gain_loss),
+        ("15", "Net long-term capital gain or (loss)", form.long_term_totals.gain_loss),
+        ("16", "Combined net gain or (loss)", form.net_gain_loss),
+    ] {
+        let record = [line.to_string(), description.to_string(), String::new(), String::new(), String::new(), csv.number(value)];
+        csv.write_record(&record)?;
+    }
+    csv.flush()?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{day, test_db};
+    use crate::{db_path, record_purchase, use_tao};
+
+    #[test]
+    fn boxes_follow_the_tax_year() {
+        let _db = test_db();
+        record_purchase(2.0, 5.0, day(2023, 1, 1), None, None, None, None).unwrap();
+        use_tao(1.0, day(2024, 6, 1), 9.0, None, None, None).unwrap();
+        use_tao(1.0, day(2025, 6, 1), 9.0, None, None, None).unwrap();
+
+        let conn = connect_and_setup_db().unwrap();
+        let form_2024 = build_form_8949(&conn, 2024).unwrap();
+        assert_eq!((form_2024.short_term_box.as_str(), form_2024.long_term_box.as_str()), ("C", "F"));
+        let form_2025 = build_form_8949(&conn, 2025).unwrap();
+        assert_eq!((form_2025.short_term_box.as_str(), form_2025.long_term_box.as_str()), ("I", "L"));
+
+        let path = db_path().with_file_name("8949.csv");
+        export_form_8949(2025, path.to_string_lossy().into_owned()).unwrap();
+        let csv = std::fs::read_to_string(&path).unwrap();
+        assert!(csv.contains("Part I - Short-Term (Box I)"));
+        assert!(csv.contains("Long-term totals from Form 8949 box L"));
+        assert!(!csv.contains("Box C"));
+    }
+}
@@ -0,0 +1,205 @@
+use rusqlite::Connection;
+
+use crate::reports::{disposals, effective_date, fiscal_year, held_more_than_years, reward_income, with_lot_links, Disposal, FiscalYearStart};
+use crate::tax::{bracket_tax, load_tax_profile, TaxEstimate, TaxProfile};
+use crate::timestamp::load_display_offset;
+use crate::{connect_and_setup_db, transaction_from_row, AppError};
+
+// Rewards are "sonstige Einkünfte" under §22 Nr. 3 EStG, which have their own Freigrenze.
+const REWARD_FREIGRENZE: f64 = 256.0;
+
+/// Freigrenze for private sales (§23 Abs. 3 EStG): €600 up to 2023, €1,000 from 2024. Unlike an
+/// allowance it is all or nothing: net gains below it are tax-free, net gains at or above it are
+/// taxable in full.
+pub fn freigrenze(year: i32) -> f64 {
+    if year >= 2024 {
+        1_000.0
+    } else {
+        600.0
+    }
+}
+
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+pub struct GermanDisposal {
+    disposal: Disposal,
+    gain: f64,
+    staked: bool, // lot came from staking rewards
+    holding_period_years: i32, // years that make the disposal tax-free
+    exempt: bool,
+}
+
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+pub struct GermanyTaxReport {
+    year: i32,
+    disposals: Vec<GermanDisposal>,
+    exempt_gain: f64, // disposals past their holding period
+    net_private_sales: f64, // within the holding period, losses offset
+    freigrenze: f64,
+    freigrenze_applies: bool,
+    taxable_gain: f64,
+    reward_income: f64,
+    reward_freigrenze_applies: bool,
+    taxable_reward_income: f64,
+    pub estimate: TaxEstimate,
+}
+
+pub fn build_germany_report(conn: &Connection, profile: &TaxProfile, year: i32) -> Result<GermanyTaxReport, AppError> {
+    let linked = with_lot_links(conn)?;
+    let conn = linked.as_ref().unwrap_or(conn);
+
+    // The German tax year is the calendar year, whatever fiscal year the portfolio reports in.
+    let calendar = FiscalYearStart::default();
+    let display_offset = load_display_offset(conn)?;
+    let tax_year = |date: &str| fiscal_year(date, &calendar, display_offset);
+    let mut german_disposals = Vec::new();
+    let (mut exempt_gain, mut net_private_sales) = (0.0, 0.0);
+    for disposal in disposals(conn)?.into_iter().filter(|d| tax_year(&d.liquidation_date) == Some(year)) {
+        // Under the former view, coins used for staking had a ten-year holding period. The BMF
+        // no longer applies it, so it is only used when the profile asks for it.
+        let holding_period_years = if disposal.from_reward && profile.staking_ten_year_rule { 10 } else { 1 };
+        let exempt = disposal
+            .purchase_date
+            .as_deref()
+            .map(|acquired| held_more_than_years(acquired, &disposal.liquidation_date, holding_period_years, display_offset))
+            .unwrap_or(false);
+        let gain = disposal.gain();
+        if exempt {
+            exempt_gain += gain;
+        } else {
+            net_private_sales += gain;
+        }
+        german_disposals.push(GermanDisposal { staked: disposal.from_reward, disposal, gain, holding_period_years, exempt });
+    }
+
+    let freigrenze = freigrenze(year);
+    // A net loss leaves nothing for the Freigrenze to exempt; it is carried forward instead.
+    let freigrenze_applies = net_private_sales > 0.0 && net_private_sales < freigrenze;
+    let taxable_gain = if net_private_sales >= freigrenze { net_private_sales } else { 0.0 };
+
+    let mut stmt = conn.prepare("SELECT * FROM all_transactions WHERE is_reward AND NOT is_used")?;
+    let mut reward_total = 0.0;
+    for txn in stmt.query_map([], transaction_from_row)? {
+        let txn = txn?;
+        if effective_date(&txn).and_then(tax_year) == Some(year) {
+            reward_total += reward_income(&txn);
+        }
+    }
+    let reward_freigrenze_applies = reward_total < REWARD_FREIGRENZE;
+    let taxable_reward_income = if reward_freigrenze_applies { 0.0 } else { reward_total };
+
+    let short_term_tax = bracket_tax(taxable_gain, &profile.short_term_brackets);
+    let reward_income_tax = taxable_reward_income * profile.reward_income_rate;
+    let estimate = TaxEstimate {
+        year,
+        short_term_gain: net_private_sales,
+        long_term_gain: exempt_gain,
+        exemption_used: if freigrenze_applies { net_private_sales } else { 0.0 },
+        taxable_short_term: taxable_gain,
+        taxable_long_term: 0.0,
+        short_term_tax,
+        long_term_tax: 0.0,
+        reward_income: reward_total,
+        reward_income_tax,
+        total_tax: short_term_tax + reward_income_tax,
+        // Losses from private sales can only be set against later private sales gains.
+        loss_carryforward: net_private_sales.min(0.0).abs(),
+    };
+
+    Ok(GermanyTaxReport {
+        year,
+        disposals: german_disposals,
+        exempt_gain,
+        net_private_sales,
+        freigrenze,
+        freigrenze_applies,
+        taxable_gain,
+        reward_income: reward_total,
+        reward_freigrenze_applies,
+        taxable_reward_income,
+        estimate,
+    })
+}
+
+/// Year-end report of taxable and tax-free disposals under German rules.
+#[tauri::command]
+pub fn germany_tax_report(year: i32) -> Result<GermanyTaxReport, AppError> {
+    let conn = connect_and_setup_db()?;
+    let profile = load_tax_profile(&conn)?;
+    build_germany_report(&conn, &profile, year)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tax::Jurisdiction;
+    use crate::test_support::{day, test_db};
+    use crate::{record_purchase, use_tao};
+
+    fn germany() -> TaxProfile {
+        TaxProfile::preset(Jurisdiction::Germany)
+    }
+
+    #[test]
+    fn freigrenze_is_all_or_nothing_at_its_edges() {
+        let _db = test_db();
+        // (year, gain on one TAO bought for 100 in January and sold in June, taxable gain)
+        let cases = [(2022, 599.99, 0.0), (2023, 600.0, 600.0), (2024, 999.99, 0.0), (2025, 1_000.0, 1_000.0)];
+        for (year, gain, _) in cases {
+            record_purchase(1.0, 100.0, day(year, 1, 1), None, None, None, None).unwrap();
+            use_tao(1.0, day(year, 6, 1), 100.0 + gain, None, None, None).unwrap();
+        }
+        let conn = connect_and_setup_db().unwrap();
+        for (year, gain, taxable) in cases {
+            let report = build_germany_report(&conn, &germany(), year).unwrap();
+            assert!((report.net_private_sales - gain).abs() < 1e-6, "{}", year);
+            assert_eq!(report.freigrenze_applies, taxable == 0.0, "{}", year);
+            assert!((report.taxable_gain - taxable).abs() < 1e-6, "{}", year);
+            assert!((report.estimate.exemption_used - (gain - taxable)).abs() < 1e-6, "{}", year);
+            assert!((report.estimate.short_term_tax - taxable * 0.42).abs() < 1e-6, "{}", year);
+        }
+    }
+
+    #[test]
+    fn a_net_loss_uses_no_exemption() {
+        let _db = test_db();
+        record_purchase(1.0, 100.0, day(2020, 1, 1), None, None, None, None).unwrap();
+        record_purchase(1.0, 500.0, day(2024, 1, 1), None, None, None, None).unwrap();
+        use_tao(1.0, day(2024, 6, 1), 900.0, None, None, None).unwrap(); // held four years: exempt
+        use_tao(1.0, day(2024, 6, 1), 300.0, None, None, None).unwrap();
+
+        let report = build_germany_report(&connect_and_setup_db().unwrap(), &germany(), 2024).unwrap();
+        assert!((report.exempt_gain - 800.0).abs() < 1e-6);
+        assert!((report.net_private_sales + 200.0).abs() < 1e-6);
+        assert!(!report.freigrenze_applies);
+        assert_eq!(report.taxable_gain, 0.0);
+        assert_eq!(report.estimate.exemption_used, 0.0);
+        assert_eq!(report.estimate.total_tax, 0.0);
+        assert!((report.estimate.loss_carryforward - 200.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn staked_lots_wait_ten_years_only_under_the_former_view() {
+        let _db = test_db();
+        record_purchase(1.0, 100.0, day(2015, 3, 1), None, Some(true), None, None).unwrap();
+        record_purchase(1.0, 100.0, day(2016, 3, 1), None, Some(true), None, None).unwrap();
+        record_purchase(1.0, 100.0, day(2024, 1, 1), None, None, None, None).unwrap();
+        // Exactly ten years after the first reward; the day after the second one's tenth anniversary.
+        use_tao(1.0, day(2025, 3, 1), 1_100.0, None, None, None).unwrap();
+        use_tao(2.0, day(2026, 3, 2), 1_100.0, None, None, None).unwrap(); // with the bought lot, held two years
+
+        let conn = connect_and_setup_db().unwrap();
+        let former_view = TaxProfile { staking_ten_year_rule: true, ..germany() };
+        let exempt = |profile: &TaxProfile, year| -> Vec<(bool, bool, i32)> {
+            let report = build_germany_report(&conn, profile, year).unwrap();
+            report.disposals.iter().map(|d| (d.staked, d.exempt, d.holding_period_years)).collect()
+        };
+        assert_eq!(exempt(&germany(), 2025), [(true, true, 1)]);
+        assert_eq!(exempt(&former_view, 2025), [(true, false, 10)]);
+        assert_eq!(exempt(&germany(), 2026), [(true, true, 1), (false, true, 1)]);
+        assert_eq!(exempt(&former_view, 2026), [(true, true, 10), (false, true, 1)]);
+
+        let report = build_germany_report(&conn, &former_view, 2025).unwrap();
+        assert!((report.taxable_gain - 1_000.0).abs() < 1e-6);
+        assert!((report.estimate.short_term_tax - 420.0).abs() < 1e-6);
+    }
+}
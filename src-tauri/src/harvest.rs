@@ -0,0 +1,190 @@
+use time::{Duration, UtcOffset};
+
+use crate::prices::price_at;
+use crate::reports::{cost_basis_method, held_more_than_years, open_lots, OpenLot};
+use crate::timestamp::{format_stored, load_display_offset, local_date};
+use crate::{connect_and_setup_db, AppError};
+
+const DEFAULT_WARNING_DAYS: i64 = 30;
+
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+pub struct HarvestLot {
+    lot_id: i32,
+    txn_id: Option<i32>,
+    purchase_date: String,
+    quantity: f64,
+    cost_per_unit: f64,
+    gain_per_unit: f64,
+    unrealized_gain: f64,
+    long_term: bool,
+    days_until_long_term: Option<i64>, // None once the lot is long-term
+    near_long_term: bool,
+    tax_saving: f64, // from disposing this lot alone, by specific identification
+}
+
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+pub struct HarvestScenario {
+    method: String,
+    quantity: f64,
+    realized_gain: f64,
+    tax_saving: f64,
+}
+
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+pub struct HarvestReport {
+    as_of: String,
+    current_price: f64,
+    candidates: Vec<HarvestLot>,      // lots at a loss, largest loss per unit first
+    near_long_term: Vec<HarvestLot>, // every lot, loss or gain, about to become long-term
+    specific_id: HarvestScenario,
+    current_method: HarvestScenario,
+}
+
+// `today` is the date in the display timezone, which holding periods are counted in.
+fn harvest_lot(lot: &OpenLot, price: f64, today: time::Date, now: &str, display_offset: UtcOffset, warning_days: i64, rates: (f64, f64)) -> Result<HarvestLot, AppError> {
+    let long_term = held_more_than_years(&lot.purchase_date, now, 1, display_offset);
+    let days_until_long_term = if long_term {
+        None
+    } else {
+        let acquired = local_date(&lot.purchase_date, display_offset)?;
+        // A lot is long-term from the day after its first anniversary.
+        let anniversary = acquired
+            .replace_year(acquired.year() + 1)
+            .or_else(|_| (acquired - Duration::days(1)).replace_year(acquired.year() + 1))
+            .map_err(|_| AppError::InvalidDate(lot.purchase_date.clone()))?;
+        Some((anniversary + Duration::days(1) - today).whole_days())
+    };
+    let gain_per_unit = price - lot.price_per_ton;
+    let unrealized_gain = gain_per_unit * lot.quantity;
+    let rate = if long_term { rates.1 } else { rates.0 };
+    Ok(HarvestLot {
+        lot_id: lot.id,
+        txn_id: lot.txn_id,
+        purchase_date: lot.purchase_date.clone(),
+        quantity: lot.quantity,
+        cost_per_unit: lot.price_per_ton,
+        gain_per_unit,
+        unrealized_gain,
+        long_term,
+        near_long_term: days_until_long_term.map(|days| days <= warning_days).unwrap_or(false),
+        days_until_long_term,
+        tax_saving: -unrealized_gain * rate,
+    })
+}
+
+/// Open lots trading below their cost at `current_price` (the latest recorded price when not
+/// given), with the tax saved by selling them. Savings are worked out for selling exactly the loss
+/// lots by specific identification, and for selling the same quantity under the configured lot
+/// method, which may consume profitable lots instead. Lots that become long-term within
+/// `warning_days` (default 30) are flagged, since selling them early realizes a short-term result.
+#[tauri::command]
+pub fn tax_loss_harvesting(
+    current_price: Option<f64>,
+    short_term_rate: f64,
+    long_term_rate: f64,
+    warning_days: Option<i64>,
+) -> Result<HarvestReport, AppError> {
+    let conn = connect_and_setup_db()?;
+    let display_offset = load_display_offset(&conn)?;
+    let now_utc = time::OffsetDateTime::now_utc();
+    let today = now_utc.to_offset(display_offset).date();
+    let now = format_stored(now_utc);
+    let price = match current_price {
+        Some(price) => price,
+        None => price_at(&conn, &now)?.ok_or_else(|| AppError::MissingPrice("no current price given or recorded".to_string()))?,
+    };
+    let warning_days = warning_days.unwrap_or(DEFAULT_WARNING_DAYS);
+    let rates = (short_term_rate, long_term_rate);
+
+    let lots = open_lots(&conn)?;
+    let mut all = Vec::new();
+    for lot in &lots {
+        all.push(harvest_lot(lot, price, today, &now, display_offset, warning_days, rates)?);
+    }
+
+    let mut candidates: Vec<HarvestLot> = all.iter().filter(|lot| lot.gain_per_unit < 0.0).cloned().collect();
+    candidates.sort_by(|a, b| a.gain_per_unit.total_cmp(&b.gain_per_unit).then(a.lot_id.cmp(&b.lot_id)));
+    let near_long_term = all.iter().filter(|lot| lot.near_long_term).cloned().collect();
+
+    let quantity: f64 = candidates.iter().map(|lot| lot.quantity).sum();
+    let specific_id = HarvestScenario {
+        method: "Specific ID".to_string(),
+        quantity,
+        realized_gain: candidates.iter().map(|lot| lot.unrealized_gain).sum(),
+        tax_saving: candidates.iter().map(|lot| lot.tax_saving).sum(),
+    };
+
+    // Consume the same quantity in the order apply_use would, ties included: by the ledger row that
+    // created each lot, then by lot id. Lot ids alone are not in ledger order, as a replay re-creates
+    // the lots it rewinds.
+    let method = cost_basis_method(&conn)?;
+    let mut ordered: Vec<&HarvestLot> = all.iter().collect();
+    let oldest_first = |a: &&HarvestLot, b: &&HarvestLot| (&a.purchase_date, a.txn_id, a.lot_id).cmp(&(&b.purchase_date, b.txn_id, b.lot_id));
+    match method {
+        "FIFO" => ordered.sort_by(oldest_first),
+        "LIFO" => ordered.sort_by(|a, b| oldest_first(b, a)),
+        "LOFO" => ordered.sort_by(|a, b| a.cost_per_unit.total_cmp(&b.cost_per_unit).then_with(|| oldest_first(a, b))),
+        _ => ordered.sort_by(|a, b| b.cost_per_unit.total_cmp(&a.cost_per_unit).then_with(|| oldest_first(a, b))),
+    }
+    let (mut remaining, mut realized_gain, mut tax_saving) = (quantity, 0.0, 0.0);
+    for lot in ordered {
+        if remaining <= 0.0 {
+            break;
+        }
+        let used = lot.quantity.min(remaining);
+        let share = used / lot.quantity;
+        realized_gain += lot.unrealized_gain * share;
+        tax_saving += lot.tax_saving * share;
+        remaining -= used;
+    }
+    let current_method = HarvestScenario { method: method.to_string(), quantity, realized_gain, tax_saving };
+
+    Ok(HarvestReport {
+        as_of: now,
+        current_price: price,
+        candidates,
+        near_long_term,
+        specific_id,
+        current_method,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{day, test_db};
+    use crate::{add_transaction, record_purchase, replay_changes, use_tao};
+
+    #[test]
+    fn lots_rebuilt_by_a_replay_are_consumed_in_ledger_order() {
+        let _db = test_db();
+        record_purchase(1.0, 100.0, day(2024, 1, 1), None, None, None, None).unwrap();
+        record_purchase(1.0, 200.0, day(2024, 1, 1), None, None, None, None).unwrap();
+        use_tao(0.5, day(2024, 2, 1), 150.0, None, None, None).unwrap();
+        // A purchase back-dated before the sale rewinds it, which re-creates the first lot with a
+        // higher id than the second.
+        let added = add_transaction(1.0, 150.0, 0.0, 0.0, day(2024, 1, 15), day(2024, 1, 15), false, None, None, None, None);
+        assert_eq!(added, "Completed");
+        replay_changes(false).unwrap();
+
+        let conn = connect_and_setup_db().unwrap();
+        let lots = open_lots(&conn).unwrap();
+        let order: Vec<Option<i32>> = lots.iter().map(|lot| lot.txn_id).collect();
+        assert_eq!(order, [Some(1), Some(2), Some(4)]);
+        assert!(lots[0].id > lots[1].id);
+
+        // At 150 only the second lot is at a loss. FIFO sells the rest of the first lot before it,
+        // as the next use_tao would.
+        let report = tax_loss_harvesting(Some(150.0), 0.3, 0.15, None).unwrap();
+        assert_eq!(report.candidates.len(), 1);
+        assert_eq!(report.candidates[0].txn_id, Some(2));
+        assert!((report.specific_id.realized_gain + 50.0).abs() < 1e-6);
+        assert!(report.current_method.realized_gain.abs() < 1e-6);
+
+        use_tao(1.0, day(2024, 3, 1), 150.0, None, None, None).unwrap();
+        let used: f64 = conn
+            .query_row("SELECT SUM(quantity * (sell_price - orig_price)) FROM used_timber WHERE liquidation_date = '2024-03-01T00:00:00Z'", [], |row| row.get(0))
+            .unwrap();
+        assert!((used - report.current_method.realized_gain).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,191 @@
+use rusqlite::{params, Connection};
+use crate::pnl::Granularity;
+use crate::prices::price_at;
+use crate::reports::{effective_date, load_fiscal_year_start, open_lots, OpenLot};
+use crate::timestamp::{end_of_day, load_display_offset, local_date};
+use crate::{apply_transaction, connect_and_setup_db, replay_into, setup_db, transaction_from_row, AppError, DateTime};
+
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+pub struct HoldingsAsOf {
+    as_of: String,
+    open_lots: Vec<OpenLot>,
+    quantity: f64,
+    cost_basis: f64,
+}
+
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+pub struct SeriesPoint {
+    date: String,
+    quantity: f64,
+    cost_basis: f64,
+    price: Option<f64>, // latest recorded price at the point, if any
+    market_value: Option<f64>,
+    unrealized_gain: Option<f64>,
+}
+
+// Scratch database with the live lot method and no lots yet.
+pub fn scratch_db(ledger: &Connection) -> Result<Connection, AppError> {
+    let scratch = setup_db(Connection::open_in_memory()?)?;
+    let usage_type: i64 = ledger.query_row("SELECT usage_type FROM app_settings WHERE id = 1", [], |row| row.get(0))?;
+    scratch.execute("UPDATE app_settings SET usage_type = ?1 WHERE id = 1", params![usage_type])?;
+    Ok(scratch)
+}
+
+// Replays the live ledger up to `until` into an in-memory database with the same lot method, so
+// the live timber_purchases and used_timber tables are never touched.
+pub fn scratch_replay(ledger: &Connection, until: &str) -> Result<Connection, AppError> {
+    let scratch = scratch_db(ledger)?;
+    replay_into(&scratch, ledger, Some(until), None)?;
+    Ok(scratch)
+}
+
+/// Open lots, quantity held and cost basis as they stood at `date_time`, reconstructed from
+/// all_transactions.
+#[tauri::command]
+pub fn holdings_as_of(date_time: DateTime) -> Result<HoldingsAsOf, AppError> {
+    let conn = connect_and_setup_db()?;
+    let as_of = date_time.to_stored(&conn)?;
+    let scratch = scratch_replay(&conn, &as_of)?;
+
+    let open_lots = open_lots(&scratch)?;
+    Ok(HoldingsAsOf {
+        quantity: open_lots.iter().map(|lot| lot.quantity).sum(),
+        cost_basis: open_lots.iter().map(|lot| lot.quantity * lot.price_per_ton).sum(),
+        open_lots,
+        as_of,
+    })
+}
+
+/// Quantity held, cost basis and, where prices have been recorded, market value and unrealized
+/// gain at the end of every period from `from` to `to`. The ledger is replayed once, in date
+/// order, sampling the lots at each period end. `from` defaults to the first ledger entry and `to`
+/// to today.
+#[tauri::command]
+pub fn holdings_series(from: Option<DateTime>, to: Option<DateTime>, interval: Granularity) -> Result<Vec<SeriesPoint>, AppError> {
+    let conn = connect_and_setup_db()?;
+    let mut stmt = conn.prepare(
+        "SELECT * FROM all_transactions
+         WHERE (CASE WHEN is_used THEN liquidation_date ELSE purchase_date END) IS NOT NULL
+         ORDER BY CASE WHEN is_used THEN liquidation_date ELSE purchase_date END, id",
+    )?;
+    let transactions = stmt.query_map([], transaction_from_row)?.collect::<rusqlite::Result<Vec<_>>>()?;
+
+    // Periods are days in the display timezone, as for tax years.
+    let display_offset = load_display_offset(&conn)?;
+    let day_of = |date_time: &DateTime| date_time.resolve(display_offset).map(|instant| instant.to_offset(display_offset).date());
+    let start = match (&from, transactions.first().and_then(effective_date)) {
+        (Some(from), _) => day_of(from)?,
+        (None, Some(first)) => local_date(first, display_offset)?,
+        (None, None) => return Ok(Vec::new()),
+    };
+    let end = match &to {
+        Some(to) => day_of(to)?,
+        None => time::OffsetDateTime::now_utc().to_offset(display_offset).date(),
+    };
+
+    let fiscal_year_start = load_fiscal_year_start(&conn)?;
+    let scratch = scratch_db(&conn)?;
+    let mut pending = transactions.iter().peekable();
+    let mut points = Vec::new();
+    let mut period_start = start;
+    while period_start <= end {
+        let point_date = interval.period_end(period_start, &fiscal_year_start).min(end);
+        let until = end_of_day(point_date, display_offset);
+        while let Some(txn) = pending.next_if(|txn| effective_date(txn).map(|date| date <= until.as_str()).unwrap_or(false)) {
+            apply_transaction(&scratch, txn)?;
+        }
+
+        let (quantity, cost_basis): (f64, f64) = scratch.query_row(
+            "SELECT COALESCE(SUM(quantity), 0), COALESCE(SUM(acquisition_value), 0) FROM timber_purchases",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        let price = price_at(&conn, &until)?;
+        let market_value = price.map(|price| price * quantity);
+        points.push(SeriesPoint {
+            date: point_date.to_string(),
+            quantity,
+            cost_basis,
+            price,
+            market_value,
+            unrealized_gain: market_value.map(|value| value - cost_basis),
+        });
+
+        period_start = match point_date.next_day() {
+            Some(next) => next,
+            None => break,
+        };
+    }
+    Ok(points)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{day, test_db};
+    use crate::prices::record_price;
+    use crate::{record_purchase, use_tao};
+
+    fn lot_figures(lots: &[OpenLot]) -> Vec<(f64, f64, String, Option<i32>)> {
+        lots.iter().map(|lot| (lot.quantity, lot.price_per_ton, lot.purchase_date.clone(), lot.txn_id)).collect()
+    }
+
+    #[test]
+    fn holdings_are_rebuilt_as_they_stood_on_each_date() {
+        let _db = test_db();
+        record_purchase(10.0, 5.0, day(2024, 1, 1), None, None, None, None).unwrap();
+        record_purchase(5.0, 6.0, day(2024, 2, 1), None, None, None, None).unwrap();
+        use_tao(12.0, day(2024, 3, 1), 9.0, None, None, None).unwrap();
+        use_tao(2.0, day(2024, 4, 1), 9.0, None, None, None).unwrap();
+
+        let held = |y, m, d| {
+            let holdings = holdings_as_of(day(y, m, d)).unwrap();
+            (holdings.quantity, holdings.cost_basis, holdings.open_lots.len())
+        };
+        assert_eq!(held(2023, 12, 31), (0.0, 0.0, 0));
+        assert_eq!(held(2024, 1, 15), (10.0, 50.0, 1));
+        assert_eq!(held(2024, 2, 15), (15.0, 80.0, 2));
+        // FIFO: the first sale takes all of the January lot and 2 of February's.
+        assert_eq!(held(2024, 3, 15), (3.0, 18.0, 1));
+        assert_eq!(held(2024, 12, 31), (1.0, 6.0, 1));
+
+        // After the last disposal the rebuilt lots are the live ones.
+        let conn = connect_and_setup_db().unwrap();
+        let live = open_lots(&conn).unwrap();
+        assert_eq!(lot_figures(&holdings_as_of(day(2024, 12, 31)).unwrap().open_lots), lot_figures(&live));
+        // Rebuilding never touches the live tables.
+        assert_eq!(lot_figures(&open_lots(&conn).unwrap()), lot_figures(&live));
+    }
+
+    #[test]
+    fn series_points_carry_the_last_price_forward() {
+        let _db = test_db();
+        record_purchase(10.0, 5.0, day(2024, 1, 1), None, None, None, None).unwrap();
+        use_tao(4.0, day(2024, 1, 3), 9.0, None, None, None).unwrap();
+        record_price(day(2024, 1, 2), 6.0).unwrap();
+        record_price(day(2024, 1, 4), 7.0).unwrap();
+
+        let series = holdings_series(Some(day(2024, 1, 1)), Some(day(2024, 1, 5)), Granularity::Day).unwrap();
+        let figures: Vec<_> = series
+            .iter()
+            .map(|point| (point.date.as_str(), point.quantity, point.cost_basis, point.price, point.market_value, point.unrealized_gain))
+            .collect();
+        assert_eq!(
+            figures,
+            [
+                ("2024-01-01", 10.0, 50.0, None, None, None),
+                ("2024-01-02", 10.0, 50.0, Some(6.0), Some(60.0), Some(10.0)),
+                // No price on the 3rd: the 2nd's is used.
+                ("2024-01-03", 6.0, 30.0, Some(6.0), Some(36.0), Some(6.0)),
+                ("2024-01-04", 6.0, 30.0, Some(7.0), Some(42.0), Some(12.0)),
+                ("2024-01-05", 6.0, 30.0, Some(7.0), Some(42.0), Some(12.0)),
+            ]
+        );
+
+        // Month ends with no price of their own take the last one before them, and the series
+        // stops at `to`.
+        let monthly = holdings_series(Some(day(2024, 1, 1)), Some(day(2024, 3, 15)), Granularity::Month).unwrap();
+        let prices: Vec<_> = monthly.iter().map(|point| (point.date.as_str(), point.price)).collect();
+        assert_eq!(prices, [("2024-01-31", Some(7.0)), ("2024-02-29", Some(7.0)), ("2024-03-15", Some(7.0))]);
+    }
+}
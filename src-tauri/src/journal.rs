@@ -0,0 +1,318 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use rusqlite::{params, Connection};
+
+use crate::reports::{effective_date, with_lot_links};
+use crate::{connect_and_setup_db, transaction_from_row, AllTransactions, AppError};
+
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+pub enum JournalFormat {
+    Beancount,
+    Hledger, // also read by ledger-cli
+}
+
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+#[serde(default)]
+pub struct JournalAccounts {
+    pub holdings: String,
+    pub cash: String,
+    pub rewards: String,
+    pub capital_gains: String,
+    pub commodity: String,
+    pub currency: String,
+}
+
+impl Default for JournalAccounts {
+    fn default() -> Self {
+        JournalAccounts {
+            holdings: "Assets:Crypto:TAO".to_string(),
+            cash: "Assets:Cash".to_string(),
+            rewards: "Income:Staking".to_string(),
+            capital_gains: "Income:CapitalGains".to_string(),
+            commodity: "TAO".to_string(),
+            currency: "USD".to_string(),
+        }
+    }
+}
+
+// One lot consumed by a sale, as linked in used_timber.
+struct LotMatch {
+    quantity: f64,
+    orig_price: f64,
+    sell_price: f64,
+    purchase_date: Option<String>,
+    purchase_txn_id: Option<i32>,
+}
+
+// Quantities are written with the commodity's eight decimals, so the f32 they were entered as does
+// not show through (0.10000000 rather than 0.10000000149011612); cash amounts get as many.
+fn fixed(value: f64) -> String {
+    format!("{:.8}", value)
+}
+
+fn quoted(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn day(date: &str) -> &str {
+    date.get(0..10).unwrap_or(date)
+}
+
+fn lot_matches(conn: &Connection, sale_txn_id: i32) -> rusqlite::Result<Vec<LotMatch>> {
+    let mut stmt = conn.prepare(
+        "SELECT quantity, orig_price, sell_price, purchase_date, purchase_txn_id FROM used_timber WHERE sale_txn_id = ?1 ORDER BY id",
+    )?;
+    let matches = stmt.query_map(params![sale_txn_id], |row| {
+        Ok(LotMatch {
+            quantity: row.get(0)?,
+            orig_price: row.get(1)?,
+            sell_price: row.get(2)?,
+            purchase_date: row.get(3)?,
+            purchase_txn_id: row.get(4)?,
+        })
+    })?;
+    matches.collect()
+}
+
+// Every transaction leaves its last posting without an amount, so the journal balances exactly
+// even where the stored values carry more decimals than the currency does. A sale that consumed
+// no lots (there were not enough to cover it when it was replayed) is not booked, as it would
+// only move nothing into cash; a comment marks its place instead.
+fn write_transaction<W: Write>(
+    out: &mut W,
+    format: &JournalFormat,
+    accounts: &JournalAccounts,
+    txn: &AllTransactions,
+    matches: &[LotMatch],
+) -> std::io::Result<()> {
+    let date = match effective_date(txn) {
+        Some(date) => day(date),
+        None => return Ok(()),
+    };
+    let kind = if txn.is_used { "Sale" } else if txn.is_reward { "Reward" } else { "Purchase" };
+    let (commodity, currency) = (&accounts.commodity, &accounts.currency);
+    let quantity = fixed(txn.quantity);
+    if txn.is_used && matches.is_empty() {
+        writeln!(out, "; {} Sale of {} {} (ledger_id {}) is not booked: it consumed no lots", date, quantity, commodity, txn.id)?;
+        return writeln!(out);
+    }
+    // Content hashes only guard against re-imports and mean nothing outside this database.
+    let external_ref = txn.external_ref.as_deref().filter(|external_ref| !external_ref.starts_with("hash:"));
+
+    match format {
+        JournalFormat::Beancount => {
+            writeln!(out, "{} * {} {}", date, quoted(kind), quoted(&format!("{} {} {}", kind, quantity, commodity)))?;
+            writeln!(out, "  ledger_id: {}", txn.id)?;
+            if let Some(external_ref) = external_ref {
+                writeln!(out, "  external_ref: {}", quoted(external_ref))?;
+            }
+        }
+        JournalFormat::Hledger => {
+            writeln!(out, "{} * {} {} {}  ; ledger_id:{}", date, kind, quantity, commodity, txn.id)?;
+            if let Some(external_ref) = external_ref {
+                writeln!(out, "    ; external_ref:{}", external_ref)?;
+            }
+        }
+    }
+
+    if txn.is_used {
+        // Each consumed lot is reduced at its own cost; the difference to the proceeds is the
+        // realized gain, which the elided income posting picks up.
+        let mut proceeds = 0.0;
+        for lot in matches {
+            let lot_quantity = fixed(lot.quantity);
+            proceeds += lot.quantity * lot.sell_price;
+            match format {
+                JournalFormat::Beancount => {
+                    let mut cost = format!("{} {}", lot.orig_price, currency);
+                    if let Some(purchase_date) = &lot.purchase_date {
+                        cost.push_str(&format!(", {}", day(purchase_date)));
+                    }
+                    if let Some(purchase_txn_id) = lot.purchase_txn_id {
+                        cost.push_str(&format!(", \"lot-{}\"", purchase_txn_id));
+                    }
+                    writeln!(out, "  {}  -{} {} {{{}}} @ {} {}", accounts.holdings, lot_quantity, commodity, cost, lot.sell_price, currency)?;
+                }
+                JournalFormat::Hledger => {
+                    let acquired = lot.purchase_date.as_deref().map(day).unwrap_or("unknown");
+                    writeln!(
+                        out,
+                        "    {}  -{} {} @ {} {}  ; acquired:{}, sold at {} {}",
+                        accounts.holdings, lot_quantity, commodity, lot.orig_price, currency, acquired, lot.sell_price, currency
+                    )?;
+                }
+            }
+        }
+        let indent = if matches!(format, JournalFormat::Beancount) { "  " } else { "    " };
+        writeln!(out, "{}{}  {} {}", indent, accounts.cash, fixed(proceeds), currency)?;
+        writeln!(out, "{}{}", indent, accounts.capital_gains)?;
+    } else {
+        let price = txn.price_per_ton.unwrap_or(0.0);
+        let counter_account = if txn.is_reward { &accounts.rewards } else { &accounts.cash };
+        match format {
+            JournalFormat::Beancount => {
+                writeln!(out, "  {}  {} {} {{{} {}, \"lot-{}\"}}", accounts.holdings, quantity, commodity, price, currency, txn.id)?;
+                writeln!(out, "  {}", counter_account)?;
+            }
+            JournalFormat::Hledger => {
+                writeln!(out, "    {}  {} {} @ {} {}", accounts.holdings, quantity, commodity, price, currency)?;
+                writeln!(out, "    {}", counter_account)?;
+            }
+        }
+    }
+    writeln!(out)
+}
+
+pub fn write_journal<W: Write>(
+    conn: &Connection,
+    out: &mut W,
+    format: &JournalFormat,
+    accounts: &JournalAccounts,
+) -> Result<(), AppError> {
+    let linked = with_lot_links(conn)?;
+    let conn = linked.as_ref().unwrap_or(conn);
+
+    let opened = [&accounts.holdings, &accounts.cash, &accounts.rewards, &accounts.capital_gains];
+    match format {
+        JournalFormat::Beancount => {
+            writeln!(out, "option \"title\" \"TAO Inventory\"")?;
+            writeln!(out, "option \"operating_currency\" {}", quoted(&accounts.currency))?;
+            writeln!(out)?;
+            writeln!(out, "1970-01-01 commodity {}", accounts.commodity)?;
+            for account in opened {
+                writeln!(out, "1970-01-01 open {}", account)?;
+            }
+        }
+        JournalFormat::Hledger => {
+            writeln!(out, "commodity 1.00000000 {}", accounts.commodity)?;
+            for account in opened {
+                writeln!(out, "account {}", account)?;
+            }
+        }
+    }
+    writeln!(out)?;
+
+    let mut stmt = conn.prepare(
+        "SELECT * FROM all_transactions
+         ORDER BY CASE WHEN is_used THEN liquidation_date ELSE purchase_date END, is_used, id",
+    )?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let txn = transaction_from_row(row)?;
+        let matches = if txn.is_used { lot_matches(conn, txn.id)? } else { Vec::new() };
+        write_transaction(out, format, accounts, &txn, &matches)?;
+    }
+    Ok(())
+}
+
+/// Writes the ledger as a Beancount or hledger journal to `path` and returns the path. Sales are
+/// booked against the lots they consumed, with the realized gain posted to the capital gains
+/// account.
+#[tauri::command]
+pub fn export_journal(format: JournalFormat, path: String, accounts: Option<JournalAccounts>) -> Result<String, AppError> {
+    let conn = connect_and_setup_db()?;
+    let mut out = BufWriter::new(File::create(&path)?);
+    write_journal(&conn, &mut out, &format, &accounts.unwrap_or_default())?;
+    out.flush()?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{day as on, test_db};
+    use crate::{record_purchase, use_tao};
+
+    // Quantity, commodity and weight in the currency of a posting read back from a journal.
+    type Amount = (f64, String, f64);
+
+    // A transaction read back from a journal: its ledger id and its postings, each an account and
+    // an amount unless it was left for the journal to balance.
+    struct Parsed {
+        ledger_id: i32,
+        postings: Vec<(String, Option<Amount>)>,
+    }
+
+    fn parse_posting(text: &str, currency: &str) -> (String, Option<Amount>) {
+        let text = text.split("  ;").next().unwrap_or(text).trim();
+        let (account, amount) = match text.split_once("  ") {
+            Some((account, amount)) => (account, amount.trim()),
+            None => return (text.to_string(), None),
+        };
+        let mut tokens = amount.split_whitespace();
+        let quantity_text = tokens.next().unwrap();
+        let commodity = tokens.next().unwrap().to_string();
+        assert_eq!(quantity_text.split('.').nth(1).map(str::len), Some(8), "{} is not fixed precision", quantity_text);
+        let quantity: f64 = quantity_text.parse().unwrap();
+        let unit = |marker: char| {
+            amount.split_once(marker).map(|(_, rest)| rest.trim_start().split([' ', ',']).next().unwrap().parse::<f64>().unwrap())
+        };
+        let weight = match (unit('{'), unit('@')) {
+            (Some(cost), _) | (None, Some(cost)) => quantity * cost,
+            (None, None) if commodity == currency => quantity,
+            (None, None) => panic!("no weight for {}", text),
+        };
+        (account.to_string(), Some((quantity, commodity, weight)))
+    }
+
+    fn parse(journal: &str, currency: &str) -> Vec<Parsed> {
+        let mut parsed: Vec<Parsed> = Vec::new();
+        let mut open = false;
+        for line in journal.lines() {
+            if line.trim().is_empty() {
+                open = false;
+            } else if line.starts_with(|c: char| c.is_ascii_digit()) && line.contains(" * ") {
+                let ledger_id = line.split_once("ledger_id:").map(|(_, id)| id.trim().parse().unwrap()).unwrap_or(0);
+                parsed.push(Parsed { ledger_id, postings: Vec::new() });
+                open = true;
+            } else if open && line.starts_with(' ') {
+                let text = line.trim();
+                let transaction = parsed.last_mut().unwrap();
+                if let Some(id) = text.strip_prefix("ledger_id:") {
+                    transaction.ledger_id = id.trim().parse().unwrap();
+                } else if !text.starts_with(';') && !text.split_whitespace().next().unwrap().ends_with(':') {
+                    transaction.postings.push(parse_posting(text, currency));
+                }
+            }
+        }
+        parsed
+    }
+
+    #[test]
+    fn journals_read_back_balanced_and_match_the_lots() {
+        let _db = test_db();
+        record_purchase(0.1, 5.0, on(2024, 1, 1), None, None, None, None).unwrap();
+        record_purchase(1.0, 6.0, on(2024, 1, 2), None, Some(true), None, None).unwrap();
+        use_tao(0.6, on(2024, 2, 1), 9.0, None, None, None).unwrap();
+        use_tao(100.0, on(2024, 3, 1), 9.0, None, None, None).unwrap(); // more than is held
+        let conn = connect_and_setup_db().unwrap();
+        let held: f64 = conn.query_row("SELECT SUM(quantity) FROM timber_purchases", [], |row| row.get(0)).unwrap();
+        let gain: f64 = conn.query_row("SELECT SUM(sell_value - orig_value) FROM used_timber", [], |row| row.get(0)).unwrap();
+        let accounts = JournalAccounts::default();
+
+        for format in [JournalFormat::Beancount, JournalFormat::Hledger] {
+            let mut out = Vec::new();
+            write_journal(&conn, &mut out, &format, &accounts).unwrap();
+            let journal = String::from_utf8(out).unwrap();
+            assert!(!journal.contains("0.10000000149"), "{}", journal);
+            assert!(journal.contains("; 2024-03-01 Sale of 100.00000000 TAO (ledger_id 4) is not booked"), "{}", journal);
+
+            let transactions = parse(&journal, &accounts.currency);
+            assert_eq!(transactions.iter().map(|txn| txn.ledger_id).collect::<Vec<_>>(), vec![1, 2, 3], "{}", journal);
+            let mut holdings = 0.0;
+            for txn in &transactions {
+                let elided: Vec<&String> = txn.postings.iter().filter(|(_, amount)| amount.is_none()).map(|(account, _)| account).collect();
+                assert_eq!(elided.len(), 1, "{}", journal);
+                holdings += txn.postings.iter().filter(|(account, _)| *account == accounts.holdings).filter_map(|(_, amount)| amount.as_ref()).map(|amount| amount.0).sum::<f64>();
+                if txn.ledger_id == 3 {
+                    // The capital gains posting takes what the sale's postings leave over.
+                    let balance: f64 = txn.postings.iter().filter_map(|(_, amount)| amount.as_ref()).map(|amount| amount.2).sum();
+                    assert_eq!(elided[0], &accounts.capital_gains);
+                    assert!((balance - gain).abs() < 1e-6, "{} != {}", balance, gain);
+                }
+            }
+            assert!((holdings - held).abs() < 1e-6, "{} != {}", holdings, held);
+        }
+    }
+}
@@ -4,7 +4,30 @@
 // Learn more about Tauri commands at https://tauri.app/v1/guides/features/command
 
 use thiserror::Error;
-use xlsxwriter::*;
+
+mod audit;
+mod australia;
+mod backdating;
+mod backup;
+mod csv_export;
+mod duplicates;
+mod excel;
+mod form8949;
+mod germany;
+mod harvest;
+mod holdings;
+mod journal;
+mod pdf;
+mod performance;
+mod periods;
+mod pnl;
+mod prices;
+mod reports;
+mod snapshots;
+mod tax;
+#[cfg(test)]
+mod test_support;
+mod timestamp;
 
 
 
@@ -12,13 +35,36 @@ use xlsxwriter::*;
 fn greet(name: &str) -> String {
     format!("Hello, {}! You've been greeted from Rust!", name)
 }
-use rusqlite::{params, Connection, Result};
+use std::path::PathBuf;
+
+use rusqlite::{params, Connection, OptionalExtension, Result};
+use timestamp::DateTime;
 
 
 #[derive(Debug, Error)]
 pub enum AppError {
     #[error("Database error: {0}")]
     DatabaseError(#[from] rusqlite::Error),
+    #[error("Duplicate transaction: {0}")]
+    DuplicateTransaction(String),
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("JSON error: {0}")]
+    JsonError(#[from] serde_json::Error),
+    #[error("Backup error: {0}")]
+    BackupError(String),
+    #[error("Invalid date: {0}")]
+    InvalidDate(String),
+    #[error("Missing price: {0}")]
+    MissingPrice(String),
+    #[error("Excel error: {0}")]
+    ExcelError(#[from] xlsxwriter::XlsxError),
+    #[error("PDF error: {0}")]
+    PdfError(#[from] printpdf::Error),
+    #[error("Period lock: {0}")]
+    PeriodLock(String),
+    #[error("Audit log: {0}")]
+    AuditError(String),
     // Add more error types as needed
 }
 
@@ -31,7 +77,7 @@ impl From<AppError> for tauri::InvokeError {
 }
 
 #[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
-struct AllTransactions {
+pub struct AllTransactions {
     id: i32,
     quantity: f64,
     price_per_ton: Option<f64>,
@@ -40,6 +86,8 @@ struct AllTransactions {
     liquidation_date: Option<String>,
     purchase_date: Option<String>,
     is_used: bool,
+    external_ref: Option<String>,
+    is_reward: bool,
 }
 
 #[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
@@ -49,16 +97,7 @@ pub struct Spec {
     sale_price: f64,
     liquidation_date: String,
 }
-#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
-pub struct DateTime {
-    year: i32,
-    month: u32,
-    day: u32,
-    hour: u32,
-    minute: u32,
-    second: u32,
-}
-
+// Kind of change recorded in audit_log.
 #[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
 pub enum TransactionType {
     Remove,
@@ -67,40 +106,59 @@ pub enum TransactionType {
 
 }
 
-impl DateTime {
-    fn to_string(&self) -> String {
-        format!(
-            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
-            self.year, self.month, self.day, self.hour, self.minute, self.second
-        )
-    }
 
-    fn from_string(date_str: &str) -> Result<DateTime, &'static str> {
-        let parts: Vec<&str> = date_str.split(|c: char| c == '-' || c == ' ' || c == ':').collect();
-        if parts.len() != 6 {
-            return Err("Invalid date time format");
+const DB_PATH: &str = "/Users/j-supha/desktop/Tao_Inventory.db";
+
+// Unit tests get a database of their own; see test_support.rs.
+pub fn db_path() -> PathBuf {
+    #[cfg(test)]
+    {
+        if let Some(path) = test_support::db_path() {
+            return path;
         }
-        Ok(DateTime {
-            year: parts[0].parse().map_err(|_| "Invalid year")?,
-            month: parts[1].parse().map_err(|_| "Invalid month")?,
-            day: parts[2].parse().map_err(|_| "Invalid day")?,
-            hour: parts[3].parse().map_err(|_| "Invalid hour")?,
-            minute: parts[4].parse().map_err(|_| "Invalid minute")?,
-            second: parts[5].parse().map_err(|_| "Invalid second")?,
-        })
     }
+    PathBuf::from(DB_PATH)
 }
 
-
 pub fn connect_and_setup_db() -> Result<Connection> {
-    let conn = Connection::open("/Users/j-supha/desktop/Tao_Inventory.db")?;
+    setup_db(Connection::open(db_path())?)
+}
+
+// Creates or migrates the schema on any connection, including the in-memory scratch databases
+// used for point-in-time replays.
+fn setup_db(conn: Connection) -> Result<Connection> {
     conn.execute(
     "CREATE TABLE IF NOT EXISTS app_settings (
         id INTEGER PRIMARY KEY CHECK (id = 1),
-        usage_type INTEGER NOT NULL
+        usage_type INTEGER NOT NULL,
+        snapshot_retention INTEGER NOT NULL DEFAULT 20,
+        snapshot_interval_minutes INTEGER NOT NULL DEFAULT 60,
+        tax_profile TEXT,
+        fiscal_year_start_month INTEGER NOT NULL DEFAULT 1,
+        fiscal_year_start_day INTEGER NOT NULL DEFAULT 1,
+        display_utc_offset_seconds INTEGER NOT NULL DEFAULT 0,
+        replay_from_date TEXT,
+        replay_from_txn_id INTEGER,
+        ledger_format INTEGER NOT NULL DEFAULT 0
     )",
     [],
     )?;
+    add_column_if_missing(&conn, "app_settings", "snapshot_retention", "INTEGER NOT NULL DEFAULT 20")?;
+    add_column_if_missing(&conn, "app_settings", "snapshot_interval_minutes", "INTEGER NOT NULL DEFAULT 60")?;
+    add_column_if_missing(&conn, "app_settings", "snapshot_last_error", "TEXT")?;
+    // JSON-encoded tax::TaxProfile, NULL until one is saved.
+    add_column_if_missing(&conn, "app_settings", "tax_profile", "TEXT")?;
+    // First day of the fiscal year, e.g. 4/6 in the UK or 7/1 in Australia.
+    add_column_if_missing(&conn, "app_settings", "fiscal_year_start_month", "INTEGER NOT NULL DEFAULT 1")?;
+    add_column_if_missing(&conn, "app_settings", "fiscal_year_start_day", "INTEGER NOT NULL DEFAULT 1")?;
+    // Offset used to read times entered in the app and to show stored UTC times.
+    add_column_if_missing(&conn, "app_settings", "display_utc_offset_seconds", "INTEGER NOT NULL DEFAULT 0")?;
+    // Replay position (date, then ledger id) of the earliest change since the lot tables were last
+    // replayed, NULL when they are up to date. See replay_changes.
+    add_column_if_missing(&conn, "app_settings", "replay_from_date", "TEXT")?;
+    add_column_if_missing(&conn, "app_settings", "replay_from_txn_id", "INTEGER")?;
+    // Format of the stored dates and generated references; see migrate_ledger_format.
+    add_column_if_missing(&conn, "app_settings", "ledger_format", "INTEGER NOT NULL DEFAULT 0")?;
     // Attempt to insert the default setting without checking if the table is empty
     // This will insert the row if the table is empty, and do nothing if the row already exists
     conn.execute(
@@ -115,7 +173,8 @@ pub fn connect_and_setup_db() -> Result<Connection> {
             quantity REAL NOT NULL,
             price_per_ton REAL NOT NULL,
             purchase_date TEXT NOT NULL,
-            acquisition_value REAL AS (quantity * price_per_ton)
+            acquisition_value REAL AS (quantity * price_per_ton),
+            txn_id INTEGER
         )",
         [],
     )?;
@@ -129,10 +188,24 @@ pub fn connect_and_setup_db() -> Result<Connection> {
             sell_price REAL NOT NULL,
             liquidation_date TEXT NOT NULL,
             orig_value REAL AS (quantity * orig_price),
-            sell_value REAL AS (quantity * sell_price)
+            sell_value REAL AS (quantity * sell_price),
+            purchase_date TEXT,
+            purchase_txn_id INTEGER,
+            sale_txn_id INTEGER
         )",
         [],
     )?;
+    // Lot links: txn_id is the all_transactions row that created a lot, and every disposal records
+    // the lot it consumed. Rows written before these columns existed are filled in by the next
+    // replay of the ledger.
+    add_column_if_missing(&conn, "timber_purchases", "txn_id", "INTEGER")?;
+    add_column_if_missing(&conn, "used_timber", "purchase_date", "TEXT")?;
+    add_column_if_missing(&conn, "used_timber", "purchase_txn_id", "INTEGER")?;
+    add_column_if_missing(&conn, "used_timber", "sale_txn_id", "INTEGER")?;
+    // rewind_to looks lots and disposals up by the ledger rows they came from.
+    conn.execute("CREATE INDEX IF NOT EXISTS idx_timber_purchases_txn_id ON timber_purchases (txn_id)", [])?;
+    conn.execute("CREATE INDEX IF NOT EXISTS idx_used_timber_purchase_txn_id ON used_timber (purchase_txn_id)", [])?;
+    conn.execute("CREATE INDEX IF NOT EXISTS idx_used_timber_sale_txn_id ON used_timber (sale_txn_id)", [])?;
     conn.execute(
         "CREATE TABLE IF NOT EXISTS all_transactions (
             id INTEGER PRIMARY KEY AUTOINCREMENT,
@@ -142,68 +215,436 @@ pub fn connect_and_setup_db() -> Result<Connection> {
             sell_price REAL,
             liquidation_date TEXT,
             purchase_date TEXT,
-            is_used BOOLEAN NOT NULL
+            is_used BOOLEAN NOT NULL,
+            external_ref TEXT,
+            is_reward BOOLEAN NOT NULL DEFAULT 0
         )",
         [],
     )?;
-
+    // Databases created before external_ref existed get the column added and backfilled with
+    // content hashes. Rows that are already exact duplicates of an earlier row keep NULL and are
+    // left for find_duplicate_transactions to flag.
+    let external_ref_added = add_column_if_missing(&conn, "all_transactions", "external_ref", "TEXT")?;
+    // Rewards are acquisitions like purchases, but their value at receipt is reported as income.
+    add_column_if_missing(&conn, "all_transactions", "is_reward", "BOOLEAN NOT NULL DEFAULT 0")?;
+    conn.execute(
+        "CREATE UNIQUE INDEX IF NOT EXISTS idx_all_transactions_external_ref ON all_transactions (external_ref)",
+        [],
+    )?;
+    if external_ref_added {
+        backfill_content_hashes(&conn)?;
+    }
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS duplicate_candidates (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            transaction_id INTEGER NOT NULL,
+            duplicate_of_id INTEGER NOT NULL,
+            status TEXT NOT NULL DEFAULT 'pending',
+            UNIQUE (transaction_id, duplicate_of_id)
+        )",
+        [],
+    )?;
+    // Market prices per unit, used to value holdings at past dates.
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS price_history (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            price_date TEXT NOT NULL UNIQUE,
+            price REAL NOT NULL
+        )",
+        [],
+    )?;
+    // Ledger and price dates that rewrite_legacy_dates took as UTC, with the value it gave them, for
+    // timestamp::reinterpret_legacy_dates.
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS legacy_dates (
+            table_name TEXT NOT NULL,
+            column_name TEXT NOT NULL,
+            row_id INTEGER NOT NULL,
+            migrated_to TEXT NOT NULL,
+            PRIMARY KEY (table_name, column_name, row_id)
+        )",
+        [],
+    )?;
+    // Fiscal years whose returns have been filed, with the disposal totals they were filed with.
+    // See periods.rs.
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS closed_periods (
+            year INTEGER PRIMARY KEY,
+            closed_at TEXT NOT NULL,
+            quantity REAL NOT NULL,
+            proceeds REAL NOT NULL,
+            cost_basis REAL NOT NULL
+        )",
+        [],
+    )?;
+    // Append-only history of changes to the ledger and settings; see audit.rs.
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS audit_log (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            recorded_at TEXT NOT NULL,
+            actor TEXT NOT NULL,
+            command TEXT NOT NULL,
+            action TEXT NOT NULL,
+            table_name TEXT NOT NULL,
+            row_id INTEGER,
+            before_image TEXT,
+            after_image TEXT
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TRIGGER IF NOT EXISTS audit_log_no_update BEFORE UPDATE ON audit_log
+         BEGIN SELECT RAISE(ABORT, 'audit_log is append-only'); END",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TRIGGER IF NOT EXISTS audit_log_no_delete BEFORE DELETE ON audit_log
+         BEGIN SELECT RAISE(ABORT, 'audit_log is append-only'); END",
+        [],
+    )?;
+    conn.execute("CREATE INDEX IF NOT EXISTS idx_audit_log_row ON audit_log (table_name, row_id)", [])?;
+    // Backup and entry id that an entry brought in by import_backup had in its own database.
+    add_column_if_missing(&conn, "audit_log", "imported_from", "TEXT")?;
+    migrate_ledger_format(&conn)?;
 
     Ok(conn)
 }
 
 
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, definition: &str) -> Result<bool> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let mut names = stmt.query_map([], |row| row.get::<_, String>(1))?;
+    if names.any(|name| name.map(|n| n == column).unwrap_or(false)) {
+        return Ok(false);
+    }
+    conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), [])?;
+    Ok(true)
+}
+
+fn backfill_content_hashes(conn: &Connection) -> Result<()> {
+    let mut stmt = conn.prepare("SELECT * FROM all_transactions WHERE external_ref IS NULL ORDER BY id")?;
+    let rows = stmt.query_map([], transaction_from_row)?;
+    for row in rows {
+        let txn = row?;
+        conn.execute(
+            "UPDATE OR IGNORE all_transactions SET external_ref = ?1 WHERE id = ?2",
+            params![content_hash(&txn), txn.id],
+        )?;
+    }
+    Ok(())
+}
+
+// Format of the stored dates and generated references, kept in app_settings.ledger_format:
+// 1 stores UTC ISO 8601 dates, 2 also hashes is_reward into generated references.
+const LEDGER_FORMAT: i64 = 2;
+
+// Brings a database written in an older ledger format up to date, once: dates are rewritten by
+// timestamp::rewrite_legacy_dates and generated references recomputed, as they hash the dates and
+// whether a row is a reward. The rewritten ledger rows are recorded in the audit log.
+fn migrate_ledger_format(conn: &Connection) -> Result<()> {
+    let format: i64 = conn.query_row("SELECT ledger_format FROM app_settings WHERE id = 1", [], |row| row.get(0))?;
+    if format >= LEDGER_FORMAT {
+        return Ok(());
+    }
+    let tx = conn.unchecked_transaction()?;
+    let ledger_before = audit::ledger_images(&tx)?;
+    if format < 1 {
+        timestamp::rewrite_legacy_dates(&tx)?;
+    }
+    rehash_generated_refs(&tx)?;
+    audit::record_ledger_changes(&tx, "migrate_ledger_format", &ledger_before)?;
+    tx.execute("UPDATE app_settings SET ledger_format = ?1 WHERE id = 1", params![LEDGER_FORMAT])?;
+    tx.commit()
+}
+
+// Recomputes the generated reference of every ledger row that has one. A row whose content now
+// matches an earlier row's gets a numbered reference, as append_to_ledger gives an allowed
+// duplicate, and the pair is flagged in duplicate_candidates for review.
+fn rehash_generated_refs(conn: &Connection) -> Result<()> {
+    let mut stmt = conn.prepare("SELECT * FROM all_transactions WHERE external_ref LIKE 'hash:%' ORDER BY id")?;
+    let transactions = stmt.query_map([], transaction_from_row)?.collect::<Result<Vec<_>>>()?;
+    for txn in transactions {
+        let hash = content_hash(&txn);
+        let current = txn.external_ref.unwrap_or_default();
+        if current == hash || current.starts_with(&format!("{}#", hash)) {
+            continue;
+        }
+        let mut external_ref = hash.clone();
+        let mut occurrence = 1;
+        while let Some(other) = conn
+            .query_row("SELECT id FROM all_transactions WHERE external_ref = ?1", params![external_ref], |row| row.get::<_, i32>(0))
+            .optional()?
+        {
+            conn.execute(
+                "INSERT OR IGNORE INTO duplicate_candidates (transaction_id, duplicate_of_id) VALUES (?1, ?2)",
+                params![txn.id, other],
+            )?;
+            occurrence += 1;
+            external_ref = format!("{}#{}", hash, occurrence);
+        }
+        conn.execute("UPDATE all_transactions SET external_ref = ?1 WHERE id = ?2", params![external_ref, txn.id])?;
+    }
+    Ok(())
+}
+
+fn transaction_from_row(row: &rusqlite::Row) -> Result<AllTransactions> {
+    Ok(AllTransactions {
+        id: row.get(0)?,
+        quantity: row.get(1)?,
+        price_per_ton: row.get(2)?,
+        orig_price: row.get(3)?,
+        sell_price: row.get(4)?,
+        liquidation_date: row.get(5)?,
+        purchase_date: row.get(6)?,
+        is_used: row.get(7)?,
+        external_ref: row.get(8)?,
+        is_reward: row.get(9)?,
+    })
+}
+
+/// Reference stored for ledger rows that were not given one by the caller. FNV-1a is used rather
+/// than std's DefaultHasher because the value is persisted and must not change between builds.
+/// Rewards are marked, so a reward and a purchase of the same amount at the same price and time
+/// are different rows; other rows hash as they did before rewards existed.
+fn content_hash(txn: &AllTransactions) -> String {
+    let mut canonical = format!(
+        "{}|{:?}|{:?}|{:?}|{:?}|{:?}|{:?}",
+        txn.is_used, txn.quantity, txn.price_per_ton, txn.orig_price, txn.sell_price, txn.purchase_date, txn.liquidation_date
+    );
+    if txn.is_reward {
+        canonical.push_str("|reward");
+    }
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in canonical.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    format!("hash:{:016x}", hash)
+}
+
+// Inserts into all_transactions and the audit log, returning the new row's id, or None if a row
+// with the same external reference (or the same content, when no reference is given) is already
+// in the ledger. With `allow_duplicate`, a row whose content matches an earlier one is recorded
+// anyway under a numbered reference ("hash:...#2"), and its pairs with the earlier rows go into
+// duplicate_candidates as already discarded so find_duplicate_transactions does not flag them.
+// Recording commands append here and then apply the row to the lots; replays only apply
+// (apply_transaction), so the lot tables are a projection of all_transactions and replaying never
+// changes the ledger.
+fn append_to_ledger(conn: &Connection, txn: &AllTransactions, command: &str, allow_duplicate: bool) -> Result<Option<i32>> {
+    let hash = content_hash(txn);
+    let mut external_ref = txn.external_ref.clone().unwrap_or_else(|| hash.clone());
+    let mut duplicate_of = Vec::new();
+    if allow_duplicate && txn.external_ref.is_none() {
+        let mut occurrence = 1;
+        while let Some(id) = conn
+            .query_row("SELECT id FROM all_transactions WHERE external_ref = ?1", params![external_ref], |row| row.get::<_, i32>(0))
+            .optional()?
+        {
+            duplicate_of.push(id);
+            occurrence += 1;
+            external_ref = format!("{}#{}", hash, occurrence);
+        }
+    }
+    let inserted = conn.execute(
+        "INSERT OR IGNORE INTO all_transactions (quantity, price_per_ton, orig_price, sell_price, liquidation_date, purchase_date, is_used, external_ref, is_reward) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        params![txn.quantity, txn.price_per_ton, txn.orig_price, txn.sell_price, txn.liquidation_date, txn.purchase_date, txn.is_used, external_ref, txn.is_reward],
+    )?;
+    if inserted == 0 {
+        return Ok(None);
+    }
+    let txn_id = conn.last_insert_rowid() as i32;
+    for duplicate_of in duplicate_of {
+        conn.execute(
+            "INSERT OR IGNORE INTO duplicate_candidates (transaction_id, duplicate_of_id, status) VALUES (?1, ?2, 'discarded')",
+            params![txn_id, duplicate_of],
+        )?;
+    }
+    audit::record_change(conn, command, "all_transactions", Some(txn_id as i64), None, audit::ledger_image(conn, txn_id)?.as_ref())?;
+    Ok(Some(txn_id))
+}
+
 #[tauri::command]
-fn redo_transactions() -> Result<(), AppError> { // clear the timber_purchases and used_timber tables and reprocess all_transactions and reinsert into timber_purchases and used_timber
+fn redo_transactions(override_closed_period: Option<bool>) -> Result<(), AppError> {
+    snapshots::take_snapshot("redo_transactions")?;
+    replay_changes(override_closed_period.unwrap_or(false))
+}
+
+// Where a ledger row falls in a replay: its effective date, then its id, so rows on the same date
+// keep the order they were recorded in. Rows without a date sort first, as "".
+type ReplayPosition = (String, i32);
+
+fn replay_position(conn: &Connection, txn_id: i32) -> Result<Option<ReplayPosition>> {
+    conn.query_row(
+        "SELECT COALESCE(CASE WHEN is_used THEN liquidation_date ELSE purchase_date END, ''), id FROM all_transactions WHERE id = ?1",
+        params![txn_id],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+    .optional()
+}
+
+// Earliest position changed since the lot tables were last replayed, if any.
+fn replay_checkpoint(conn: &Connection) -> Result<Option<ReplayPosition>> {
+    let (date, txn_id): (String, Option<i32>) = conn.query_row(
+        "SELECT COALESCE(replay_from_date, ''), replay_from_txn_id FROM app_settings WHERE id = 1",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    Ok(txn_id.map(|txn_id| (date, txn_id)))
+}
+
+// Records that the lot tables no longer match the ledger from ledger row `txn_id`'s position on,
+// so the next replay_changes starts there. Edits call this with the row as it was and as it is
+// now, removals before the row is deleted.
+fn mark_changed_at(conn: &Connection, txn_id: i32) -> Result<()> {
+    if let Some(position) = replay_position(conn, txn_id)? {
+        if replay_checkpoint(conn)?.map(|checkpoint| position < checkpoint).unwrap_or(true) {
+            conn.execute(
+                "UPDATE app_settings SET replay_from_date = ?1, replay_from_txn_id = ?2 WHERE id = 1",
+                params![position.0, position.1],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+// True when ledger row `txn_id` takes effect before rows that are already in the ledger, so
+// applying it on top of the current lots would put it out of date order.
+fn is_backdated(conn: &Connection, txn_id: i32) -> Result<bool> {
+    let position = match replay_position(conn, txn_id)? {
+        Some(position) => position,
+        None => return Ok(false),
+    };
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM all_transactions
+                        WHERE (COALESCE(CASE WHEN is_used THEN liquidation_date ELSE purchase_date END, ''), id) > (?1, ?2))",
+        params![position.0, position.1],
+        |row| row.get(0),
+    )
+}
+
+// Commands that already took their own snapshot call these directly instead of redo_transactions.
+
+// Clears timber_purchases and used_timber and reapplies the whole ledger, in one database
+// transaction. Unless `override_lock` is set, the transaction is rolled back when the replay would
+// change a closed fiscal year.
+fn replay_ledger(override_lock: bool) -> Result<(), AppError> {
     let conn = connect_and_setup_db()?;
-    conn.execute("DELETE FROM timber_purchases", [])?;
-    conn.execute("DELETE FROM used_timber", [])?;
+    let tx = conn.unchecked_transaction()?;
+    let guard = periods::ReplayGuard::new(&tx)?;
+    tx.execute("DELETE FROM timber_purchases", [])?;
+    tx.execute("DELETE FROM used_timber", [])?;
+    replay_into(&tx, &tx, None, None)?;
+    guard.check(&tx, override_lock)?;
+    tx.execute("UPDATE app_settings SET replay_from_date = NULL, replay_from_txn_id = NULL WHERE id = 1", [])?;
+    tx.commit()?;
+    Ok(())
+}
 
-    // Step 2: Iterate through all_transactions table
-    let mut stmt = conn.prepare("SELECT * FROM all_transactions")?;
-    let transaction_iter = stmt.query_map([], |row| {
-        Ok(AllTransactions {
-            id: row.get(0)?,
-            quantity: row.get(1)?,
-            price_per_ton: row.get(2)?,
-            orig_price: row.get(3)?,
-            sell_price: row.get(4)?,
-            liquidation_date: row.get(5)?,
-            purchase_date: row.get(6)?,
-            is_used: row.get(7)?,
-        })
-    })?;
+// Brings the lot tables up to date with the ledger. When rows were added, edited or removed since
+// the last replay, the lots are rewound to just before the earliest of them and only the rows from
+// there on are reapplied. Without that checkpoint, or with lots a rewind cannot undo (lots that
+// predate the ledger links, or disposals applied out of date order by an older version), the whole
+// ledger is replayed. Closed fiscal years are protected as in replay_ledger.
+fn replay_changes(override_lock: bool) -> Result<(), AppError> {
+    let conn = connect_and_setup_db()?;
+    let from = replay_checkpoint(&conn)?;
+    let rewindable: bool = conn.query_row(
+        "SELECT NOT EXISTS (SELECT 1 FROM timber_purchases WHERE txn_id IS NULL)
+            AND NOT EXISTS (SELECT 1 FROM used_timber WHERE purchase_txn_id IS NULL OR sale_txn_id IS NULL
+                                OR (COALESCE(purchase_date, ''), purchase_txn_id) > (liquidation_date, sale_txn_id))",
+        [],
+        |row| row.get(0),
+    )?;
+    let from = match from {
+        Some(from) if rewindable => from,
+        _ => {
+            drop(conn);
+            return replay_ledger(override_lock);
+        }
+    };
+
+    let tx = conn.unchecked_transaction()?;
+    let guard = periods::ReplayGuard::new(&tx)?;
+    rewind_to(&tx, &from)?;
+    replay_into(&tx, &tx, None, Some(&from))?;
+    guard.check(&tx, override_lock)?;
+    tx.execute("UPDATE app_settings SET replay_from_date = NULL, replay_from_txn_id = NULL WHERE id = 1", [])?;
+    tx.commit()?;
+    Ok(())
+}
+
+// Puts the lot tables back as they were before the replay reached `from`, using the disposals
+// recorded since: lots created from `from` on are removed, and lots that later sales consumed get
+// their quantity back. Quantities are recomputed in f32 the way apply_use reduces them, so the
+// lots come out exactly as a full replay would leave them.
+fn rewind_to(conn: &Connection, from: &ReplayPosition) -> Result<(), AppError> {
+    let mut stmt = conn.prepare(
+        "SELECT DISTINCT purchase_txn_id FROM used_timber
+         WHERE (liquidation_date, sale_txn_id) >= (?1, ?2) AND (COALESCE(purchase_date, ''), purchase_txn_id) < (?1, ?2)",
+    )?;
+    let consumed = stmt.query_map(params![from.0, from.1], |row| row.get::<_, i32>(0))?.collect::<Result<Vec<_>>>()?;
+    conn.execute("DELETE FROM used_timber WHERE (liquidation_date, sale_txn_id) >= (?1, ?2)", params![from.0, from.1])?;
+    conn.execute("DELETE FROM timber_purchases WHERE (purchase_date, txn_id) >= (?1, ?2)", params![from.0, from.1])?;
+
+    for txn_id in consumed {
+        conn.prepare_cached("DELETE FROM timber_purchases WHERE txn_id = ?1")?.execute(params![txn_id])?;
+        let txn = match conn
+            .prepare_cached("SELECT * FROM all_transactions WHERE id = ?1")?
+            .query_row(params![txn_id], transaction_from_row)
+            .optional()?
+        {
+            Some(txn) => txn,
+            None => continue,
+        };
+        let mut quantity = txn.quantity as f32;
+        let mut used = conn.prepare_cached("SELECT quantity FROM used_timber WHERE purchase_txn_id = ?1 ORDER BY id")?;
+        for used_quantity in used.query_map(params![txn_id], |row| row.get::<_, f32>(0))? {
+            quantity -= used_quantity?;
+        }
+        if let (true, Some(purchase_date)) = (quantity > 0.0, &txn.purchase_date) {
+            apply_purchase(conn, txn.id, quantity, txn.price_per_ton.unwrap_or(0.0), purchase_date)?;
+        }
+    }
+    Ok(())
+}
+
+// Applies the rows of `ledger`'s all_transactions to the lot tables of `lots`, which may be the
+// same connection or a scratch database, in date order with ties kept in the order they were
+// recorded (see ReplayPosition). With `until`, rows taking effect after that date are skipped;
+// with `from`, rows before that position are.
+fn replay_into(lots: &Connection, ledger: &Connection, until: Option<&str>, from: Option<&ReplayPosition>) -> Result<(), AppError> {
+    let mut stmt = ledger.prepare(
+        "SELECT * FROM all_transactions
+         WHERE (?1 IS NULL OR (CASE WHEN is_used THEN liquidation_date ELSE purchase_date END) <= ?1)
+           AND (?3 IS NULL OR (COALESCE(CASE WHEN is_used THEN liquidation_date ELSE purchase_date END, ''), id) >= (?2, ?3))
+         ORDER BY CASE WHEN is_used THEN liquidation_date ELSE purchase_date END, id",
+    )?;
+    let transaction_iter = stmt.query_map(params![until, from.map(|from| &from.0), from.map(|from| from.1)], transaction_from_row)?;
 
     for transaction in transaction_iter {
-        let txn = transaction?;
-        if !txn.is_used {
-            // Convert purchase_date to DateTime
-            if let Some(date_str) = txn.purchase_date {
-                match DateTime::from_string(&date_str) {
-                    Ok(date_time) => {
-                        let _ = record_purchase(txn.quantity as f32, txn.price_per_ton.unwrap_or(0.0), date_time);
-                        // Handle error or log as needed
-                    },
-                    Err(e) => println!("Error parsing date: {}", e), // Handle or log the error as needed
-                }
-            }
-        } else {
-            // Convert liquidation_date to DateTime
-            if let Some(date_str) = txn.liquidation_date {
-                match DateTime::from_string(&date_str) {
-                    Ok(date_time) => {
-                        let _ = use_tao(txn.quantity as f32, date_time, txn.sell_price.unwrap_or(0.0));
-                        // Handle Result<Vec<Spec>, AppError> as needed
-                    },
-                    Err(e) => println!("Error parsing date: {}", e), // Handle or log the error as needed
-                }
-            }
+        apply_transaction(lots, &transaction?)?;
+    }
+    Ok(())
+}
+
+// Applies one ledger row to the lot tables, never writing to all_transactions. Undated rows are
+// skipped; a date that does not parse fails with InvalidDate, and a row that cannot be applied with
+// its error, so the replay it belongs to is rolled back instead of leaving the row out.
+fn apply_transaction(lots: &Connection, txn: &AllTransactions) -> Result<(), AppError> {
+    if !txn.is_used {
+        if let Some(date_str) = &txn.purchase_date {
+            timestamp::parse_stored(date_str)?;
+            apply_purchase(lots, txn.id, txn.quantity as f32, txn.price_per_ton.unwrap_or(0.0), date_str)?;
         }
+    } else if let Some(date_str) = &txn.liquidation_date {
+        timestamp::parse_stored(date_str)?;
+        apply_use(lots, txn.id, txn.quantity as f32, date_str, txn.sell_price.unwrap_or(0.0))?;
     }
     Ok(())
 }
 
 #[tauri::command]
-fn record_purchase(quantity: f32, price_per_ton: f64, date_time: DateTime) -> Result<(),AppError> { // need to add part of this method to insert the transactions here as well 
+fn record_purchase(quantity: f32, price_per_ton: f64, date_time: DateTime, external_ref: Option<String>, is_reward: Option<bool>, override_closed_period: Option<bool>, allow_duplicate: Option<bool>) -> Result<(),AppError> {
 
     let conn_result = connect_and_setup_db();
     let conn = match conn_result {
@@ -211,26 +652,51 @@ fn record_purchase(quantity: f32, price_per_ton: f64, date_time: DateTime) -> Re
         Err(e) => return Err(AppError::DatabaseError(e)),
     };
 
-    let date_time_str = date_time.to_string();
-    let _execute_result = conn.execute(
-        "INSERT INTO timber_purchases (quantity, price_per_ton, purchase_date) VALUES (?1, ?2, ?3)",
-        params![quantity, price_per_ton, date_time_str],
-    );
-
-    
-    let execute_all_transactions = conn.execute(
-        "INSERT INTO all_transactions (quantity, price_per_ton, purchase_date, is_used) VALUES (?1, ?2, ?3, ?4)",
-        params![quantity, price_per_ton, date_time_str, false],
-    );
-    match execute_all_transactions {
-        Ok(_) => Ok(()),
-        Err(e) => return Err(AppError::DatabaseError(e)),
+    let date_time_str = date_time.to_stored(&conn)?;
+    let override_lock = override_closed_period.unwrap_or(false);
+    periods::ensure_open(&conn, &date_time_str, override_lock)?;
+    let entry = AllTransactions {
+        id: 0,
+        quantity: quantity as f64,
+        price_per_ton: Some(price_per_ton),
+        orig_price: None,
+        sell_price: None,
+        liquidation_date: None,
+        purchase_date: Some(date_time_str.clone()),
+        is_used: false,
+        external_ref,
+        is_reward: is_reward.unwrap_or(false),
+    };
+    // The row is recorded together with its lot, or with the checkpoint that replays it.
+    let tx = conn.unchecked_transaction()?;
+    let txn_id = match append_to_ledger(&tx, &entry, "record_purchase", allow_duplicate.unwrap_or(false))? {
+        Some(txn_id) => txn_id,
+        None => return Err(AppError::DuplicateTransaction(format!("purchase of {} on {} is already recorded; allow duplicates to record it again", quantity, date_time_str))),
+    };
+    // A back-dated purchase is replayed into place, as later sales may have consumed other lots.
+    if is_backdated(&tx, txn_id)? {
+        mark_changed_at(&tx, txn_id)?;
+        tx.commit()?;
+        drop(conn);
+        return replay_changes(override_lock);
     }
+    apply_purchase(&tx, txn_id, quantity, price_per_ton, &date_time_str)?;
+    tx.commit()?;
+    Ok(())
+}
 
+// Adds a lot to timber_purchases without touching the ledger; used both for new purchases and
+// when redo_transactions replays all_transactions.
+fn apply_purchase(conn: &Connection, txn_id: i32, quantity: f32, price_per_ton: f64, purchase_date: &str) -> Result<()> {
+    conn.execute(
+        "INSERT INTO timber_purchases (quantity, price_per_ton, purchase_date, txn_id) VALUES (?1, ?2, ?3, ?4)",
+        params![quantity, price_per_ton, purchase_date, txn_id],
+    )?;
+    Ok(())
 }
 
 #[tauri::command]
-fn add_transaction(quantity: f32, price_per_ton: f64, orig_price: f64, sell_price: f64, purchase_date: DateTime, liquidation_date: DateTime, is_used: bool) -> String {
+fn add_transaction(quantity: f32, price_per_ton: f64, orig_price: f64, sell_price: f64, purchase_date: DateTime, liquidation_date: DateTime, is_used: bool, external_ref: Option<String>, is_reward: Option<bool>, override_closed_period: Option<bool>, allow_duplicate: Option<bool>) -> String {
     println!("Recording things here");
     let conn_result = connect_and_setup_db();
     let conn = match conn_result {
@@ -238,15 +704,32 @@ fn add_transaction(quantity: f32, price_per_ton: f64, orig_price: f64, sell_pric
         Err(e) => return format!("Error connecting to database: {}", e),
     };
     println!("Connected to database");
-    let purchase_date_str = purchase_date.to_string();
-    let liquidation_date_str = liquidation_date.to_string();
-    let execute_result = conn.execute(
-        "INSERT INTO all_transactions (quantity, price_per_ton, orig_price, sell_price, purchase_date, liquidation_date, is_used) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
-        params![quantity, price_per_ton, orig_price, sell_price, purchase_date_str, liquidation_date_str, is_used],
-    );
+    let (purchase_date, liquidation_date) = match (purchase_date.to_stored(&conn), liquidation_date.to_stored(&conn)) {
+        (Ok(purchase_date), Ok(liquidation_date)) => (purchase_date, liquidation_date),
+        (Err(e), _) | (_, Err(e)) => return e.to_string(),
+    };
+    let effective_date = if is_used { &liquidation_date } else { &purchase_date };
+    if let Err(e) = periods::ensure_open(&conn, effective_date, override_closed_period.unwrap_or(false)) {
+        return e.to_string();
+    }
+    let entry = AllTransactions {
+        id: 0,
+        quantity: quantity as f64,
+        price_per_ton: Some(price_per_ton),
+        orig_price: Some(orig_price),
+        sell_price: Some(sell_price),
+        liquidation_date: Some(liquidation_date),
+        purchase_date: Some(purchase_date),
+        is_used,
+        external_ref,
+        is_reward: is_reward.unwrap_or(false),
+    };
+    let execute_result = append_to_ledger(&conn, &entry, "add_transaction", allow_duplicate.unwrap_or(false))
+        .and_then(|inserted| match inserted { Some(txn_id) => mark_changed_at(&conn, txn_id).map(|_| true), None => Ok(false) });
     println!("Executed query");
     match execute_result {
-        Ok(_) => "Completed".to_string(),
+        Ok(true) => "Completed".to_string(),
+        Ok(false) => "Duplicate transaction: already recorded".to_string(),
         Err(e) => format!("Error executing database operation: {}", e),
     }
 }
@@ -255,18 +738,7 @@ fn add_transaction(quantity: f32, price_per_ton: f64, orig_price: f64, sell_pric
 fn show_all_transactions() -> Result<Vec<AllTransactions>, AppError> {
     let conn = connect_and_setup_db()?;
     let mut stmt = conn.prepare("SELECT * FROM all_transactions")?;
-    let transaction_iter = stmt.query_map([], |row| {
-        Ok(AllTransactions {
-            id: row.get(0)?,
-            quantity: row.get(1)?,
-            price_per_ton: row.get(2)?,
-            orig_price: row.get(3)?,
-            sell_price: row.get(4)?,
-            liquidation_date: row.get(5)?,
-            purchase_date: row.get(6)?,
-            is_used: row.get(7)?,
-        })
-    })?;
+    let transaction_iter = stmt.query_map([], transaction_from_row)?;
 
     let mut transactions = Vec::new();
     for transaction in transaction_iter {
@@ -277,16 +749,24 @@ fn show_all_transactions() -> Result<Vec<AllTransactions>, AppError> {
 }
 
 #[tauri::command]
-fn remove_transaction_via_id(id: i32) -> String {
+fn remove_transaction_via_id(id: i32, override_closed_period: Option<bool>) -> String {
+    if let Err(e) = snapshots::take_snapshot("remove_transaction_via_id") {
+        return format!("Error taking snapshot: {}", e);
+    }
     let conn_result = connect_and_setup_db();
     let conn = match conn_result {
         Ok(conn) => conn,
         Err(e) => return format!("Error connecting to database: {}", e),
     };
-    let execute_result = conn.execute(
-        "DELETE FROM all_transactions WHERE id = ?1",
-        params![id],
-    );
+    if let Err(e) = periods::ensure_row_open(&conn, id, override_closed_period.unwrap_or(false)) {
+        return e.to_string();
+    }
+    let before = audit::ledger_image(&conn, id);
+    let execute_result = before.and_then(|before| {
+        mark_changed_at(&conn, id)?;
+        conn.execute("DELETE FROM all_transactions WHERE id = ?1", params![id])?;
+        audit::record_change(&conn, "remove_transaction_via_id", "all_transactions", Some(id as i64), before.as_ref(), None)
+    });
     match execute_result {
         Ok(_) => "Completed".to_string(),
         Err(e) => format!("Error executing database operation: {}", e),
@@ -294,30 +774,75 @@ fn remove_transaction_via_id(id: i32) -> String {
 }
 
 #[tauri::command]
-fn edit_transaction_via_id(id: i32, quantity: f32, price_per_ton: f64, orig_price: f64, sell_price: f64, purchase_date: DateTime, liquidation_date: DateTime, is_used: bool) -> String {
+fn edit_transaction_via_id(id: i32, quantity: f32, price_per_ton: f64, orig_price: f64, sell_price: f64, purchase_date: DateTime, liquidation_date: DateTime, is_used: bool, is_reward: Option<bool>, override_closed_period: Option<bool>) -> String {
+    if let Err(e) = snapshots::take_snapshot("edit_transaction_via_id") {
+        return format!("Error taking snapshot: {}", e);
+    }
     let conn_result = connect_and_setup_db();
     let conn = match conn_result {
         Ok(conn) => conn,
         Err(e) => return format!("Error connecting to database: {}", e),
     };
-    let purchase_date_str = purchase_date.to_string();
-    let liquidation_date_str = liquidation_date.to_string();
-    let execute_result = conn.execute(
-        "UPDATE all_transactions SET quantity = ?1, price_per_ton = ?2, orig_price = ?3, sell_price = ?4, purchase_date = ?5, liquidation_date = ?6, is_used = ?7 WHERE id = ?8",
-        params![quantity, price_per_ton, orig_price, sell_price, purchase_date_str, liquidation_date_str, is_used, id],
-    );
+    let (purchase_date_str, liquidation_date_str) = match (purchase_date.to_stored(&conn), liquidation_date.to_stored(&conn)) {
+        (Ok(purchase_date), Ok(liquidation_date)) => (purchase_date, liquidation_date),
+        (Err(e), _) | (_, Err(e)) => return e.to_string(),
+    };
+    // Both the row as it was and as it will be must be clear of closed years.
+    let override_lock = override_closed_period.unwrap_or(false);
+    let effective_date = if is_used { &liquidation_date_str } else { &purchase_date_str };
+    if let Err(e) = periods::ensure_row_open(&conn, id, override_lock).and_then(|_| periods::ensure_open(&conn, effective_date, override_lock)) {
+        return e.to_string();
+    }
+    // The edit and its checkpoint move together, so a refused edit leaves the replay where it was.
+    let execute_result = conn.unchecked_transaction().map_err(AppError::from).and_then(|tx| {
+        let before = audit::ledger_image(&tx, id)?;
+        // Generated references follow the row's content, keeping the number of an allowed
+        // duplicate; references supplied by an import are kept. Without `is_reward` the row stays
+        // the kind it was.
+        let edited = AllTransactions {
+            id,
+            quantity: quantity as f64,
+            price_per_ton: Some(price_per_ton),
+            orig_price: Some(orig_price),
+            sell_price: Some(sell_price),
+            liquidation_date: Some(liquidation_date_str.clone()),
+            purchase_date: Some(purchase_date_str.clone()),
+            is_used,
+            external_ref: None,
+            is_reward: is_reward
+                .or_else(|| before.as_ref().and_then(|before| before.get("is_reward")).and_then(serde_json::Value::as_bool))
+                .unwrap_or(false),
+        };
+        mark_changed_at(&tx, id)?;
+        tx.execute(
+            "UPDATE all_transactions SET quantity = ?1, price_per_ton = ?2, orig_price = ?3, sell_price = ?4, purchase_date = ?5, liquidation_date = ?6, is_used = ?7,
+                external_ref = CASE WHEN external_ref LIKE ?9 || '#%' THEN external_ref
+                                    WHEN external_ref IS NULL OR external_ref LIKE 'hash:%' THEN ?9 ELSE external_ref END,
+                is_reward = ?10
+             WHERE id = ?8",
+            params![quantity, price_per_ton, orig_price, sell_price, purchase_date_str, liquidation_date_str, is_used, id, content_hash(&edited), edited.is_reward],
+        )
+        .map_err(|e| match e {
+            rusqlite::Error::SqliteFailure(failure, _) if failure.code == rusqlite::ErrorCode::ConstraintViolation => {
+                AppError::DuplicateTransaction(format!("transaction {} edited this way is the same as a transaction already recorded", id))
+            }
+            e => AppError::DatabaseError(e),
+        })?;
+        mark_changed_at(&tx, id)?;
+        audit::record_change(&tx, "edit_transaction_via_id", "all_transactions", Some(id as i64), before.as_ref(), audit::ledger_image(&tx, id)?.as_ref())?;
+        tx.commit()?;
+        Ok(())
+    });
     match execute_result {
         Ok(_) => "Completed".to_string(),
+        Err(e @ AppError::DuplicateTransaction(_)) => e.to_string(),
         Err(e) => format!("Error executing database operation: {}", e),
     }
 }
 
-#[tauri::command]
-fn check_inventory(quantity_needed: f32) -> Result<bool> {
-    let conn = connect_and_setup_db()?;
-
+fn check_inventory(conn: &Connection, quantity_needed: f32) -> Result<bool> {
     let total_quantity: f32 = conn.query_row(
-        "SELECT SUM(quantity) FROM timber_purchases",
+        "SELECT COALESCE(SUM(quantity), 0) FROM timber_purchases",
         [],
         |row| row.get(0),
     )?;
@@ -393,10 +918,16 @@ fn print_inventory_used() -> Result<Vec<TaoPurchase>, AppError> {
 }
 
 
+// With `year`, the disposal totals cover only that fiscal year; acquisition_value is always the
+// cost of what is held now.
 #[tauri::command]
-fn inventory_statistics()  -> Result<Statistics, AppError> {
+fn inventory_statistics(year: Option<i32>)  -> Result<Statistics, AppError> {
     let conn = connect_and_setup_db()?;
-
+    let bounds = match year {
+        Some(year) => Some(reports::fiscal_year_bounds(year, &reports::load_fiscal_year_start(&conn)?, timestamp::load_display_offset(&conn)?)),
+        None => None,
+    };
+    let (from, to) = (bounds.as_ref().map(|b| b.0.as_str()), bounds.as_ref().map(|b| b.1.as_str()));
 
     let acquisition_value: f64 = conn.query_row(
         "SELECT COALESCE(SUM(acquisition_value),0) FROM timber_purchases",
@@ -405,16 +936,16 @@ fn inventory_statistics()  -> Result<Statistics, AppError> {
     )?;
 
     let orig_value: f64 = conn.query_row(
-        "SELECT ROUND(COALESCE(SUM(orig_value),0), 2) FROM used_timber",
-        [],
+        "SELECT ROUND(COALESCE(SUM(orig_value),0), 2) FROM used_timber WHERE ?1 IS NULL OR (liquidation_date >= ?1 AND liquidation_date < ?2)",
+        params![from, to],
         |row| row.get(0),
     )?;
 
     
     
     let sell_value: f64 = conn.query_row(
-        "SELECT ROUND(COALESCE(SUM(sell_value), 0), 2) FROM used_timber",
-        [],
+        "SELECT ROUND(COALESCE(SUM(sell_value), 0), 2) FROM used_timber WHERE ?1 IS NULL OR (liquidation_date >= ?1 AND liquidation_date < ?2)",
+        params![from, to],
         |row| row.get(0),
     )?;
 
@@ -427,15 +958,51 @@ fn inventory_statistics()  -> Result<Statistics, AppError> {
 
 
 #[tauri::command]
-fn use_tao(quantity_needed: f32, liquidation_date_time: DateTime, selling_price : f64) -> Result<Vec<Spec>, AppError> {
+fn use_tao(quantity_needed: f32, liquidation_date_time: DateTime, selling_price : f64, external_ref: Option<String>, override_closed_period: Option<bool>, allow_duplicate: Option<bool>) -> Result<Vec<Spec>, AppError> {
     let conn = connect_and_setup_db()?;
 
-    let _execute_result = conn.execute(
-        "INSERT INTO all_transactions (quantity, sell_price, liquidation_date, is_used) VALUES (?1, ?2, ?3, ?4)",
-        params![quantity_needed, selling_price, liquidation_date_time.to_string(), true],
-    );
+    let liquidation_date_str = liquidation_date_time.to_stored(&conn)?;
+    let override_lock = override_closed_period.unwrap_or(false);
+    periods::ensure_open(&conn, &liquidation_date_str, override_lock)?;
+    let entry = AllTransactions {
+        id: 0,
+        quantity: quantity_needed as f64,
+        price_per_ton: None,
+        orig_price: None,
+        sell_price: Some(selling_price),
+        liquidation_date: Some(liquidation_date_str.clone()),
+        purchase_date: None,
+        is_used: true,
+        external_ref,
+        is_reward: false,
+    };
+    // As in record_purchase, the row is recorded together with its disposals or its checkpoint.
+    let tx = conn.unchecked_transaction()?;
+    let txn_id = match append_to_ledger(&tx, &entry, "use_tao", allow_duplicate.unwrap_or(false))? {
+        Some(txn_id) => txn_id,
+        None => return Err(AppError::DuplicateTransaction(format!("sale of {} on {} is already recorded; allow duplicates to record it again", quantity_needed, liquidation_date_str))),
+    };
 
-    if check_inventory(quantity_needed) == Ok(false) {
+    if is_backdated(&tx, txn_id)? {
+        // Replay from the sale's date, then report the disposals the replay recorded for it.
+        mark_changed_at(&tx, txn_id)?;
+        tx.commit()?;
+        replay_changes(override_lock)?;
+        let mut stmt = conn.prepare("SELECT quantity, orig_price, sell_price, liquidation_date FROM used_timber WHERE sale_txn_id = ?1 ORDER BY id")?;
+        let specs = stmt.query_map(params![txn_id], |row| {
+            Ok(Spec { quantity: row.get(0)?, orig_price: row.get(1)?, sale_price: row.get(2)?, liquidation_date: row.get(3)? })
+        })?;
+        return Ok(specs.collect::<Result<Vec<_>>>()?);
+    }
+    let specs = apply_use(&tx, txn_id, quantity_needed, &liquidation_date_str, selling_price)?;
+    tx.commit()?;
+    Ok(specs)
+}
+
+// Consumes lots from timber_purchases according to the configured usage_type and records the
+// disposals in used_timber. Like apply_purchase, this never writes to all_transactions.
+fn apply_use(conn: &Connection, sale_txn_id: i32, quantity_needed: f32, liquidation_date_str: &str, selling_price: f64) -> Result<Vec<Spec>, AppError> {
+    if check_inventory(conn, quantity_needed) == Ok(false) {
         println!("Not enough timber in inventory.");
         return Ok(Vec::new());
     }
@@ -447,11 +1014,15 @@ fn use_tao(quantity_needed: f32, liquidation_date_time: DateTime, selling_price
         |row| row.get(0)
     )?;
 
+    // Lots that tie on the method's key are taken in ledger order: by the sequence number (id) of
+    // the all_transactions row that created them, then by lot id for lots with no ledger row. LIFO
+    // reverses this along with the date. LOFO and HIFO break price ties by purchase date first,
+    // oldest first, so a replay always consumes the same lots in the same order.
     let mut stmt = match style {
-        1 => conn.prepare("SELECT id, quantity, price_per_ton FROM timber_purchases ORDER BY purchase_date ASC")?, // FIFO Implementation
-        2 => conn.prepare("SELECT id, quantity, price_per_ton FROM timber_purchases ORDER BY purchase_date DESC")?, // LIFO Implementation
-        3 => conn.prepare("SELECT id, quantity, price_per_ton FROM timber_purchases ORDER BY price_per_ton ASC")?, // LOFO Implementation
-        _ => conn.prepare("SELECT id, quantity, price_per_ton FROM timber_purchases ORDER BY price_per_ton DESC")?, // HIFO Implemenetation - can change to LIFO or FIFO accordingly
+        1 => conn.prepare("SELECT id, quantity, price_per_ton, purchase_date, txn_id FROM timber_purchases ORDER BY purchase_date ASC, txn_id ASC, id ASC")?, // FIFO Implementation
+        2 => conn.prepare("SELECT id, quantity, price_per_ton, purchase_date, txn_id FROM timber_purchases ORDER BY purchase_date DESC, txn_id DESC, id DESC")?, // LIFO Implementation
+        3 => conn.prepare("SELECT id, quantity, price_per_ton, purchase_date, txn_id FROM timber_purchases ORDER BY price_per_ton ASC, purchase_date ASC, txn_id ASC, id ASC")?, // LOFO Implementation
+        _ => conn.prepare("SELECT id, quantity, price_per_ton, purchase_date, txn_id FROM timber_purchases ORDER BY price_per_ton DESC, purchase_date ASC, txn_id ASC, id ASC")?, // HIFO Implemenetation - can change to LIFO or FIFO accordingly
     };
     
     let mut rows = stmt.query([])?;
@@ -460,6 +1031,8 @@ fn use_tao(quantity_needed: f32, liquidation_date_time: DateTime, selling_price
         let id: i32 = row.get(0)?;
         let quantity: f32 = row.get(1)?;
         let orig_price: f64 = row.get(2)?;
+        let purchase_date: String = row.get(3)?;
+        let purchase_txn_id: Option<i32> = row.get(4)?;
 
         let used_quantity = if quantity <= remaining_quantity { quantity } else { remaining_quantity };
         let _total_price = used_quantity as f64 * orig_price;
@@ -473,18 +1046,16 @@ fn use_tao(quantity_needed: f32, liquidation_date_time: DateTime, selling_price
         }
 
         // Record used timber
-        let liquidation_date_str = liquidation_date_time.to_string(); // Convert DateTime to string
-
         conn.execute(
-            "INSERT INTO used_timber (quantity, orig_price, sell_price, liquidation_date) VALUES (?1, ?2, ?3, ?4)",
-            params![used_quantity, orig_price, selling_price, liquidation_date_str],
+            "INSERT INTO used_timber (quantity, orig_price, sell_price, liquidation_date, purchase_date, purchase_txn_id, sale_txn_id) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![used_quantity, orig_price, selling_price, liquidation_date_str, purchase_date, purchase_txn_id, sale_txn_id],
         )?;
 
         used_timber.push(Spec {
             quantity: used_quantity,
             orig_price: orig_price,
             sale_price: selling_price,
-            liquidation_date: liquidation_date_str,
+            liquidation_date: liquidation_date_str.to_string(),
 
         });
 
@@ -499,108 +1070,248 @@ fn use_tao(quantity_needed: f32, liquidation_date_time: DateTime, selling_price
     Ok(used_timber)
 }
 
-#[tauri::command]
-fn write_inventory_to_excel() -> Result<(), AppError> {
-    let conn = connect_and_setup_db()?;
-    // Create a new workbook. Note that `Workbook::new` takes ownership of the path string
-    // and returns a Workbook object that we will own and manage.
-    let workbook = Workbook::new("/Users/j-supha/desktop/inventory_report.xlsx").unwrap(); // Or use '?' for error propagation
+fn main() {
+    tauri::Builder::default()
+        .setup(|_app| {
+            snapshots::start_scheduler();
+            Ok(())
+        })
+        .invoke_handler(tauri::generate_handler![greet, record_purchase, print_inventory, print_inventory_used, use_tao, excel::write_inventory_to_excel, inventory_statistics, redo_transactions, add_transaction, remove_transaction_via_id, add_transaction, edit_transaction_via_id, show_all_transactions, duplicates::find_duplicate_transactions, duplicates::resolve_duplicate, backup::export_backup, backup::import_backup, snapshots::list_snapshots, snapshots::restore_snapshot, snapshots::get_snapshot_settings, snapshots::set_snapshot_settings, csv_export::export_csv, form8949::form_8949, form8949::export_form_8949, journal::export_journal, pdf::export_pdf_report, pnl::realized_pnl_report, holdings::holdings_as_of, holdings::holdings_series, prices::record_price, prices::list_prices, prices::remove_price, performance::portfolio_performance, harvest::tax_loss_harvesting, tax::get_tax_profile, tax::set_tax_profile, tax::tax_profile_preset, tax::estimate_tax, reports::get_fiscal_year_start, reports::set_fiscal_year_start, timestamp::get_display_timezone, timestamp::set_display_timezone, timestamp::reinterpret_legacy_dates, backdating::check_backdated_entries, periods::list_closed_periods, periods::close_period, periods::reopen_period, periods::reopen_period_report, audit::list_audit_log, audit::restore_transaction_version, germany::germany_tax_report, australia::australia_cgt_report])
+        .run(tauri::generate_context!())
+        .expect("error while running tauri application");
+}
 
-    // Now that `workbook` is of type `Workbook`, you can call `add_worksheet` on it
-    let mut timber_sheet = workbook.add_worksheet(Some("Actual")).unwrap(); // Again, consider using '?' for real applications
-    let mut used_timber_sheet = workbook.add_worksheet(Some("Used")).unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{day, test_db};
+
+    #[test]
+    fn exact_duplicates_are_recorded_only_when_allowed() {
+        let _db = test_db();
+        record_purchase(10.0, 5.0, day(2024, 1, 1), None, None, None, None).unwrap();
+        assert!(matches!(record_purchase(10.0, 5.0, day(2024, 1, 1), None, None, None, None), Err(AppError::DuplicateTransaction(_))));
+        record_purchase(10.0, 5.0, day(2024, 1, 1), None, None, None, Some(true)).unwrap();
+        record_purchase(10.0, 5.0, day(2024, 1, 1), None, None, None, Some(true)).unwrap();
+        // A reference supplied by an import still identifies its row.
+        record_purchase(1.0, 5.0, day(2024, 2, 1), Some("ext-1".into()), None, None, None).unwrap();
+        assert!(record_purchase(1.0, 5.0, day(2024, 2, 1), Some("ext-1".into()), None, None, Some(true)).is_err());
+
+        let refs: Vec<String> = show_all_transactions().unwrap().into_iter().filter_map(|txn| txn.external_ref).collect();
+        assert_eq!(refs.len(), 4);
+        assert_eq!(refs[1], format!("{}#2", refs[0]));
+        assert_eq!(refs[2], format!("{}#3", refs[0]));
+        assert_eq!(refs[3], "ext-1");
+        assert!(duplicates::find_duplicate_transactions(0).unwrap().is_empty());
+        assert_eq!(print_inventory().unwrap().len(), 4);
+
+        // Editing an allowed duplicate without changing it keeps its reference. The rows are added
+        // with every field set, as an edit writes them.
+        let at = day(2024, 3, 1);
+        for allow_duplicate in [None, Some(true)] {
+            assert_eq!(add_transaction(2.0, 5.0, 0.0, 0.0, at.clone(), at.clone(), false, None, None, None, allow_duplicate), "Completed");
+        }
+        let duplicate = show_all_transactions().unwrap()[5].clone();
+        assert!(duplicate.external_ref.as_deref().unwrap().ends_with("#2"));
+        assert_eq!(edit_transaction_via_id(duplicate.id, 2.0, 5.0, 0.0, 0.0, at.clone(), at, false, None, None), "Completed");
+        assert_eq!(show_all_transactions().unwrap()[5].external_ref, duplicate.external_ref);
+    }
 
-    // Write headers for both sheets by calling a helper function (not shown here)
-    write_headers(&mut timber_sheet, &["ID", "Quantity", "Price", "Purchase Date"]);
-    write_headers(&mut used_timber_sheet, &["ID", "Quantity", "Orig Price", "Selling Price", "Liquidation Date"]);
+    #[test]
+    fn rewards_and_purchases_with_the_same_figures_are_different_rows() {
+        let _db = test_db();
+        record_purchase(1.0, 5.0, day(2024, 1, 1), None, Some(true), None, None).unwrap();
+        record_purchase(1.0, 5.0, day(2024, 1, 1), None, None, None, None).unwrap();
+        let all = show_all_transactions().unwrap();
+        assert_ne!(all[0].external_ref, all[1].external_ref);
+
+        // An edit that leaves is_reward out keeps the row a reward, and hashes it as one.
+        assert_eq!(edit_transaction_via_id(1, 1.0, 5.0, 0.0, 0.0, day(2024, 1, 1), day(2024, 1, 1), false, None, None), "Completed");
+        let edited = show_all_transactions().unwrap().remove(0);
+        assert!(edited.is_reward);
+        assert_eq!(edited.external_ref, Some(content_hash(&edited)));
+    }
 
-    // Query and write data to the "Actual" timber sheet
-    write_timber_purchases(&conn, &mut timber_sheet)?;
+    #[test]
+    fn edits_matching_another_row_are_refused_as_duplicates() {
+        let _db = test_db();
+        record_purchase(10.0, 5.0, day(2024, 1, 1), None, None, None, None).unwrap();
+        record_purchase(4.0, 5.0, day(2024, 1, 1), None, None, None, None).unwrap();
+        // Edits write orig_price and sell_price, so match the first row as an edit would leave it.
+        assert_eq!(edit_transaction_via_id(1, 10.0, 5.0, 0.0, 0.0, day(2024, 1, 1), day(2024, 1, 1), false, None, None), "Completed");
+        redo_transactions(None).unwrap();
+        let ledger = format!("{:?}", show_all_transactions().unwrap());
+
+        let refused = edit_transaction_via_id(2, 10.0, 5.0, 0.0, 0.0, day(2024, 1, 1), day(2024, 1, 1), false, None, None);
+        assert!(refused.starts_with("Duplicate transaction:"), "{}", refused);
+        assert_eq!(format!("{:?}", show_all_transactions().unwrap()), ledger);
+        let conn = connect_and_setup_db().unwrap();
+        assert_eq!(replay_checkpoint(&conn).unwrap(), None);
+        let edits: i64 = conn.query_row("SELECT COUNT(*) FROM audit_log WHERE row_id = 2 AND action = 'Edit'", [], |row| row.get(0)).unwrap();
+        assert_eq!(edits, 0);
+    }
 
-    // Query and write data to the "Used" timber sheet
-    write_used_timber(&conn, &mut used_timber_sheet)?;
+    #[test]
+    fn reward_references_from_before_format_2_are_recomputed() {
+        let _db = test_db();
+        let conn = connect_and_setup_db().unwrap();
+        let mut reward = AllTransactions {
+            id: 1,
+            quantity: 1.0,
+            price_per_ton: Some(5.0),
+            orig_price: None,
+            sell_price: None,
+            liquidation_date: None,
+            purchase_date: Some("2024-01-01T00:00:00Z".to_string()),
+            is_used: false,
+            external_ref: None,
+            is_reward: false,
+        };
+        let old_ref = content_hash(&reward);
+        conn.execute(
+            "INSERT INTO all_transactions (quantity, price_per_ton, purchase_date, is_used, external_ref, is_reward) VALUES (1, 5, '2024-01-01T00:00:00Z', 0, ?1, 1)",
+            params![old_ref],
+        )
+        .unwrap();
+        conn.execute("UPDATE app_settings SET ledger_format = 1 WHERE id = 1", []).unwrap();
+        drop(conn);
+
+        reward.is_reward = true;
+        let migrated = show_all_transactions().unwrap().remove(0);
+        assert_eq!(migrated.external_ref, Some(content_hash(&reward)));
+        assert_ne!(migrated.external_ref, Some(old_ref));
+    }
 
-    // Close the workbook. This is where the Excel file is actually written to disk.
-    // Again, using `unwrap()` for simplicity, but error handling is recommended.
-    workbook.close().unwrap();
+    #[test]
+    fn replaying_never_changes_the_ledger() {
+        let _db = test_db();
+        record_purchase(10.0, 5.0, day(2024, 1, 1), None, None, None, None).unwrap();
+        record_purchase(1.0, 6.0, day(2024, 1, 2), None, Some(true), None, None).unwrap();
+        use_tao(4.0, day(2024, 2, 1), 9.0, None, None, None).unwrap();
+        use_tao(20.0, day(2024, 2, 2), 9.0, None, None, None).unwrap(); // more than is held
+        add_transaction(2.0, 7.0, 0.0, 0.0, day(2023, 12, 1), day(2023, 12, 1), false, None, None, None, None);
+        let conn = connect_and_setup_db().unwrap();
+        let ledger = || {
+            let mut stmt = conn.prepare("SELECT * FROM all_transactions ORDER BY id").unwrap();
+            let rows = stmt.query_map([], transaction_from_row).unwrap();
+            format!("{:?}", rows.collect::<Result<Vec<_>>>().unwrap())
+        };
+        let audit_entries = || conn.query_row("SELECT COUNT(*) FROM audit_log", [], |row| row.get::<_, i64>(0)).unwrap();
+        let (recorded, audited) = (ledger(), audit_entries());
+
+        redo_transactions(None).unwrap();
+        let lots = lot_state();
+        for _ in 0..5 {
+            redo_transactions(None).unwrap();
+            replay_ledger(false).unwrap();
+            assert_eq!(ledger(), recorded);
+            assert_eq!(audit_entries(), audited);
+            assert_eq!(lot_state(), lots);
+        }
+    }
 
-    Ok(())
-}
+    #[test]
+    fn a_row_that_cannot_be_applied_rolls_the_replay_back() {
+        let _db = test_db();
+        record_purchase(10.0, 5.0, day(2024, 1, 1), None, None, None, None).unwrap();
+        use_tao(4.0, day(2024, 2, 1), 9.0, None, None, None).unwrap();
+        let lots = lot_state();
+        // A date the migration could not rewrite, on a row inserted before the sale.
+        let conn = connect_and_setup_db().unwrap();
+        conn.execute(
+            "INSERT INTO all_transactions (quantity, price_per_ton, purchase_date, is_used) VALUES (1.0, 6.0, '2024-13-01 00:00:00', 0)",
+            [],
+        )
+        .unwrap();
 
-fn write_headers(sheet: &mut Worksheet, headers: &[&str]) {
-    let _ = headers.iter().enumerate().try_for_each(|(index, &header)| {
-        sheet.write_string(0, index as u16, header, None)
-    });
-}
+        assert!(matches!(replay_ledger(false), Err(AppError::InvalidDate(_))));
+        assert!(matches!(holdings::holdings_series(None, Some(day(2025, 1, 31)), pnl::Granularity::Month), Err(AppError::InvalidDate(_))));
+        assert_eq!(lot_state(), lots);
+    }
 
-fn write_timber_purchases(conn: &Connection, sheet: &mut Worksheet) -> Result<(), AppError> {
-    let mut stmt = conn.prepare("SELECT id, quantity, price_per_ton, purchase_date FROM timber_purchases")?;
-    let timber_iter = stmt.query_map([], |row| {
-        Ok((
-            row.get::<_, i32>(0)?,
-            row.get::<_, f32>(1)?,
-            row.get::<_, f64>(2)?,
-            row.get::<_, String>(3)?,
-        ))
-    })?;
-    let mut num = 0;
-    for (row_num, timber) in timber_iter.enumerate() {
-        let (id, quantity, price_per_ton, purchase_date) = timber?;
-        let _ = sheet.write_number(row_num as u32 + 1, 0, id.into(), None);
-        let _ =sheet.write_number(row_num as u32 + 1, 1, quantity.into(), None);
-        let _ =sheet.write_number(row_num as u32 + 1, 2, price_per_ton, None);
-        let _ =sheet.write_string(row_num as u32 + 1, 3, &purchase_date, None);
-        num = row_num +1 ;
-    }
-
-    let _ =sheet.write_string(num as u32 + 3, 0, "Inventory Value", None);
-    let res = inventory_statistics();
-    let stats = match res {
-        Ok(stats) => stats,
-        Err(_) => return Err(AppError::DatabaseError(rusqlite::Error::QueryReturnedNoRows)),
-    };
-    let _ =sheet.write_number(num as u32 + 3, 1, stats.acquisition_value, None);
+    // A ledger of `rows` rows an hour apart from 2020: mostly reward lots, every tenth row a sale
+    // that consumes several of them, partly.
+    fn seed_ledger(rows: i64) {
+        let conn = connect_and_setup_db().unwrap();
+        let tx = conn.unchecked_transaction().unwrap();
+        for i in 0..rows {
+            if i % 10 == 9 {
+                tx.execute(
+                    "INSERT INTO all_transactions (quantity, sell_price, liquidation_date, is_used, external_ref) VALUES (?1, ?2, ?3, 1, ?4)",
+                    params![0.37 + (i % 7) as f64 * 0.11, 9.0 + (i % 5) as f64, hour(i), format!("s{}", i)],
+                )
+                .unwrap();
+            } else {
+                tx.execute(
+                    "INSERT INTO all_transactions (quantity, price_per_ton, purchase_date, is_used, is_reward, external_ref) VALUES (?1, ?2, ?3, 0, 1, ?4)",
+                    params![0.1 + (i % 3) as f64 * 0.05, 5.0 + (i % 4) as f64, hour(i), format!("r{}", i)],
+                )
+                .unwrap();
+            }
+        }
+        tx.commit().unwrap();
+    }
 
-    Ok(())
-}
+    fn hour(hours: i64) -> String {
+        timestamp::format_stored(time::macros::datetime!(2020-01-01 0:00 UTC) + time::Duration::hours(hours))
+    }
 
-fn write_used_timber(conn: &Connection, sheet: &mut Worksheet) -> Result<(), AppError> {
-    let mut stmt = conn.prepare("SELECT id, quantity, orig_price, sell_price, liquidation_date FROM used_timber")?;
-    let timber_iter = stmt.query_map([], |row| {
-        Ok((
-            row.get::<_, i32>(0)?,
-            row.get::<_, f32>(1)?,
-            row.get::<_, f64>(2)?,
-            row.get::<_, f64>(3)?,
-            row.get::<_, String>(4)?,
-        ))
-    })?;
-    let mut num = 0;
-    for (row_num, timber) in timber_iter.enumerate() {
-        let (id, quantity, price_per_ton, total_price, liquidation_date) = timber?;
-        let _ =sheet.write_number(row_num as u32 + 1, 0, id.into(), None);
-        let _ =sheet.write_number(row_num as u32 + 1, 1, quantity.into(), None);
-        let _ =sheet.write_number(row_num as u32 + 1, 2, price_per_ton, None);
-        let _ =sheet.write_number(row_num as u32 + 1, 3, total_price, None);
-        let _ =sheet.write_string(row_num as u32 + 1, 4, &liquidation_date, None);
-        num = row_num +1 ;
-    }
-    let _ =sheet.write_string(num as u32 + 3, 0, "Inventory Orig Value", None);
-    let res = inventory_statistics();
-    let stats = match res {
-        Ok(stats) => stats,
-        Err(_) => return Err(AppError::DatabaseError(rusqlite::Error::QueryReturnedNoRows)),
-    };
-    let _ =sheet.write_number(num as u32 + 3, 2, stats.orig_value, None);
-    let _ =sheet.write_string(num as u32 + 4, 0, "Inventory Liquation Value", None);
-    let _ =sheet.write_number(num as u32 + 4, 2, stats.sell_value, None);
-    
-    Ok(())
-}
+    type LotState = (Vec<(Option<i32>, f64, f64, String)>, Vec<(Option<i32>, Option<i32>, f64, f64, f64, String)>);
+
+    // The lot tables without their ids, which differ between an incremental and a full replay.
+    fn lot_state() -> LotState {
+        let conn = connect_and_setup_db().unwrap();
+        let mut lots = conn.prepare("SELECT txn_id, quantity, price_per_ton, purchase_date FROM timber_purchases ORDER BY purchase_date, txn_id").unwrap();
+        let lots = lots.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))).unwrap();
+        let mut used = conn
+            .prepare("SELECT sale_txn_id, purchase_txn_id, quantity, orig_price, sell_price, liquidation_date FROM used_timber ORDER BY liquidation_date, sale_txn_id, id")
+            .unwrap();
+        let used = used.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?))).unwrap();
+        (lots.collect::<Result<_>>().unwrap(), used.collect::<Result<_>>().unwrap())
+    }
 
-fn main() {
-    tauri::Builder::default()
-        .invoke_handler(tauri::generate_handler![greet, record_purchase, print_inventory, print_inventory_used, use_tao, write_inventory_to_excel, inventory_statistics, redo_transactions, add_transaction, remove_transaction_via_id, add_transaction, edit_transaction_via_id, show_all_transactions])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+    #[test]
+    fn incremental_replays_match_a_full_replay() {
+        for method in 1..=4 {
+            let _db = test_db();
+            connect_and_setup_db().unwrap().execute("UPDATE app_settings SET usage_type = ?1", params![method]).unwrap();
+            seed_ledger(300);
+            replay_ledger(false).unwrap();
+
+            let at = |hours| DateTime::Text(hour(hours));
+            // An edit, a removal, a back-dated insert and a sale moved earlier, replayed together.
+            assert_eq!(edit_transaction_via_id(151, 0.4, 6.0, 0.0, 0.0, at(150), at(150), false, None, None), "Completed");
+            assert_eq!(remove_transaction_via_id(200, None), "Completed");
+            assert_eq!(add_transaction(0.3, 4.0, 0.0, 0.0, at(120), at(120), false, None, None, None, None), "Completed");
+            assert_eq!(edit_transaction_via_id(290, 0.8, 0.0, 0.0, 11.0, at(60), at(60), true, None, None), "Completed");
+            redo_transactions(None).unwrap();
+            let incremental = lot_state();
+            replay_ledger(false).unwrap();
+            assert_eq!(incremental, lot_state(), "method {}", method);
+
+            // A purchase moved later, past sales that had consumed it.
+            assert_eq!(edit_transaction_via_id(2, 0.15, 6.0, 0.0, 0.0, at(250), at(250), false, None, None), "Completed");
+            redo_transactions(None).unwrap();
+            let incremental = lot_state();
+            replay_ledger(false).unwrap();
+            assert_eq!(incremental, lot_state(), "method {}", method);
+        }
+    }
+
+    #[test]
+    #[ignore = "times replays of a 20,000-row ledger"]
+    fn editing_one_row_of_a_large_ledger_replays_only_the_rest() {
+        let _db = test_db();
+        seed_ledger(20_000);
+        let started = std::time::Instant::now();
+        replay_ledger(false).unwrap();
+        let full = started.elapsed();
+
+        let at = DateTime::Text(hour(19_500));
+        assert_eq!(edit_transaction_via_id(19_501, 0.2, 6.0, 0.0, 0.0, at.clone(), at, false, None, None), "Completed");
+        let started = std::time::Instant::now();
+        redo_transactions(None).unwrap();
+        let incremental = started.elapsed();
+        assert!(incremental * 10 < full, "full replay {:?}, incremental {:?}", full, incremental);
+    }
 }
@@ -0,0 +1,216 @@
+use std::fs::File;
+use std::io::BufWriter;
+
+use printpdf::{BuiltinFont, IndirectFontRef, Mm, PdfDocument, PdfDocumentReference, PdfLayerReference};
+
+use crate::reports::{build_portfolio_report, cost_basis_method, tax_year_summaries, with_lot_links, PortfolioReport};
+use crate::{connect_and_setup_db, AppError};
+
+// A4 portrait, in millimetres.
+const PAGE_WIDTH: f32 = 210.0;
+const PAGE_HEIGHT: f32 = 297.0;
+const MARGIN: f32 = 15.0;
+const LINE_HEIGHT: f32 = 5.0;
+const BODY_SIZE: f32 = 9.0;
+
+const DISPOSAL_COLUMNS: [f32; 7] = [0.0, 30.0, 60.0, 85.0, 113.0, 141.0, 169.0];
+const YEAR_COLUMNS: [f32; 6] = [0.0, 25.0, 55.0, 85.0, 115.0, 145.0];
+
+// Lays text out top to bottom, starting a new page whenever the current one is full.
+struct PageWriter {
+    doc: PdfDocumentReference,
+    layer: PdfLayerReference,
+    regular: IndirectFontRef,
+    bold: IndirectFontRef,
+    y: f32,
+}
+
+impl PageWriter {
+    fn new(title: &str) -> Result<Self, AppError> {
+        let (doc, page, layer) = PdfDocument::new(title, Mm(PAGE_WIDTH), Mm(PAGE_HEIGHT), "Layer 1");
+        let regular = doc.add_builtin_font(BuiltinFont::Helvetica)?;
+        let bold = doc.add_builtin_font(BuiltinFont::HelveticaBold)?;
+        let layer = doc.get_page(page).get_layer(layer);
+        Ok(PageWriter { doc, layer, regular, bold, y: PAGE_HEIGHT - MARGIN })
+    }
+
+    fn new_page(&mut self) {
+        let (page, layer) = self.doc.add_page(Mm(PAGE_WIDTH), Mm(PAGE_HEIGHT), "Layer 1");
+        self.layer = self.doc.get_page(page).get_layer(layer);
+        self.y = PAGE_HEIGHT - MARGIN;
+    }
+
+    // True when a new page was started to fit `height` more millimetres.
+    fn make_room(&mut self, height: f32) -> bool {
+        if self.y - height < MARGIN {
+            self.new_page();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn text(&mut self, text: &str, size: f32, bold: bool) {
+        let height = size * 0.5;
+        self.make_room(height);
+        self.y -= height;
+        let font = if bold { &self.bold } else { &self.regular };
+        self.layer.use_text(text, size, Mm(MARGIN), Mm(self.y), font);
+    }
+
+    fn row<S: AsRef<str>>(&mut self, cells: &[S], columns: &[f32], bold: bool) {
+        self.make_room(LINE_HEIGHT);
+        self.y -= LINE_HEIGHT;
+        let font = if bold { &self.bold } else { &self.regular };
+        for (cell, x) in cells.iter().zip(columns) {
+            self.layer.use_text(cell.as_ref(), BODY_SIZE, Mm(MARGIN + x), Mm(self.y), font);
+        }
+    }
+
+    fn gap(&mut self) {
+        self.y -= LINE_HEIGHT;
+    }
+
+    fn heading(&mut self, text: &str) {
+        self.gap();
+        self.make_room(4.0 * LINE_HEIGHT);
+        self.text(text, 13.0, true);
+        self.y -= 2.0;
+    }
+}
+
+fn money(value: f64) -> String {
+    if value < 0.0 {
+        format!("-${:.2}", -value)
+    } else {
+        format!("${:.2}", value)
+    }
+}
+
+fn quantity(value: f64) -> String {
+    format!("{:.4}", value)
+}
+
+fn day(date: &str) -> &str {
+    date.get(0..10).unwrap_or(date)
+}
+
+fn write_report(writer: &mut PageWriter, report: &PortfolioReport, method: &str) {
+    let now = time::OffsetDateTime::now_utc();
+    writer.text("TAO Portfolio Report", 18.0, true);
+    writer.gap();
+    writer.row(
+        &[format!("Generated {:04}-{:02}-{:02} {:02}:{:02} UTC", now.year(), now.month() as u8, now.day(), now.hour(), now.minute())],
+        &[0.0],
+        false,
+    );
+    writer.row(&[format!("Cost basis method: {}", method)], &[0.0], false);
+
+    let summary = &report.summary;
+    writer.heading("Holdings");
+    let mut holdings = vec![
+        ("Quantity held", quantity(summary.open_quantity)),
+        ("Open lots", report.open_lots.len().to_string()),
+        ("Cost basis of holdings", money(summary.open_cost_basis)),
+    ];
+    if let (Some(price), Some(value), Some(unrealized)) = (summary.current_price, summary.market_value, summary.unrealized_gain) {
+        holdings.push(("Current price", money(price)));
+        holdings.push(("Market value", money(value)));
+        holdings.push(("Unrealized gain/loss", money(unrealized)));
+    }
+    for (label, value) in holdings {
+        writer.row(&[label.to_string(), value], &[0.0, 60.0], false);
+    }
+
+    writer.heading("Realized Gains and Income by Tax Year");
+    let header = ["Tax Year", "Proceeds", "Cost Basis", "Short-Term", "Long-Term", "Reward Income"];
+    writer.row(&header, &YEAR_COLUMNS, true);
+    let years = tax_year_summaries(report);
+    for year in &years {
+        let cells = [
+            year.year.to_string(),
+            money(year.proceeds),
+            money(year.cost_basis),
+            money(year.short_term_gain),
+            money(year.long_term_gain),
+            money(year.reward_income),
+        ];
+        writer.row(&cells, &YEAR_COLUMNS, false);
+    }
+    let totals = [
+        "Total".to_string(),
+        money(summary.proceeds),
+        money(summary.realized_cost_basis),
+        money(years.iter().map(|year| year.short_term_gain).sum()),
+        money(years.iter().map(|year| year.long_term_gain).sum()),
+        money(summary.reward_income),
+    ];
+    writer.row(&totals, &YEAR_COLUMNS, true);
+    writer.row(&[format!("Net realized gain/loss: {}", money(summary.realized_gain))], &[0.0], false);
+
+    writer.heading("Disposals");
+    let header = ["Sold", "Acquired", "Quantity", "Cost Basis", "Proceeds", "Gain/Loss", "Term"];
+    writer.row(&header, &DISPOSAL_COLUMNS, true);
+    for disposal in &report.disposals {
+        // Repeat the header at the top of every page the table runs onto.
+        if writer.make_room(LINE_HEIGHT) {
+            writer.row(&header, &DISPOSAL_COLUMNS, true);
+        }
+        let term = match disposal.is_long_term(report.display_offset) {
+            Some(true) => "Long",
+            Some(false) => "Short",
+            None => "Unknown",
+        };
+        let cells = [
+            day(&disposal.liquidation_date).to_string(),
+            disposal.purchase_date.as_deref().map(day).unwrap_or("Unknown").to_string(),
+            quantity(disposal.quantity),
+            money(disposal.cost_basis()),
+            money(disposal.proceeds()),
+            money(disposal.gain()),
+            term.to_string(),
+        ];
+        writer.row(&cells, &DISPOSAL_COLUMNS, false);
+    }
+    if report.disposals.is_empty() {
+        writer.row(&["No disposals recorded."], &[0.0], false);
+    }
+}
+
+/// Writes a printable PDF report to `path` and returns the path. It reads the same report data
+/// as the Excel workbook, with lots linked the same way, so the figures in both agree.
+#[tauri::command]
+pub fn export_pdf_report(path: String, current_price: Option<f64>) -> Result<String, AppError> {
+    let conn = connect_and_setup_db()?;
+    let linked = with_lot_links(&conn)?;
+    let conn = linked.as_ref().unwrap_or(&conn);
+    let report = build_portfolio_report(conn, current_price)?;
+    let method = cost_basis_method(conn)?;
+
+    let mut writer = PageWriter::new("TAO Portfolio Report")?;
+    write_report(&mut writer, &report, method);
+    writer.doc.save(&mut BufWriter::new(File::create(&path)?))?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{day, test_db};
+    use crate::{db_path, record_purchase, use_tao};
+
+    #[test]
+    fn a_small_ledger_makes_a_pdf() {
+        let _db = test_db();
+        record_purchase(10.0, 5.0, day(2023, 1, 1), None, None, None, None).unwrap();
+        record_purchase(2.0, 7.0, day(2024, 3, 1), Some("reward".to_string()), Some(true), None, None).unwrap();
+        use_tao(4.0, day(2024, 6, 1), 9.0, None, None, None).unwrap();
+
+        let path = export_pdf_report(db_path().with_file_name("report.pdf").to_string_lossy().into_owned(), Some(8.0)).unwrap();
+        let pdf = std::fs::read(path).unwrap();
+        assert!(pdf.starts_with(b"%PDF-"));
+        let text = String::from_utf8_lossy(&pdf);
+        assert!(text.contains("TAO Portfolio Report"));
+        assert!(text.trim_end().ends_with("%%EOF"));
+    }
+}
@@ -0,0 +1,277 @@
+use rusqlite::params;
+
+use crate::holdings::scratch_replay;
+use crate::prices::price_at;
+use crate::reports::effective_date;
+use crate::timestamp::parse_stored;
+use crate::{apply_transaction, connect_and_setup_db, transaction_from_row, AppError, DateTime};
+
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+pub struct PerformanceReport {
+    from: String,
+    to: String,
+    start_value: Option<f64>,
+    end_value: Option<f64>,
+    contributions: f64, // purchases in the period
+    withdrawals: f64,   // sale proceeds in the period
+    xirr: Option<f64>,
+    time_weighted_return: Option<f64>,
+    annualized_time_weighted_return: Option<f64>,
+}
+
+// Fractional days since the Unix epoch for a stored timestamp.
+fn days(date: &str) -> Result<f64, AppError> {
+    Ok(parse_stored(date)?.unix_timestamp() as f64 / 86400.0)
+}
+
+/// Annual rate at which the cash flows, given as (years since the first flow, amount), have a net
+/// present value of zero. Newton's method is tried first; bisection is the fallback when it does
+/// not converge. None when the flows do not change sign, as no rate exists then.
+pub fn xirr(flows: &[(f64, f64)]) -> Option<f64> {
+    if !flows.iter().any(|&(_, amount)| amount > 0.0) || !flows.iter().any(|&(_, amount)| amount < 0.0) {
+        return None;
+    }
+    newton_rate(flows).or_else(|| bisection_rate(flows))
+}
+
+fn npv(flows: &[(f64, f64)], rate: f64) -> f64 {
+    flows.iter().map(|&(years, amount)| amount / (1.0 + rate).powf(years)).sum()
+}
+
+// Newton's method from 10%. None when a step leaves the rates above -100% or it does not settle.
+fn newton_rate(flows: &[(f64, f64)]) -> Option<f64> {
+    let slope = |rate: f64| flows.iter().map(|&(years, amount)| -years * amount / (1.0 + rate).powf(years + 1.0)).sum::<f64>();
+    let mut rate = 0.1;
+    for _ in 0..100 {
+        let (value, derivative) = (npv(flows, rate), slope(rate));
+        if derivative == 0.0 || !value.is_finite() {
+            return None;
+        }
+        let next = rate - value / derivative;
+        if next <= -1.0 || !next.is_finite() {
+            return None;
+        }
+        if (next - rate).abs() < 1e-10 {
+            return Some(next);
+        }
+        rate = next;
+    }
+    None
+}
+
+// Bisection between just above -100% and an upper bound doubled until the NPV changes sign.
+fn bisection_rate(flows: &[(f64, f64)]) -> Option<f64> {
+    let npv = |rate: f64| npv(flows, rate);
+    let (mut low, mut high) = (-0.999_999, 1.0);
+    while npv(low).signum() == npv(high).signum() {
+        high *= 2.0;
+        if high > 1e9 {
+            return None;
+        }
+    }
+    for _ in 0..200 {
+        let mid = (low + high) / 2.0;
+        if npv(mid).signum() == npv(low).signum() {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    Some((low + high) / 2.0)
+}
+
+/// Money-weighted (XIRR) and time-weighted returns between `from` and `to`. Holdings are valued
+/// from price_history at both ends; purchases count as money put in and sales as money taken
+/// out, while rewards are part of the return. The time-weighted return chains the sub-periods
+/// between purchases and sales, valuing holdings at each trade's own price when no stored price
+/// is available. Returns that need a valuation nothing in price_history provides are None.
+#[tauri::command]
+pub fn portfolio_performance(from: DateTime, to: DateTime) -> Result<PerformanceReport, AppError> {
+    let conn = connect_and_setup_db()?;
+    let (from, to) = (from.to_stored(&conn)?, to.to_stored(&conn)?);
+    let (start_day, end_day) = (days(&from)?, days(&to)?);
+    if end_day <= start_day {
+        return Err(AppError::InvalidDate(format!("{} is not after {}", to, from)));
+    }
+
+    // Quantities are read from the lots as a replay leaves them, so a sale that found nothing to
+    // consume (see apply_use) moves neither units nor money.
+    let scratch = scratch_replay(&conn, &from)?;
+    let held = || scratch.query_row("SELECT COALESCE(SUM(quantity), 0) FROM timber_purchases", [], |row| row.get::<_, f64>(0));
+    let value_at = |quantity: f64, price: Option<f64>| if quantity <= 0.0 { Some(0.0) } else { price.map(|price| price * quantity) };
+    let start_value = value_at(held()?, price_at(&conn, &from)?);
+
+    let mut stmt = conn.prepare(
+        "SELECT * FROM all_transactions
+         WHERE (CASE WHEN is_used THEN liquidation_date ELSE purchase_date END) > ?1
+           AND (CASE WHEN is_used THEN liquidation_date ELSE purchase_date END) <= ?2
+         ORDER BY CASE WHEN is_used THEN liquidation_date ELSE purchase_date END, id",
+    )?;
+    let transactions = stmt.query_map(params![from, to], transaction_from_row)?.collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut flows = Vec::new(); // (years since from, amount received by the investor)
+    let (mut contributions, mut withdrawals) = (0.0, 0.0);
+    let mut growth = Some(1.0);
+    let mut period_start_value = start_value;
+    for txn in &transactions {
+        let date = match effective_date(txn) {
+            Some(date) => date,
+            None => continue,
+        };
+        let trade_price = if txn.is_used { txn.sell_price } else { txn.price_per_ton };
+        let price = price_at(&conn, date)?.or(trade_price);
+        let value_before = value_at(held()?, price);
+        apply_transaction(&scratch, txn)?;
+
+        // Rewards add to the holdings without any money changing hands.
+        if txn.is_reward && !txn.is_used {
+            continue;
+        }
+        growth = match (growth, period_start_value, value_before) {
+            (Some(growth), Some(start), Some(end)) if start > 0.0 => Some(growth * end / start),
+            (Some(growth), Some(_), Some(_)) => Some(growth),
+            _ => None,
+        };
+
+        let years = (days(date)? - start_day) / 365.0;
+        if txn.is_used {
+            let sold: f64 = scratch.query_row("SELECT COALESCE(SUM(quantity), 0) FROM used_timber WHERE sale_txn_id = ?1", params![txn.id], |row| row.get(0))?;
+            let amount = sold * trade_price.unwrap_or(0.0);
+            withdrawals += amount;
+            flows.push((years, amount));
+        } else {
+            let amount = txn.quantity * trade_price.unwrap_or(0.0);
+            contributions += amount;
+            flows.push((years, -amount));
+        }
+        period_start_value = value_at(held()?, price);
+    }
+
+    let end_value = value_at(held()?, price_at(&conn, &to)?);
+    let time_weighted_return = match (growth, period_start_value, end_value) {
+        (Some(growth), Some(start), Some(end)) if start > 0.0 => Some(growth * end / start - 1.0),
+        (Some(growth), Some(_), Some(_)) => Some(growth - 1.0),
+        _ => None,
+    };
+    let period_years = (end_day - start_day) / 365.0;
+    let annualized_time_weighted_return = time_weighted_return.map(|twr| (1.0 + twr).powf(1.0 / period_years) - 1.0);
+
+    let xirr = match (start_value, end_value) {
+        (Some(start_value), Some(end_value)) => {
+            flows.push((0.0, -start_value));
+            flows.push((period_years, end_value));
+            xirr(&flows)
+        }
+        _ => None,
+    };
+
+    Ok(PerformanceReport {
+        from,
+        to,
+        start_value,
+        end_value,
+        contributions,
+        withdrawals,
+        xirr,
+        time_weighted_return,
+        annualized_time_weighted_return,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prices::record_price;
+    use crate::test_support::{day, test_db};
+    use crate::{record_purchase, use_tao};
+
+    fn close(actual: Option<f64>, expected: f64) -> bool {
+        actual.map(|actual| (actual - expected).abs() < 1e-9).unwrap_or(false)
+    }
+
+    #[test]
+    fn xirr_known_values() {
+        assert!(close(xirr(&[(0.0, -1000.0), (1.0, 1100.0)]), 0.1));
+        assert!(close(xirr(&[(0.0, -100.0), (2.0, 121.0)]), 0.1));
+        assert!(close(xirr(&[(0.0, -100.0), (1.0, 50.0), (1.0, 50.0)]), 0.0));
+        assert!(close(xirr(&[(0.0, -1.0), (1.0, 1000.0)]), 999.0));
+        assert!(close(xirr(&[(0.0, -100.0), (1.0, 25.0)]), -0.75));
+    }
+
+    #[test]
+    fn xirr_matches_a_spreadsheet_on_irregular_dates() {
+        // The example from Excel's XIRR documentation, whose result is 0.373362535. Like XIRR,
+        // years are days / 365 from the first flow: 1 Jan 2008, 1 Mar 2008, 30 Oct 2008,
+        // 15 Feb 2009 and 1 Apr 2009.
+        let flows = [(0.0, -10000.0), (60.0, 2750.0), (303.0, 4250.0), (411.0, 3250.0), (456.0, 2750.0)];
+        let flows: Vec<(f64, f64)> = flows.iter().map(|&(days, amount)| (days / 365.0, amount)).collect();
+        let rate = xirr(&flows).unwrap();
+        assert!((rate - 0.373362535).abs() < 1e-8, "{}", rate);
+    }
+
+    #[test]
+    fn bisection_takes_over_when_newton_fails() {
+        // Almost everything is lost: the rate is -99%. From 10% the first Newton step lands far
+        // below -100%, where no rate is defined.
+        let flows = [(0.0, -100.0), (1.0, 1.0)];
+        assert_eq!(newton_rate(&flows), None);
+        assert!(close(xirr(&flows), -0.99));
+        // The same loss over a year and a half, on irregular dates.
+        let flows = [(0.0, -60.0), (0.25, -40.0), (1.5, 1.0)];
+        assert_eq!(newton_rate(&flows), None);
+        let rate = xirr(&flows).unwrap();
+        assert!(npv(&flows, rate).abs() < 1e-6, "{}", rate);
+    }
+
+    #[test]
+    fn xirr_is_none_without_a_rate() {
+        // All money in, or all money out.
+        assert_eq!(xirr(&[(0.0, -100.0), (1.0, -10.0)]), None);
+        assert_eq!(xirr(&[(0.0, 100.0)]), None);
+        // The flows change sign, but 1 - x + x^2 (x = 1 / (1 + rate)) is positive for every rate,
+        // so neither Newton's method nor bisection finds one.
+        assert_eq!(xirr(&[(0.0, 1.0), (1.0, -1.0), (2.0, 1.0)]), None);
+    }
+
+    #[test]
+    fn a_year_at_ten_percent() {
+        let _db = test_db();
+        record_purchase(10.0, 10.0, day(2022, 6, 1), None, None, None, None).unwrap();
+        record_price(day(2023, 1, 1), 10.0).unwrap();
+        record_price(day(2024, 1, 1), 11.0).unwrap();
+        let report = portfolio_performance(day(2023, 1, 1), day(2024, 1, 1)).unwrap();
+        assert!(close(report.start_value, 100.0));
+        assert!(close(report.end_value, 110.0));
+        assert!(close(report.xirr, 0.1));
+        assert!(close(report.time_weighted_return, 0.1));
+        assert!(close(report.annualized_time_weighted_return, 0.1));
+    }
+
+    #[test]
+    fn time_weighted_return_chains_sub_periods() {
+        let _db = test_db();
+        record_purchase(10.0, 10.0, day(2022, 6, 1), None, None, None, None).unwrap();
+        record_price(day(2023, 1, 1), 10.0).unwrap();
+        record_price(day(2023, 7, 1), 20.0).unwrap();
+        record_price(day(2023, 12, 31), 10.0).unwrap();
+        use_tao(5.0, day(2023, 7, 1), 20.0, None, None, None).unwrap();
+        // The price doubles before the sale and halves after it: +100% then -50%.
+        let report = portfolio_performance(day(2023, 1, 1), day(2023, 12, 31)).unwrap();
+        assert!(close(report.time_weighted_return, 0.0));
+        assert!(close(Some(report.withdrawals), 100.0));
+        assert!(close(report.end_value, 50.0));
+    }
+
+    #[test]
+    fn sales_that_consumed_no_lots_move_nothing() {
+        let _db = test_db();
+        record_purchase(10.0, 10.0, day(2022, 6, 1), None, None, None, None).unwrap();
+        record_price(day(2023, 1, 1), 10.0).unwrap();
+        record_price(day(2024, 1, 1), 11.0).unwrap();
+        use_tao(50.0, day(2023, 6, 1), 10.0, None, None, None).unwrap(); // more than is held
+        let report = portfolio_performance(day(2023, 1, 1), day(2024, 1, 1)).unwrap();
+        assert_eq!(report.withdrawals, 0.0);
+        assert!(close(report.end_value, 110.0));
+        assert!(close(report.xirr, 0.1));
+    }
+}
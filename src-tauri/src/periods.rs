@@ -0,0 +1,350 @@
+use std::collections::BTreeMap;
+
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_json::Value;
+use time::UtcOffset;
+
+use crate::holdings::scratch_db;
+use crate::reports::{disposals, effective_date, fiscal_year, fiscal_year_bounds, load_fiscal_year_start, Disposal, FiscalYearStart};
+use crate::timestamp::{load_display_offset, now_stored};
+use crate::{audit, connect_and_setup_db, replay_changes, replay_into, replay_position, snapshots, AllTransactions, AppError};
+
+// A fiscal year is closed once its return has been filed. Changes that could alter a closed year,
+// directly or through the replay that follows them, are refused unless the caller overrides the
+// lock, and replays check that the disposals of closed years come out unchanged.
+
+#[derive(Debug, Clone, Default,serde::Serialize,serde::Deserialize)]
+pub struct PeriodFigures {
+    quantity: f64,
+    proceeds: f64,
+    cost_basis: f64,
+    gain: f64,
+}
+
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+pub struct ClosedPeriod {
+    year: i32,
+    closed_at: String,
+    filed: PeriodFigures, // disposal totals when the year was closed
+}
+
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+pub struct ReopenReport {
+    year: i32,
+    filed: PeriodFigures,
+    current: PeriodFigures,                // from the lot tables as they are
+    replayed: PeriodFigures,               // after replaying the ledger with the year reopened
+    dropped_disposals: Vec<Disposal>,      // in the lot tables but not in the replay
+    new_disposals: Vec<Disposal>,          // in the replay but not in the lot tables
+    other_closed_years_changed: Vec<i32>,  // would still block the replay while they stay closed
+}
+
+// What a disposal contributes to a return: date, quantity, cost and sale price.
+type DisposalFigures = (String, f64, f64, f64);
+
+fn closed_years(conn: &Connection) -> rusqlite::Result<Vec<i32>> {
+    let mut stmt = conn.prepare("SELECT year FROM closed_periods ORDER BY year")?;
+    let years = stmt.query_map([], |row| row.get(0))?;
+    years.collect()
+}
+
+fn figures<'a>(disposals: impl Iterator<Item = &'a Disposal>) -> PeriodFigures {
+    let mut figures = PeriodFigures::default();
+    for disposal in disposals {
+        figures.quantity += disposal.quantity;
+        figures.proceeds += disposal.proceeds();
+        figures.cost_basis += disposal.cost_basis();
+    }
+    figures.gain = figures.proceeds - figures.cost_basis;
+    figures
+}
+
+fn in_year<'a>(disposals: &'a [Disposal], year: i32, start: &'a FiscalYearStart, display_offset: UtcOffset) -> impl Iterator<Item = &'a Disposal> {
+    disposals.iter().filter(move |disposal| fiscal_year(&disposal.liquidation_date, start, display_offset) == Some(year))
+}
+
+/// Refuses a change taking effect at `date` when it could alter a closed year: the year the date
+/// falls in, or any closed year after it, as replaying from the date can change which lots later
+/// sales consume. Undated changes are never replayed and always pass.
+pub fn ensure_open(conn: &Connection, date: &str, override_lock: bool) -> Result<(), AppError> {
+    if override_lock || date.is_empty() {
+        return Ok(());
+    }
+    ensure_open_in(&closed_years(conn)?, &load_fiscal_year_start(conn)?, load_display_offset(conn)?, date)
+}
+
+fn ensure_open_in(years: &[i32], start: &FiscalYearStart, display_offset: UtcOffset, date: &str) -> Result<(), AppError> {
+    let affected: Vec<String> = years
+        .iter()
+        .filter(|&&year| date < fiscal_year_bounds(year, start, display_offset).1.as_str())
+        .map(|year| year.to_string())
+        .collect();
+    if affected.is_empty() {
+        return Ok(());
+    }
+    Err(AppError::PeriodLock(format!(
+        "a change dated {} could alter closed fiscal year {}; reopen it or override the lock",
+        date,
+        affected.join(", ")
+    )))
+}
+
+/// ensure_open for ledger row `txn_id` as it is stored.
+pub fn ensure_row_open(conn: &Connection, txn_id: i32, override_lock: bool) -> Result<(), AppError> {
+    match replay_position(conn, txn_id)? {
+        Some((date, _)) => ensure_open(conn, &date, override_lock),
+        None => Ok(()),
+    }
+}
+
+// Disposals of closed years in `lots`, by year, in a fixed order so two sets can be compared.
+// `lots` may be a scratch database; the closed years are read from `conn`.
+fn closed_disposals(conn: &Connection, lots: &Connection) -> Result<BTreeMap<i32, Vec<DisposalFigures>>, AppError> {
+    let years = closed_years(conn)?;
+    let mut by_year = BTreeMap::new();
+    if years.is_empty() {
+        return Ok(by_year);
+    }
+    let start = load_fiscal_year_start(conn)?;
+    let display_offset = load_display_offset(conn)?;
+    let mut stmt = lots.prepare(
+        "SELECT liquidation_date, quantity, orig_price, sell_price FROM used_timber ORDER BY liquidation_date, quantity, orig_price, sell_price",
+    )?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))?;
+    for row in rows {
+        let row: DisposalFigures = row?;
+        if let Some(year) = fiscal_year(&row.0, &start, display_offset).filter(|year| years.contains(year)) {
+            by_year.entry(year).or_insert_with(Vec::new).push(row);
+        }
+    }
+    Ok(by_year)
+}
+
+fn changed_years(before: &BTreeMap<i32, Vec<DisposalFigures>>, after: &BTreeMap<i32, Vec<DisposalFigures>>) -> Vec<i32> {
+    let mut years: Vec<i32> = before.keys().chain(after.keys()).copied().collect();
+    years.sort_unstable();
+    years.dedup();
+    years.retain(|year| before.get(year) != after.get(year));
+    years
+}
+
+/// Disposals of the closed years as they stood before a replay, to check the replay against.
+pub struct ReplayGuard(BTreeMap<i32, Vec<DisposalFigures>>);
+
+impl ReplayGuard {
+    pub fn new(conn: &Connection) -> Result<Self, AppError> {
+        Ok(ReplayGuard(closed_disposals(conn, conn)?))
+    }
+
+    /// Fails, so the replay's transaction is rolled back, when the replay changed the disposals of
+    /// a closed year and the lock was not overridden.
+    pub fn check(&self, conn: &Connection, override_lock: bool) -> Result<(), AppError> {
+        if override_lock || self.0.is_empty() {
+            return Ok(());
+        }
+        let changed = changed_years(&self.0, &closed_disposals(conn, conn)?);
+        if changed.is_empty() {
+            return Ok(());
+        }
+        let changed: Vec<String> = changed.iter().map(|year| year.to_string()).collect();
+        Err(AppError::PeriodLock(format!(
+            "replaying the ledger would change closed fiscal year {}; reopen it or override the lock",
+            changed.join(", ")
+        )))
+    }
+}
+
+/// The ledger and the closed years as they stood before a restore or an import replaced them, to
+/// check the result against before it is kept.
+pub struct RestoreGuard {
+    ledger: BTreeMap<i32, Value>,
+    closed: Vec<i32>,
+    start: FiscalYearStart,
+    display_offset: UtcOffset,
+}
+
+impl RestoreGuard {
+    pub fn new(conn: &Connection) -> Result<Self, AppError> {
+        Ok(RestoreGuard {
+            ledger: audit::ledger_images(conn)?,
+            closed: closed_years(conn)?,
+            start: load_fiscal_year_start(conn)?,
+            display_offset: load_display_offset(conn)?,
+        })
+    }
+
+    /// Fails, unless the lock is overridden, when `restored` no longer has a year that was closed
+    /// or has ledger rows added, changed or removed in a way ensure_open would refuse.
+    pub fn check(&self, restored: &Connection, override_lock: bool) -> Result<(), AppError> {
+        if override_lock || self.closed.is_empty() {
+            return Ok(());
+        }
+        let kept = closed_years(restored)?;
+        let dropped: Vec<String> = self.closed.iter().filter(|year| !kept.contains(year)).map(|year| year.to_string()).collect();
+        if !dropped.is_empty() {
+            return Err(AppError::PeriodLock(format!(
+                "this would reopen closed fiscal year {}; reopen it first or override the lock",
+                dropped.join(", ")
+            )));
+        }
+
+        let restored = audit::ledger_images(restored)?;
+        let date = |image: &Value| {
+            serde_json::from_value::<AllTransactions>(image.clone()).ok().and_then(|txn| effective_date(&txn).map(str::to_string))
+        };
+        let earliest = self
+            .ledger
+            .keys()
+            .chain(restored.keys())
+            .filter(|id| self.ledger.get(id) != restored.get(id))
+            .flat_map(|id| [self.ledger.get(id), restored.get(id)])
+            .flatten()
+            .filter_map(date)
+            .min();
+        match earliest {
+            Some(earliest) => ensure_open_in(&self.closed, &self.start, self.display_offset, &earliest),
+            None => Ok(()),
+        }
+    }
+}
+
+#[tauri::command]
+pub fn list_closed_periods() -> Result<Vec<ClosedPeriod>, AppError> {
+    let conn = connect_and_setup_db()?;
+    let mut stmt = conn.prepare("SELECT year, closed_at, quantity, proceeds, cost_basis FROM closed_periods ORDER BY year")?;
+    let periods = stmt.query_map([], |row| {
+        let (proceeds, cost_basis): (f64, f64) = (row.get(3)?, row.get(4)?);
+        Ok(ClosedPeriod {
+            year: row.get(0)?,
+            closed_at: row.get(1)?,
+            filed: PeriodFigures { quantity: row.get(2)?, proceeds, cost_basis, gain: proceeds - cost_basis },
+        })
+    })?;
+    Ok(periods.collect::<rusqlite::Result<Vec<_>>>()?)
+}
+
+/// Closes fiscal year `year`, which must have ended. Pending ledger changes are replayed first so
+/// the disposal totals kept as filed match the ledger.
+#[tauri::command]
+pub fn close_period(year: i32) -> Result<(), AppError> {
+    let conn = connect_and_setup_db()?;
+    let start = load_fiscal_year_start(&conn)?;
+    let display_offset = load_display_offset(&conn)?;
+    if fiscal_year_bounds(year, &start, display_offset).1 > now_stored() {
+        return Err(AppError::PeriodLock(format!("fiscal year {} has not ended yet", year)));
+    }
+    if closed_years(&conn)?.contains(&year) {
+        return Err(AppError::PeriodLock(format!("fiscal year {} is already closed", year)));
+    }
+    replay_changes(false)?;
+
+    let disposals = disposals(&conn)?;
+    let filed = figures(in_year(&disposals, year, &start, display_offset));
+    conn.execute(
+        "INSERT INTO closed_periods (year, closed_at, quantity, proceeds, cost_basis) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![year, now_stored(), filed.quantity, filed.proceeds, filed.cost_basis],
+    )?;
+    audit::record_change(&conn, "close_period", "closed_periods", Some(year as i64), None, audit::closed_period_image(&conn, year)?.as_ref())?;
+    Ok(())
+}
+
+#[tauri::command]
+pub fn reopen_period(year: i32) -> Result<(), AppError> {
+    snapshots::take_snapshot("reopen_period")?;
+    let conn = connect_and_setup_db()?;
+    let before = audit::closed_period_image(&conn, year)?;
+    conn.execute("DELETE FROM closed_periods WHERE year = ?1", params![year])?;
+    audit::record_change(&conn, "reopen_period", "closed_periods", Some(year as i64), before.as_ref(), None)?;
+    Ok(())
+}
+
+/// What reopening closed fiscal year `year` and replaying the ledger would change in it, worked out
+/// in a scratch database so nothing is changed.
+#[tauri::command]
+pub fn reopen_period_report(year: i32) -> Result<ReopenReport, AppError> {
+    let conn = connect_and_setup_db()?;
+    let filed = conn
+        .query_row("SELECT quantity, proceeds, cost_basis FROM closed_periods WHERE year = ?1", params![year], |row| {
+            let (proceeds, cost_basis): (f64, f64) = (row.get(1)?, row.get(2)?);
+            Ok(PeriodFigures { quantity: row.get(0)?, proceeds, cost_basis, gain: proceeds - cost_basis })
+        })
+        .optional()?
+        .ok_or_else(|| AppError::PeriodLock(format!("fiscal year {} is not closed", year)))?;
+    let start = load_fiscal_year_start(&conn)?;
+    let display_offset = load_display_offset(&conn)?;
+
+    let scratch = scratch_db(&conn)?;
+    replay_into(&scratch, &conn, None, None)?;
+    let live = disposals(&conn)?;
+    let replay = disposals(&scratch)?;
+
+    // Disposals are matched on what they contribute to the return; ids differ between databases.
+    let key = |disposal: &Disposal| {
+        (disposal.liquidation_date.clone(), disposal.purchase_date.clone(), disposal.quantity, disposal.orig_price, disposal.sell_price)
+    };
+    let mut new_disposals: Vec<Disposal> = in_year(&replay, year, &start, display_offset).cloned().collect();
+    let mut dropped_disposals = Vec::new();
+    for disposal in in_year(&live, year, &start, display_offset) {
+        match new_disposals.iter().position(|candidate| key(candidate) == key(disposal)) {
+            Some(index) => {
+                new_disposals.remove(index);
+            }
+            None => dropped_disposals.push(disposal.clone()),
+        }
+    }
+
+    let mut other_closed_years_changed = changed_years(&closed_disposals(&conn, &conn)?, &closed_disposals(&conn, &scratch)?);
+    other_closed_years_changed.retain(|&other| other != year);
+
+    Ok(ReopenReport {
+        year,
+        filed,
+        current: figures(in_year(&live, year, &start, display_offset)),
+        replayed: figures(in_year(&replay, year, &start, display_offset)),
+        dropped_disposals,
+        new_disposals,
+        other_closed_years_changed,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshots::{list_snapshots, restore_snapshot};
+    use crate::test_support::{day, test_db};
+    use crate::timestamp::set_display_timezone;
+    use crate::{record_purchase, use_tao, DateTime};
+
+    #[test]
+    fn reopening_a_period_can_be_undone_from_its_snapshot() {
+        let _db = test_db();
+        record_purchase(10.0, 5.0, day(2022, 1, 1), None, None, None, None).unwrap();
+        use_tao(5.0, day(2022, 7, 1), 10.0, None, None, None).unwrap();
+        close_period(2022).unwrap();
+
+        reopen_period(2022).unwrap();
+        assert!(list_closed_periods().unwrap().is_empty());
+        let newest = serde_json::to_value(&list_snapshots().unwrap()[0]).unwrap();
+        assert_eq!(newest["reason"], "reopen_period");
+        restore_snapshot(newest["file_name"].as_str().unwrap().to_string(), None).unwrap();
+        assert_eq!(list_closed_periods().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn closed_years_end_at_midnight_in_the_display_timezone() {
+        let _db = test_db();
+        set_display_timezone("+10:00".to_string()).unwrap();
+        let local = |text: &str| DateTime::Text(text.to_string());
+        record_purchase(10.0, 5.0, day(2024, 1, 1), None, None, None, None).unwrap();
+        use_tao(1.0, local("2024-12-31T12:00"), 10.0, None, None, None).unwrap();
+        close_period(2024).unwrap();
+
+        // 05:00 on 1 January is 19:00 UTC on 31 December, but in 2025 and so open.
+        use_tao(1.0, local("2025-01-01T05:00"), 10.0, None, None, None).unwrap();
+        assert!(matches!(use_tao(1.0, local("2024-12-31T23:00"), 10.0, None, None, None), Err(AppError::PeriodLock(_))));
+
+        // Changing the timezone would move sales near midnight between closed and open years.
+        assert!(matches!(set_display_timezone("Z".to_string()), Err(AppError::PeriodLock(_))));
+        assert_eq!(list_closed_periods().unwrap()[0].filed.quantity, 1.0);
+    }
+}
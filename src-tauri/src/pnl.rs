@@ -0,0 +1,218 @@
+use std::collections::BTreeMap;
+
+use rusqlite::params;
+use time::{Date, Month, UtcOffset};
+
+use crate::reports::{fiscal_year, load_fiscal_year_start, FiscalYearStart};
+use crate::timestamp::{load_display_offset, local_date};
+use crate::{connect_and_setup_db, AppError, DateTime};
+
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+pub enum Granularity {
+    Day,
+    Month,
+    Quarter,
+    Year,
+}
+
+impl Granularity {
+    // Name of the period a stored date ("2024-03-01T14:30:00Z") falls into, e.g. "2024-03-01",
+    // "2024-03", "2024-Q1" or "2024"; days are days in the display timezone. Quarters and years follow the fiscal year, so with a 6 April
+    // start "2025-Q1" runs from 6 April to 5 July 2024. Each name sorts chronologically as text.
+    fn period_name(&self, date: &str, start: &FiscalYearStart, display_offset: UtcOffset) -> Option<String> {
+        let local = local_date(date, display_offset).ok()?;
+        let (month, day) = (local.month() as u32, local.day() as u32);
+        match self {
+            Granularity::Day => Some(format!("{:04}-{:02}-{:02}", local.year(), month, day)),
+            Granularity::Month => Some(format!("{:04}-{:02}", local.year(), month)),
+            Granularity::Quarter => {
+                // Fiscal months start on the fiscal year's start day.
+                let fiscal_month = if day < start.day { month as i32 - 1 } else { month as i32 };
+                let quarter = (fiscal_month - start.month as i32).rem_euclid(12) / 3 + 1;
+                Some(format!("{}-Q{}", fiscal_year(date, start, display_offset)?, quarter))
+            }
+            Granularity::Year => Some(fiscal_year(date, start, display_offset)?.to_string()),
+        }
+    }
+
+    /// Last day of the period containing `date`, with quarters and years ending where the fiscal
+    /// year starting on `start` puts them.
+    pub fn period_end(&self, date: Date, start: &FiscalYearStart) -> Date {
+        let months = match self {
+            Granularity::Day => return date,
+            Granularity::Month => return Date::from_calendar_date(date.year(), date.month(), date.month().length(date.year())).unwrap_or(date),
+            Granularity::Quarter => 3,
+            Granularity::Year => 12,
+        };
+        // `start.day` is at most 28, so it exists in every month.
+        let boundary = |year: i32, offset: u32| {
+            let index = start.month - 1 + offset;
+            let month = Month::try_from((index % 12 + 1) as u8).unwrap_or(Month::January);
+            Date::from_calendar_date(year + (index / 12) as i32, month, start.day as u8).unwrap_or(date)
+        };
+        let year_start = if boundary(date.year(), 0) <= date { date.year() } else { date.year() - 1 };
+        (1..=12 / months)
+            .map(|period| boundary(year_start, period * months))
+            .find(|next| *next > date)
+            .map(|next| next.previous_day().unwrap_or(next))
+            .unwrap_or(date)
+    }
+}
+
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+pub struct PnlPeriod {
+    period: String,
+    quantity: f64,
+    proceeds: f64,
+    cost_basis: f64,
+    gain: f64,
+}
+
+/// Realized proceeds, cost basis, gain/loss and quantity disposed between `from` and `to`
+/// (inclusive), one entry per period that had disposals. Quarters and years are fiscal.
+#[tauri::command]
+pub fn realized_pnl_report(from: DateTime, to: DateTime, granularity: Granularity) -> Result<Vec<PnlPeriod>, AppError> {
+    let conn = connect_and_setup_db()?;
+
+    let fiscal_year_start = load_fiscal_year_start(&conn)?;
+    let display_offset = load_display_offset(&conn)?;
+
+    let mut stmt = conn.prepare(
+        "SELECT liquidation_date, quantity, sell_value, orig_value FROM used_timber
+         WHERE liquidation_date >= ?1 AND liquidation_date <= ?2
+         ORDER BY liquidation_date",
+    )?;
+    let rows = stmt.query_map(params![from.to_stored(&conn)?, to.to_stored(&conn)?], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, f64>(1)?, row.get::<_, f64>(2)?, row.get::<_, f64>(3)?))
+    })?;
+    let mut totals: BTreeMap<String, (f64, f64, f64)> = BTreeMap::new();
+    for row in rows {
+        let (date, quantity, sell_value, orig_value) = row?;
+        let period = granularity.period_name(&date, &fiscal_year_start, display_offset).ok_or(AppError::InvalidDate(date))?;
+        let entry = totals.entry(period).or_default();
+        entry.0 += quantity;
+        entry.1 += sell_value;
+        entry.2 += orig_value;
+    }
+
+    let round = |value: f64| (value * 100.0).round() / 100.0;
+    Ok(totals
+        .into_iter()
+        .map(|(period, (quantity, proceeds, cost_basis))| {
+            let (proceeds, cost_basis) = (round(proceeds), round(cost_basis));
+            PnlPeriod { period, quantity, proceeds, cost_basis, gain: round(proceeds - cost_basis) }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reports::set_fiscal_year_start;
+    use crate::test_support::{day, test_db};
+    use crate::{record_purchase, use_tao};
+    use time::macros::date;
+
+    const JANUARY: FiscalYearStart = FiscalYearStart { month: 1, day: 1 };
+    const APRIL_6: FiscalYearStart = FiscalYearStart { month: 4, day: 6 };
+    const JULY: FiscalYearStart = FiscalYearStart { month: 7, day: 1 };
+
+    #[test]
+    fn periods_are_named_by_fiscal_year() {
+        use Granularity::*;
+        let cases = [
+            (Day, "2024-04-05T23:59:59Z", APRIL_6, "2024-04-05"),
+            (Month, "2024-04-05T23:59:59Z", APRIL_6, "2024-04"),
+            (Quarter, "2024-03-31T00:00:00Z", JANUARY, "2024-Q1"),
+            (Quarter, "2024-04-01T00:00:00Z", JANUARY, "2024-Q2"),
+            (Quarter, "2024-12-31T00:00:00Z", JANUARY, "2024-Q4"),
+            (Quarter, "2024-04-05T23:59:59Z", APRIL_6, "2024-Q4"),
+            (Quarter, "2024-04-06T00:00:00Z", APRIL_6, "2025-Q1"),
+            (Quarter, "2024-07-05T00:00:00Z", APRIL_6, "2025-Q1"),
+            (Quarter, "2024-07-06T00:00:00Z", APRIL_6, "2025-Q2"),
+            (Quarter, "2025-01-05T00:00:00Z", APRIL_6, "2025-Q3"),
+            (Quarter, "2025-01-06T00:00:00Z", APRIL_6, "2025-Q4"),
+            (Quarter, "2024-06-30T00:00:00Z", JULY, "2024-Q4"),
+            (Quarter, "2024-07-01T00:00:00Z", JULY, "2025-Q1"),
+            (Quarter, "2024-10-01T00:00:00Z", JULY, "2025-Q2"),
+            (Year, "2024-12-31T00:00:00Z", JANUARY, "2024"),
+            (Year, "2024-04-05T00:00:00Z", APRIL_6, "2024"),
+            (Year, "2024-04-06T00:00:00Z", APRIL_6, "2025"),
+            (Year, "2024-07-01T00:00:00Z", JULY, "2025"),
+        ];
+        for (granularity, date, start, expected) in cases {
+            assert_eq!(granularity.period_name(date, &start, UtcOffset::UTC).as_deref(), Some(expected), "{:?} of {} from {:?}", granularity, date, start);
+        }
+        assert_eq!(Granularity::Quarter.period_name("not a date", &JANUARY, UtcOffset::UTC), None);
+
+        // Periods go by the date in the display timezone: 23:30 UTC on 31 December is already
+        // 1 January at +10:00.
+        let sydney = UtcOffset::from_hms(10, 0, 0).unwrap();
+        assert_eq!(Granularity::Day.period_name("2024-12-31T23:30:00Z", &JANUARY, sydney).as_deref(), Some("2025-01-01"));
+        assert_eq!(Granularity::Quarter.period_name("2024-12-31T23:30:00Z", &JANUARY, sydney).as_deref(), Some("2025-Q1"));
+        assert_eq!(Granularity::Year.period_name("2024-04-05T14:00:00Z", &APRIL_6, sydney).as_deref(), Some("2025"));
+    }
+
+    #[test]
+    fn periods_end_where_the_fiscal_year_puts_them() {
+        use Granularity::*;
+        let cases = [
+            (Day, date!(2024 - 04 - 05), APRIL_6, date!(2024 - 04 - 05)),
+            (Month, date!(2024 - 02 - 10), APRIL_6, date!(2024 - 02 - 29)),
+            (Month, date!(2023 - 02 - 10), JANUARY, date!(2023 - 02 - 28)),
+            (Quarter, date!(2024 - 02 - 10), JANUARY, date!(2024 - 03 - 31)),
+            (Quarter, date!(2024 - 12 - 31), JANUARY, date!(2024 - 12 - 31)),
+            (Quarter, date!(2024 - 04 - 05), APRIL_6, date!(2024 - 04 - 05)),
+            (Quarter, date!(2024 - 04 - 06), APRIL_6, date!(2024 - 07 - 05)),
+            (Quarter, date!(2025 - 01 - 05), APRIL_6, date!(2025 - 01 - 05)),
+            (Quarter, date!(2025 - 01 - 06), APRIL_6, date!(2025 - 04 - 05)),
+            (Quarter, date!(2024 - 08 - 15), JULY, date!(2024 - 09 - 30)),
+            (Year, date!(2024 - 02 - 10), JANUARY, date!(2024 - 12 - 31)),
+            (Year, date!(2024 - 01 - 01), APRIL_6, date!(2024 - 04 - 05)),
+            (Year, date!(2024 - 04 - 06), APRIL_6, date!(2025 - 04 - 05)),
+            (Year, date!(2024 - 06 - 30), JULY, date!(2024 - 06 - 30)),
+            (Year, date!(2024 - 07 - 01), JULY, date!(2025 - 06 - 30)),
+        ];
+        for (granularity, date, start, expected) in cases {
+            assert_eq!(granularity.period_end(date, &start), expected, "{:?} of {} from {:?}", granularity, date, start);
+        }
+    }
+
+    #[test]
+    fn the_report_totals_disposals_by_period() {
+        let _db = test_db();
+        set_fiscal_year_start(4, 6).unwrap();
+        record_purchase(10.0, 5.0, day(2024, 1, 1), None, None, None, None).unwrap();
+        use_tao(1.0, day(2024, 4, 5), 8.0, None, None, None).unwrap();
+        use_tao(2.0, day(2024, 4, 6), 9.0, None, None, None).unwrap();
+        use_tao(3.0, day(2024, 7, 6), 10.0, None, None, None).unwrap();
+        use_tao(1.0, day(2024, 12, 1), 4.0, None, None, None).unwrap();
+
+        let report = |from, to, granularity| -> Vec<(String, f64, f64, f64, f64)> {
+            realized_pnl_report(from, to, granularity)
+                .unwrap()
+                .into_iter()
+                .map(|period| (period.period, period.quantity, period.proceeds, period.cost_basis, period.gain))
+                .collect()
+        };
+        let row = |period: &str, quantity, proceeds, cost_basis, gain| (period.to_string(), quantity, proceeds, cost_basis, gain);
+
+        assert_eq!(
+            report(day(2024, 1, 1), day(2024, 12, 31), Granularity::Quarter),
+            [row("2024-Q4", 1.0, 8.0, 5.0, 3.0), row("2025-Q1", 2.0, 18.0, 10.0, 8.0), row("2025-Q2", 3.0, 30.0, 15.0, 15.0), row("2025-Q3", 1.0, 4.0, 5.0, -1.0)]
+        );
+        assert_eq!(
+            report(day(2024, 1, 1), day(2024, 12, 31), Granularity::Year),
+            [row("2024", 1.0, 8.0, 5.0, 3.0), row("2025", 6.0, 52.0, 30.0, 22.0)]
+        );
+        assert_eq!(
+            report(day(2024, 1, 1), day(2024, 12, 31), Granularity::Month),
+            [row("2024-04", 3.0, 26.0, 15.0, 11.0), row("2024-07", 3.0, 30.0, 15.0, 15.0), row("2024-12", 1.0, 4.0, 5.0, -1.0)]
+        );
+        // Both ends of the range are included.
+        assert_eq!(
+            report(day(2024, 4, 6), day(2024, 7, 6), Granularity::Day),
+            [row("2024-04-06", 2.0, 18.0, 10.0, 8.0), row("2024-07-06", 3.0, 30.0, 15.0, 15.0)]
+        );
+    }
+}
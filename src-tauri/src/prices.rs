@@ -0,0 +1,102 @@
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::{audit, connect_and_setup_db, snapshots, AppError, DateTime};
+
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+pub struct PricePoint {
+    id: i32,
+    price_date: String,
+    price: f64,
+}
+
+/// Latest recorded price at or before `date`, if any.
+pub fn price_at(conn: &Connection, date: &str) -> rusqlite::Result<Option<f64>> {
+    conn.query_row(
+        "SELECT price FROM price_history WHERE price_date <= ?1 ORDER BY price_date DESC LIMIT 1",
+        params![date],
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+// Recording a price for a date that already has one replaces it, after taking a snapshot.
+#[tauri::command]
+pub fn record_price(date_time: DateTime, price: f64) -> Result<(), AppError> {
+    let conn = connect_and_setup_db()?;
+    let price_date = date_time.to_stored(&conn)?;
+    let existing: Option<i64> =
+        conn.query_row("SELECT id FROM price_history WHERE price_date = ?1", params![price_date], |row| row.get(0)).optional()?;
+    let before = match existing {
+        Some(id) => {
+            snapshots::take_snapshot("record_price")?;
+            audit::price_image(&conn, id)?
+        }
+        None => None,
+    };
+    conn.execute(
+        "INSERT INTO price_history (price_date, price) VALUES (?1, ?2)
+         ON CONFLICT (price_date) DO UPDATE SET price = excluded.price",
+        params![price_date, price],
+    )?;
+    let id = existing.unwrap_or_else(|| conn.last_insert_rowid());
+    audit::record_change(&conn, "record_price", "price_history", Some(id), before.as_ref(), audit::price_image(&conn, id)?.as_ref())?;
+    Ok(())
+}
+
+#[tauri::command]
+pub fn list_prices() -> Result<Vec<PricePoint>, AppError> {
+    let conn = connect_and_setup_db()?;
+    let mut stmt = conn.prepare("SELECT id, price_date, price FROM price_history ORDER BY price_date")?;
+    let prices = stmt.query_map([], |row| Ok(PricePoint { id: row.get(0)?, price_date: row.get(1)?, price: row.get(2)? }))?;
+    Ok(prices.collect::<rusqlite::Result<Vec<_>>>()?)
+}
+
+#[tauri::command]
+pub fn remove_price(id: i32) -> Result<(), AppError> {
+    snapshots::take_snapshot("remove_price")?;
+    let conn = connect_and_setup_db()?;
+    let before = audit::price_image(&conn, id as i64)?;
+    conn.execute("DELETE FROM price_history WHERE id = ?1", params![id])?;
+    audit::record_change(&conn, "remove_price", "price_history", Some(id as i64), before.as_ref(), None)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{day, test_db};
+
+    #[test]
+    fn the_latest_price_at_or_before_a_date_applies() {
+        let _db = test_db();
+        record_price(day(2024, 1, 2), 6.0).unwrap();
+        record_price(day(2024, 1, 4), 7.0).unwrap();
+        let conn = connect_and_setup_db().unwrap();
+        let cases = [
+            ("2024-01-01T23:59:59Z", None),
+            ("2024-01-02T00:00:00Z", Some(6.0)),
+            ("2024-01-03T12:00:00Z", Some(6.0)),
+            ("2024-01-04T00:00:00Z", Some(7.0)),
+            ("2025-01-01T00:00:00Z", Some(7.0)),
+        ];
+        for (date, expected) in cases {
+            assert_eq!(price_at(&conn, date).unwrap(), expected, "{}", date);
+        }
+    }
+
+    #[test]
+    fn recording_a_price_again_replaces_it_and_removing_it_falls_back() {
+        let _db = test_db();
+        record_price(day(2024, 1, 2), 6.0).unwrap();
+        record_price(day(2024, 1, 4), 7.0).unwrap();
+        record_price(day(2024, 1, 4), 7.5).unwrap();
+        let prices = list_prices().unwrap();
+        let figures: Vec<_> = prices.iter().map(|point| (point.price_date.as_str(), point.price)).collect();
+        assert_eq!(figures, [("2024-01-02T00:00:00Z", 6.0), ("2024-01-04T00:00:00Z", 7.5)]);
+
+        remove_price(prices[1].id).unwrap();
+        let conn = connect_and_setup_db().unwrap();
+        assert_eq!(list_prices().unwrap().len(), 1);
+        assert_eq!(price_at(&conn, "2024-01-05T00:00:00Z").unwrap(), Some(6.0));
+    }
+}
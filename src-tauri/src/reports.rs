@@ -0,0 +1,431 @@
+use rusqlite::backup::{Backup, StepResult};
+use rusqlite::{params, Connection, Result};
+
+use time::{Date, Month, UtcOffset};
+
+use crate::timestamp::{load_display_offset, local_date, start_of_day};
+use crate::{audit, connect_and_setup_db, replay_into, transaction_from_row, AllTransactions, AppError};
+
+// Report data shared by the exporters. Everything is read from one connection so the sheets of a
+// workbook (or pages of a report) always describe the same state of the database.
+
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+pub struct OpenLot {
+    pub id: i32,
+    pub quantity: f64,
+    pub price_per_ton: f64,
+    pub purchase_date: String,
+    pub txn_id: Option<i32>, // ledger row that created the lot
+}
+
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+pub struct Disposal {
+    pub id: i32,
+    pub quantity: f64,
+    pub orig_price: f64,
+    pub sell_price: f64,
+    pub liquidation_date: String,
+    pub purchase_date: Option<String>, // date of the lot this disposal consumed
+    pub from_reward: bool,             // whether that lot was received as a reward
+}
+
+impl Disposal {
+    pub fn cost_basis(&self) -> f64 {
+        self.quantity * self.orig_price
+    }
+
+    pub fn proceeds(&self) -> f64 {
+        self.quantity * self.sell_price
+    }
+
+    pub fn gain(&self) -> f64 {
+        self.proceeds() - self.cost_basis()
+    }
+
+    /// Whether the consumed lot was held for more than a year; None when the lot is unknown.
+    pub fn is_long_term(&self, display_offset: UtcOffset) -> Option<bool> {
+        self.purchase_date
+            .as_deref()
+            .map(|acquired| held_more_than_years(acquired, &self.liquidation_date, 1, display_offset))
+    }
+}
+
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+pub struct HoldingPoint {
+    pub date: String,
+    pub quantity: f64,
+}
+
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+pub struct PortfolioSummary {
+    pub open_quantity: f64,
+    pub open_cost_basis: f64,
+    pub proceeds: f64,
+    pub realized_cost_basis: f64,
+    pub realized_gain: f64,
+    pub reward_income: f64,
+    pub current_price: Option<f64>,
+    pub market_value: Option<f64>,
+    pub unrealized_gain: Option<f64>,
+}
+
+#[derive(Debug, Clone, Default,serde::Serialize,serde::Deserialize)]
+pub struct TaxYearSummary {
+    pub year: i32,
+    pub proceeds: f64,
+    pub cost_basis: f64,
+    pub short_term_gain: f64,
+    pub long_term_gain: f64,
+    pub reward_income: f64,
+}
+
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+pub struct PortfolioReport {
+    pub open_lots: Vec<OpenLot>,
+    pub disposals: Vec<Disposal>,
+    pub transactions: Vec<AllTransactions>,
+    pub holdings: Vec<HoldingPoint>,
+    pub tax_years: Vec<i32>,
+    pub fiscal_year_start: FiscalYearStart,
+    #[serde(skip, default = "utc")]
+    pub display_offset: UtcOffset, // dates for tax years and holding periods are taken at this offset
+    pub summary: PortfolioSummary,
+}
+
+fn utc() -> UtcOffset {
+    UtcOffset::UTC
+}
+
+/// First day of a fiscal year. Fiscal years are named after the calendar year they end in, so
+/// with a 1 July start "2025" runs from 1 July 2024 to 30 June 2025.
+#[derive(Debug, Clone, Copy, PartialEq,serde::Serialize,serde::Deserialize)]
+pub struct FiscalYearStart {
+    pub month: u32,
+    pub day: u32,
+}
+
+impl Default for FiscalYearStart {
+    fn default() -> Self {
+        FiscalYearStart { month: 1, day: 1 }
+    }
+}
+
+/// Fiscal year a stored timestamp falls into, going by its date in the display timezone.
+pub fn fiscal_year(date: &str, start: &FiscalYearStart, display_offset: UtcOffset) -> Option<i32> {
+    let date = local_date(date, display_offset).ok()?;
+    let (month, day) = (date.month() as u32, date.day() as u32);
+    if (start.month, start.day) == (1, 1) || (month, day) < (start.month, start.day) {
+        Some(date.year())
+    } else {
+        Some(date.year() + 1)
+    }
+}
+
+/// First moment of fiscal year `year` and of the year after it, as stored dates, so a date is in
+/// the year when `start <= date < end`. Each is midnight in the display timezone.
+pub fn fiscal_year_bounds(year: i32, start: &FiscalYearStart, display_offset: UtcOffset) -> (String, String) {
+    let first_year = if (start.month, start.day) == (1, 1) { year } else { year - 1 };
+    let month = u8::try_from(start.month).ok().and_then(|month| Month::try_from(month).ok()).unwrap_or(Month::January);
+    // set_fiscal_year_start keeps the day to 28 or less, so it exists in every year.
+    let bound = |year: i32| Date::from_calendar_date(year, month, start.day as u8).map(|date| start_of_day(date, display_offset)).unwrap_or_default();
+    (bound(first_year), bound(first_year + 1))
+}
+
+/// The portfolio's configured fiscal year start, which every report, export and statistic uses to
+/// decide what a tax year means.
+pub fn load_fiscal_year_start(conn: &Connection) -> Result<FiscalYearStart> {
+    conn.query_row(
+        "SELECT fiscal_year_start_month, fiscal_year_start_day FROM app_settings WHERE id = 1",
+        [],
+        |row| Ok(FiscalYearStart { month: row.get(0)?, day: row.get(1)? }),
+    )
+}
+
+#[tauri::command]
+pub fn get_fiscal_year_start() -> Result<FiscalYearStart, AppError> {
+    let conn = connect_and_setup_db()?;
+    Ok(load_fiscal_year_start(&conn)?)
+}
+
+/// Sets the first day of the fiscal year, e.g. 4/6 for the UK or 7/1 for Australia. The day is
+/// limited to 28 so that fiscal years and quarters start on a day every month has.
+#[tauri::command]
+pub fn set_fiscal_year_start(month: u32, day: u32) -> Result<(), AppError> {
+    if !(1..=12).contains(&month) || !(1..=28).contains(&day) {
+        return Err(AppError::InvalidDate(format!("{}/{} is not a valid fiscal year start", month, day)));
+    }
+    let conn = connect_and_setup_db()?;
+    // Closed years are stored by name, which would then cover different dates.
+    let closed: i64 = conn.query_row("SELECT COUNT(*) FROM closed_periods", [], |row| row.get(0))?;
+    if closed > 0 && load_fiscal_year_start(&conn)? != (FiscalYearStart { month, day }) {
+        return Err(AppError::PeriodLock("reopen the closed fiscal years before changing when the fiscal year starts".to_string()));
+    }
+    let before = audit::settings_image(&conn)?;
+    conn.execute(
+        "UPDATE app_settings SET fiscal_year_start_month = ?1, fiscal_year_start_day = ?2 WHERE id = 1",
+        params![month, day],
+    )?;
+    audit::record_settings_change(&conn, "set_fiscal_year_start", before)?;
+    Ok(())
+}
+
+/// True when `sold` falls after the `years`-th anniversary of `acquired`, which is how the US,
+/// Germany and Australia all count a holding period. A 29 February acquisition has its
+/// anniversary on 28 February. Both dates are taken in the display timezone. Dates that do not
+/// parse count as not held long enough.
+pub fn held_more_than_years(acquired: &str, sold: &str, years: i32, display_offset: UtcOffset) -> bool {
+    let (acquired, sold) = match (local_date(acquired, display_offset), local_date(sold, display_offset)) {
+        (Ok(acquired), Ok(sold)) => (acquired, sold),
+        _ => return false,
+    };
+    let anniversary_year = acquired.year() + years;
+    let anniversary = acquired
+        .replace_year(anniversary_year)
+        .or_else(|_| acquired.replace_day(28).and_then(|day| day.replace_year(anniversary_year)));
+    anniversary.map(|anniversary| sold > anniversary).unwrap_or(false)
+}
+
+/// Date a ledger row takes effect: the purchase date for acquisitions, the liquidation date for
+/// disposals.
+pub fn effective_date(txn: &AllTransactions) -> Option<&str> {
+    if txn.is_used {
+        txn.liquidation_date.as_deref()
+    } else {
+        txn.purchase_date.as_deref()
+    }
+}
+
+/// Value of a reward at receipt, which is reported as income. Zero for anything else.
+pub fn reward_income(txn: &AllTransactions) -> f64 {
+    if txn.is_reward && !txn.is_used {
+        txn.quantity * txn.price_per_ton.unwrap_or(0.0)
+    } else {
+        0.0
+    }
+}
+
+/// Name of the lot selection method configured in app_settings.usage_type.
+pub fn cost_basis_method(conn: &Connection) -> Result<&'static str> {
+    let usage_type: i64 = conn.query_row("SELECT usage_type FROM app_settings WHERE id = 1", [], |row| row.get(0))?;
+    Ok(match usage_type {
+        1 => "FIFO",
+        2 => "LIFO",
+        3 => "LOFO",
+        _ => "HIFO",
+    })
+}
+
+pub fn open_lots(conn: &Connection) -> Result<Vec<OpenLot>> {
+    let mut stmt = conn.prepare("SELECT id, quantity, price_per_ton, purchase_date, txn_id FROM timber_purchases ORDER BY purchase_date, txn_id, id")?;
+    let lots = stmt.query_map([], |row| {
+        Ok(OpenLot {
+            id: row.get(0)?,
+            quantity: row.get(1)?,
+            price_per_ton: row.get(2)?,
+            purchase_date: row.get(3)?,
+            txn_id: row.get(4)?,
+        })
+    })?;
+    lots.collect()
+}
+
+pub fn disposals(conn: &Connection) -> Result<Vec<Disposal>> {
+    let mut stmt = conn.prepare(
+        "SELECT u.id, u.quantity, u.orig_price, u.sell_price, u.liquidation_date, u.purchase_date, COALESCE(a.is_reward, 0)
+         FROM used_timber u LEFT JOIN all_transactions a ON a.id = u.purchase_txn_id
+         ORDER BY u.liquidation_date, u.id",
+    )?;
+    let disposals = stmt.query_map([], |row| {
+        Ok(Disposal {
+            id: row.get(0)?,
+            quantity: row.get(1)?,
+            orig_price: row.get(2)?,
+            sell_price: row.get(3)?,
+            liquidation_date: row.get(4)?,
+            purchase_date: row.get(5)?,
+            from_reward: row.get(6)?,
+        })
+    })?;
+    disposals.collect()
+}
+
+/// Disposals recorded before lots were linked have no acquisition date until the ledger is
+/// replayed. Exporters that need the link read from the copy this returns when there are any: the
+/// database in memory with the ledger replayed, so a report never changes the lot tables.
+pub fn with_lot_links(conn: &Connection) -> std::result::Result<Option<Connection>, AppError> {
+    let unlinked: i64 = conn.query_row("SELECT COUNT(*) FROM used_timber WHERE purchase_date IS NULL", [], |row| row.get(0))?;
+    if unlinked == 0 {
+        return Ok(None);
+    }
+    let mut linked = Connection::open_in_memory()?;
+    if !matches!(Backup::new(conn, &mut linked)?.step(-1)?, StepResult::Done) {
+        return Err(AppError::BackupError("the database is busy; try the report again".to_string()));
+    }
+    linked.execute("DELETE FROM timber_purchases", [])?;
+    linked.execute("DELETE FROM used_timber", [])?;
+    replay_into(&linked, &linked, None, None)?;
+    Ok(Some(linked))
+}
+
+// Running balance after every ledger row, in date order.
+fn holdings(transactions: &[AllTransactions]) -> Vec<HoldingPoint> {
+    let mut dated: Vec<(&str, f64)> = transactions
+        .iter()
+        .filter_map(|txn| {
+            let change = if txn.is_used { -txn.quantity } else { txn.quantity };
+            effective_date(txn).map(|date| (date, change))
+        })
+        .collect();
+    dated.sort_by(|a, b| a.0.cmp(b.0));
+
+    let mut quantity = 0.0;
+    dated
+        .into_iter()
+        .map(|(date, change)| {
+            quantity = (quantity + change).max(0.0);
+            HoldingPoint { date: date.to_string(), quantity }
+        })
+        .collect()
+}
+
+pub fn build_portfolio_report(conn: &Connection, current_price: Option<f64>) -> Result<PortfolioReport> {
+    let open_lots = open_lots(conn)?;
+    let disposals = disposals(conn)?;
+
+    let mut stmt = conn.prepare("SELECT * FROM all_transactions ORDER BY id")?;
+    let transactions = stmt.query_map([], transaction_from_row)?.collect::<Result<Vec<_>>>()?;
+
+    let fiscal_year_start = load_fiscal_year_start(conn)?;
+    let display_offset = load_display_offset(conn)?;
+    let tax_year = |date: &str| fiscal_year(date, &fiscal_year_start, display_offset);
+    let mut tax_years: Vec<i32> = disposals
+        .iter()
+        .filter_map(|disposal| tax_year(&disposal.liquidation_date))
+        .chain(
+            transactions
+                .iter()
+                .filter(|txn| reward_income(txn) != 0.0)
+                .filter_map(|txn| effective_date(txn).and_then(tax_year)),
+        )
+        .collect();
+    tax_years.sort_unstable();
+    tax_years.dedup();
+
+    let open_quantity: f64 = open_lots.iter().map(|lot| lot.quantity).sum();
+    let open_cost_basis: f64 = open_lots.iter().map(|lot| lot.quantity * lot.price_per_ton).sum();
+    let proceeds: f64 = disposals.iter().map(Disposal::proceeds).sum();
+    let realized_cost_basis: f64 = disposals.iter().map(Disposal::cost_basis).sum();
+    let market_value = current_price.map(|price| price * open_quantity);
+
+    let summary = PortfolioSummary {
+        open_quantity,
+        open_cost_basis,
+        proceeds,
+        realized_cost_basis,
+        realized_gain: proceeds - realized_cost_basis,
+        reward_income: transactions.iter().map(reward_income).sum(),
+        current_price,
+        market_value,
+        unrealized_gain: market_value.map(|value| value - open_cost_basis),
+    };
+
+    Ok(PortfolioReport {
+        holdings: holdings(&transactions),
+        open_lots,
+        disposals,
+        transactions,
+        tax_years,
+        fiscal_year_start,
+        display_offset,
+        summary,
+    })
+}
+
+/// Realized gains split by holding period, and reward income, for each tax year in the report.
+/// Disposals from an unknown lot count as short-term, as on Form 8949.
+pub fn tax_year_summaries(report: &PortfolioReport) -> Vec<TaxYearSummary> {
+    let mut summaries: Vec<TaxYearSummary> = report
+        .tax_years
+        .iter()
+        .map(|&year| TaxYearSummary { year, ..Default::default() })
+        .collect();
+    let tax_year = |date: &str| fiscal_year(date, &report.fiscal_year_start, report.display_offset);
+
+    for disposal in &report.disposals {
+        let summary = match tax_year(&disposal.liquidation_date).and_then(|year| summaries.iter_mut().find(|s| s.year == year)) {
+            Some(summary) => summary,
+            None => continue,
+        };
+        summary.proceeds += disposal.proceeds();
+        summary.cost_basis += disposal.cost_basis();
+        if disposal.is_long_term(report.display_offset).unwrap_or(false) {
+            summary.long_term_gain += disposal.gain();
+        } else {
+            summary.short_term_gain += disposal.gain();
+        }
+    }
+    for txn in &report.transactions {
+        let income = reward_income(txn);
+        if income == 0.0 {
+            continue;
+        }
+        if let Some(summary) = effective_date(txn).and_then(tax_year).and_then(|year| summaries.iter_mut().find(|s| s.year == year)) {
+            summary.reward_income += income;
+        }
+    }
+    summaries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{day, test_db};
+    use crate::{periods, record_purchase, replay_ledger, tax, use_tao};
+
+    #[test]
+    fn reports_on_unlinked_disposals_leave_the_lot_tables_alone() {
+        let _db = test_db();
+        record_purchase(10.0, 5.0, day(2022, 1, 1), None, None, None, None).unwrap();
+        use_tao(5.0, day(2022, 7, 1), 10.0, None, None, None).unwrap();
+        periods::close_period(2022).unwrap();
+        // A disposal from before lots were linked, and a ledger the closed year no longer replays
+        // the same from.
+        let conn = connect_and_setup_db().unwrap();
+        conn.execute("UPDATE used_timber SET purchase_date = NULL, purchase_txn_id = NULL", []).unwrap();
+        conn.execute("UPDATE all_transactions SET price_per_ton = 6 WHERE id = 1", []).unwrap();
+        assert!(matches!(replay_ledger(false), Err(AppError::PeriodLock(_))));
+
+        let estimate = tax::estimate_tax(2022).unwrap();
+        assert!((estimate.short_term_gain - 20.0).abs() < 1e-9);
+        let unlinked: i64 = conn.query_row("SELECT COUNT(*) FROM used_timber WHERE purchase_date IS NULL", [], |row| row.get(0)).unwrap();
+        assert_eq!(unlinked, 1);
+    }
+
+    #[test]
+    fn tax_years_and_holding_periods_go_by_the_display_timezone() {
+        let sydney = UtcOffset::from_hms(10, 0, 0).unwrap();
+        let new_york = UtcOffset::from_hms(-5, 0, 0).unwrap();
+        let calendar = FiscalYearStart::default();
+        let july = FiscalYearStart { month: 7, day: 1 };
+
+        // 23:30 UTC on 31 December is already 1 January in Sydney; 03:00 UTC on 1 January is
+        // still 31 December in New York.
+        assert_eq!(fiscal_year("2024-12-31T23:30:00Z", &calendar, UtcOffset::UTC), Some(2024));
+        assert_eq!(fiscal_year("2024-12-31T23:30:00Z", &calendar, sydney), Some(2025));
+        assert_eq!(fiscal_year("2025-01-01T03:00:00Z", &calendar, new_york), Some(2024));
+        assert_eq!(fiscal_year("2024-06-30T15:00:00Z", &july, sydney), Some(2025));
+
+        // Each year starts at local midnight.
+        let (from, to) = fiscal_year_bounds(2025, &calendar, sydney);
+        assert_eq!((from.as_str(), to.as_str()), ("2024-12-31T14:00:00Z", "2025-12-31T14:00:00Z"));
+        assert!(from.as_str() <= "2024-12-31T23:30:00Z" && "2024-12-31T23:30:00Z" < to.as_str());
+        let (from, to) = fiscal_year_bounds(2025, &july, new_york);
+        assert_eq!((from.as_str(), to.as_str()), ("2024-07-01T05:00:00Z", "2025-07-01T05:00:00Z"));
+
+        // Bought on 2 March and sold on 2 March a year later in Sydney, which is not more than a
+        // year, though the UTC dates are 1 March and 2 March.
+        let (acquired, sold) = ("2023-03-01T15:00:00Z", "2024-03-02T10:00:00Z");
+        assert!(held_more_than_years(acquired, sold, 1, UtcOffset::UTC));
+        assert!(!held_more_than_years(acquired, sold, 1, sydney));
+    }
+}
@@ -0,0 +1,325 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use rusqlite::backup::Progress;
+use rusqlite::{params, Connection, DatabaseName};
+
+use crate::backup::{dump_table, restore_rows};
+use crate::{audit, connect_and_setup_db, db_path, periods, setup_db, timestamp, AppError};
+
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+pub struct SnapshotInfo {
+    file_name: String,
+    created_at: String,
+    reason: String,
+    size_bytes: u64,
+}
+
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+pub struct SnapshotSettings {
+    retention: i64,        // number of snapshots kept, 0 keeps all of them
+    interval_minutes: i64, // time between scheduled snapshots, 0 disables the schedule
+    last_error: Option<String>, // why the last scheduled snapshot failed, cleared when one succeeds
+}
+
+fn snapshot_dir() -> PathBuf {
+    db_path().with_file_name("Tao_Inventory_snapshots")
+}
+
+fn load_settings(conn: &Connection) -> rusqlite::Result<SnapshotSettings> {
+    conn.query_row(
+        "SELECT snapshot_retention, snapshot_interval_minutes, snapshot_last_error FROM app_settings WHERE id = 1",
+        [],
+        |row| Ok(SnapshotSettings { retention: row.get(0)?, interval_minutes: row.get(1)?, last_error: row.get(2)? }),
+    )
+}
+
+// Snapshot files are named "<YYYYMMDD-HHMMSS-mmm>_<reason>.db" in UTC, so sorting by name sorts
+// them chronologically.
+fn snapshot_files() -> Result<Vec<PathBuf>, AppError> {
+    let dir = snapshot_dir();
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().map(|ext| ext == "db").unwrap_or(false) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn write_snapshot(conn: &Connection, reason: &str) -> Result<PathBuf, AppError> {
+    let dir = snapshot_dir();
+    std::fs::create_dir_all(&dir)?;
+
+    let now = time::OffsetDateTime::now_utc();
+    let file_name = format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}-{:03}_{}.db",
+        now.year(), now.month() as u8, now.day(), now.hour(), now.minute(), now.second(), now.millisecond(), reason
+    );
+    let path = dir.join(file_name);
+    conn.backup(DatabaseName::Main, &path, None)?;
+    Ok(path)
+}
+
+fn prune_snapshots(retention: i64) -> Result<(), AppError> {
+    if retention <= 0 {
+        return Ok(());
+    }
+    let files = snapshot_files()?;
+    let excess = files.len().saturating_sub(retention as usize);
+    for path in &files[..excess] {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Copies the live database into the snapshot directory using SQLite's online backup API and
+/// drops the oldest snapshots beyond the configured retention. Called before every command that
+/// deletes or overwrites data.
+pub fn take_snapshot(reason: &str) -> Result<PathBuf, AppError> {
+    let conn = connect_and_setup_db()?;
+    let settings = load_settings(&conn)?;
+    let path = write_snapshot(&conn, reason)?;
+    prune_snapshots(settings.retention)?;
+    Ok(path)
+}
+
+// Takes a snapshot every snapshot_interval_minutes while the app is running.
+pub fn start_scheduler() {
+    std::thread::spawn(|| loop {
+        std::thread::sleep(run_scheduled_snapshot());
+    });
+}
+
+// One pass of the scheduler: takes a snapshot unless the database has not been written to since
+// the newest one, and returns how long to wait before the next pass. A failure is kept in
+// app_settings for get_snapshot_settings to show, and cleared by the next snapshot that succeeds.
+fn run_scheduled_snapshot() -> Duration {
+    let interval_minutes = connect_and_setup_db()
+        .ok()
+        .and_then(|conn| load_settings(&conn).ok())
+        .map(|settings| settings.interval_minutes)
+        .unwrap_or(0);
+    if interval_minutes <= 0 {
+        return Duration::from_secs(60);
+    }
+
+    let db_modified = std::fs::metadata(db_path()).and_then(|m| m.modified()).ok();
+    let last_snapshot = snapshot_files()
+        .ok()
+        .and_then(|files| files.last().cloned())
+        .and_then(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok());
+    let changed = match (db_modified, last_snapshot) {
+        (Some(db), Some(snapshot)) => db > snapshot,
+        _ => true,
+    };
+    if changed {
+        let error = take_snapshot("scheduled").err().map(|e| format!("{}: {}", timestamp::now_stored(), e));
+        if let Err(e) = record_snapshot_error(error.as_deref()) {
+            eprintln!("Could not record the scheduled snapshot result: {}", e);
+        }
+    }
+    Duration::from_secs(interval_minutes as u64 * 60)
+}
+
+// Only writes when the stored error changes, so clearing it does not count as a change to the
+// database for the next pass.
+fn record_snapshot_error(error: Option<&str>) -> Result<(), AppError> {
+    let conn = connect_and_setup_db()?;
+    conn.execute(
+        "UPDATE app_settings SET snapshot_last_error = ?1 WHERE id = 1 AND snapshot_last_error IS NOT ?1",
+        params![error],
+    )?;
+    Ok(())
+}
+
+#[tauri::command]
+pub fn list_snapshots() -> Result<Vec<SnapshotInfo>, AppError> {
+    let mut snapshots = Vec::new();
+    for path in snapshot_files()?.into_iter().rev() {
+        let file_name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        let stem = file_name.trim_end_matches(".db");
+        let (stamp, reason) = stem.split_once('_').unwrap_or((stem, ""));
+        // "20240131-235959-123" -> "2024-01-31T23:59:59Z"; file names are stamped in UTC.
+        let created_at = if stamp.len() >= 15 {
+            format!(
+                "{}-{}-{}T{}:{}:{}Z",
+                &stamp[0..4], &stamp[4..6], &stamp[6..8], &stamp[9..11], &stamp[11..13], &stamp[13..15]
+            )
+        } else {
+            stamp.to_string()
+        };
+        snapshots.push(SnapshotInfo {
+            size_bytes: std::fs::metadata(&path)?.len(),
+            file_name: file_name.clone(),
+            created_at,
+            reason: reason.to_string(),
+        });
+    }
+    Ok(snapshots)
+}
+
+// Replaces the live database with the given snapshot. The current state is snapshotted first,
+// so a restore can itself be undone. The audit log is kept rather than rolled back with the rest:
+// entries written since the snapshot are added back, followed by the changes the restore made.
+// A snapshot that would reopen a closed fiscal year, or change ledger rows that could alter one,
+// is refused unless `override_closed_period` is set.
+#[tauri::command]
+pub fn restore_snapshot(file_name: String, override_closed_period: Option<bool>) -> Result<(), AppError> {
+    let path = snapshot_dir().join(&file_name);
+    if Path::new(&file_name).file_name().map(|name| name != file_name.as_str()).unwrap_or(true) || !path.is_file() {
+        return Err(AppError::BackupError(format!("no snapshot named {}", file_name)));
+    }
+
+    let mut conn = connect_and_setup_db()?;
+    let mut snapshot = Connection::open_in_memory()?;
+    snapshot.restore(DatabaseName::Main, &path, None::<fn(Progress)>)?;
+    periods::RestoreGuard::new(&conn)?.check(&setup_db(snapshot)?, override_closed_period.unwrap_or(false))?;
+
+    let settings = load_settings(&conn)?;
+    let ledger_before = audit::ledger_images(&conn)?;
+    let settings_before = audit::settings_image(&conn)?;
+    let audit_log = dump_table(&conn, "audit_log")?;
+    write_snapshot(&conn, "restore_snapshot")?;
+    conn.restore(DatabaseName::Main, &path, None::<fn(Progress)>)?;
+    drop(conn);
+
+    // Older snapshots may predate the current schema.
+    let conn = connect_and_setup_db()?;
+    let tx = conn.unchecked_transaction()?;
+    restore_rows(&tx, "audit_log", &audit_log, true)?;
+    audit::record_ledger_changes(&tx, "restore_snapshot", &ledger_before)?;
+    audit::record_settings_change(&tx, "restore_snapshot", settings_before)?;
+    tx.commit()?;
+    prune_snapshots(settings.retention)
+}
+
+#[tauri::command]
+pub fn get_snapshot_settings() -> Result<SnapshotSettings, AppError> {
+    let conn = connect_and_setup_db()?;
+    Ok(load_settings(&conn)?)
+}
+
+#[tauri::command]
+pub fn set_snapshot_settings(retention: i64, interval_minutes: i64) -> Result<(), AppError> {
+    let conn = connect_and_setup_db()?;
+    let before = audit::settings_image(&conn)?;
+    conn.execute(
+        "UPDATE app_settings SET snapshot_retention = ?1, snapshot_interval_minutes = ?2 WHERE id = 1",
+        params![retention.max(0), interval_minutes.max(0)],
+    )?;
+    audit::record_settings_change(&conn, "set_snapshot_settings", before)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{day, test_db};
+    use crate::{edit_transaction_via_id, record_purchase, show_all_transactions, use_tao};
+
+    fn snapshot(reason: &str) -> String {
+        take_snapshot(reason).unwrap().file_name().unwrap().to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn snapshots_that_could_alter_a_closed_year_need_an_override() {
+        let _db = test_db();
+        record_purchase(10.0, 5.0, day(2022, 1, 1), None, None, None, None).unwrap();
+        use_tao(5.0, day(2022, 7, 1), 10.0, None, None, None).unwrap();
+        let before_closing = snapshot("before_closing");
+        periods::close_period(2022).unwrap();
+        record_purchase(1.0, 6.0, day(2024, 1, 1), None, None, None, None).unwrap();
+        let after_closing = snapshot("after_closing");
+
+        // Rows after the closed year roll back freely.
+        record_purchase(2.0, 7.0, day(2024, 2, 1), None, None, None, None).unwrap();
+        restore_snapshot(after_closing.clone(), None).unwrap();
+        assert_eq!(show_all_transactions().unwrap().len(), 3);
+
+        // Rows of the closed year changed since only roll back with the override.
+        assert_eq!(edit_transaction_via_id(1, 10.0, 4.0, 0.0, 0.0, day(2022, 1, 1), day(2022, 1, 1), false, None, Some(true)), "Completed");
+        assert!(matches!(restore_snapshot(after_closing.clone(), None), Err(AppError::PeriodLock(_))));
+        assert_eq!(show_all_transactions().unwrap()[0].price_per_ton, Some(4.0));
+        restore_snapshot(after_closing, Some(true)).unwrap();
+        assert_eq!(show_all_transactions().unwrap()[0].price_per_ton, Some(5.0));
+
+        // So does a snapshot from before the year was closed, which would reopen it.
+        assert!(matches!(restore_snapshot(before_closing.clone(), None), Err(AppError::PeriodLock(_))));
+        assert_eq!(periods::list_closed_periods().unwrap().len(), 1);
+        restore_snapshot(before_closing, Some(true)).unwrap();
+        assert!(periods::list_closed_periods().unwrap().is_empty());
+    }
+
+    fn snapshot_names() -> Vec<String> {
+        list_snapshots().unwrap().into_iter().map(|info| info.file_name).collect()
+    }
+
+    #[test]
+    fn pruning_keeps_the_newest_snapshots_up_to_the_retention() {
+        let _db = test_db();
+        set_snapshot_settings(0, 60).unwrap();
+        let taken: Vec<String> = (1..=5).map(|i| snapshot(&format!("s{}", i))).collect();
+        assert_eq!(snapshot_names().len(), 5);
+
+        set_snapshot_settings(3, 60).unwrap();
+        let newest = snapshot("s6");
+        // list_snapshots is newest first.
+        assert_eq!(snapshot_names(), [newest, taken[4].clone(), taken[3].clone()]);
+    }
+
+    #[test]
+    fn a_restore_can_be_undone_from_the_snapshot_it_takes() {
+        let _db = test_db();
+        record_purchase(10.0, 5.0, day(2024, 1, 1), None, None, None, None).unwrap();
+        let earlier = snapshot("earlier");
+        record_purchase(2.0, 7.0, day(2024, 2, 1), None, None, None, None).unwrap();
+
+        restore_snapshot(earlier.clone(), None).unwrap();
+        assert_eq!(show_all_transactions().unwrap().len(), 1);
+        let undo = list_snapshots().unwrap().into_iter().find(|info| info.reason == "restore_snapshot").unwrap();
+        assert_ne!(undo.file_name, earlier);
+
+        restore_snapshot(undo.file_name, None).unwrap();
+        let ledger = show_all_transactions().unwrap();
+        assert_eq!(ledger.len(), 2);
+        assert_eq!(ledger[1].price_per_ton, Some(7.0));
+    }
+
+    #[test]
+    fn the_scheduler_snapshots_changes_and_keeps_its_last_error() {
+        let _db = test_db();
+        record_purchase(10.0, 5.0, day(2024, 1, 1), None, None, None, None).unwrap();
+        let scheduled = || list_snapshots().unwrap().iter().filter(|info| info.reason == "scheduled").count();
+
+        // A disabled schedule checks back every minute without taking anything.
+        set_snapshot_settings(20, 0).unwrap();
+        assert_eq!(run_scheduled_snapshot(), Duration::from_secs(60));
+        assert_eq!(scheduled(), 0);
+
+        set_snapshot_settings(20, 5).unwrap();
+        assert_eq!(run_scheduled_snapshot(), Duration::from_secs(300));
+        assert_eq!(scheduled(), 1);
+        // Nothing was written since, so there is nothing to snapshot.
+        run_scheduled_snapshot();
+        assert_eq!(scheduled(), 1);
+
+        // A snapshot directory that cannot be created fails the snapshot, which is kept for the UI.
+        let dir = snapshot_dir();
+        std::fs::rename(&dir, dir.with_extension("moved")).unwrap();
+        std::fs::write(&dir, "").unwrap();
+        run_scheduled_snapshot();
+        assert!(get_snapshot_settings().unwrap().last_error.is_some());
+
+        std::fs::remove_file(&dir).unwrap();
+        std::fs::rename(dir.with_extension("moved"), &dir).unwrap();
+        run_scheduled_snapshot();
+        assert_eq!(scheduled(), 2);
+        assert_eq!(get_snapshot_settings().unwrap().last_error, None);
+    }
+}
@@ -0,0 +1,329 @@
+use rusqlite::{params, Connection};
+
+use crate::australia::build_australia_report;
+use crate::germany::build_germany_report;
+use crate::reports::{build_portfolio_report, tax_year_summaries, with_lot_links, TaxYearSummary};
+use crate::{audit, connect_and_setup_db, AppError};
+
+#[derive(Debug, Clone, PartialEq,serde::Serialize,serde::Deserialize)]
+pub enum Jurisdiction {
+    US,
+    UK,
+    Germany,
+    Australia,
+    Other,
+}
+
+/// `rate` applies to the part of the taxable amount above `threshold`, up to the next bracket's
+/// threshold. A flat rate is a single bracket with a threshold of 0.
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+pub struct TaxBracket {
+    pub threshold: f64,
+    pub rate: f64,
+}
+
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+#[serde(default)]
+pub struct TaxProfile {
+    pub jurisdiction: Jurisdiction,
+    pub short_term_brackets: Vec<TaxBracket>,
+    pub long_term_brackets: Vec<TaxBracket>,
+    pub reward_income_rate: f64,
+    pub annual_exemption: f64, // allowance deducted from the year's net gains
+    pub staking_ten_year_rule: bool, // Germany: ten-year holding period for lots from staking
+}
+
+impl Default for TaxProfile {
+    fn default() -> Self {
+        TaxProfile::preset(Jurisdiction::Other)
+    }
+}
+
+fn flat(rate: f64) -> Vec<TaxBracket> {
+    vec![TaxBracket { threshold: 0.0, rate }]
+}
+
+impl TaxProfile {
+    /// Starting points for a profile. Income-dependent rates use a single assumed bracket and
+    /// should be adjusted to the taxpayer's own situation.
+    pub fn preset(jurisdiction: Jurisdiction) -> Self {
+        match jurisdiction {
+            // 2024 single filer: long-term 0/15/20%, short-term and rewards at an assumed 24%
+            // ordinary rate.
+            Jurisdiction::US => TaxProfile {
+                jurisdiction,
+                short_term_brackets: flat(0.24),
+                long_term_brackets: vec![
+                    TaxBracket { threshold: 0.0, rate: 0.0 },
+                    TaxBracket { threshold: 47_025.0, rate: 0.15 },
+                    TaxBracket { threshold: 518_900.0, rate: 0.20 },
+                ],
+                reward_income_rate: 0.24,
+                annual_exemption: 0.0,
+                staking_ten_year_rule: false,
+            },
+            // 2024/25 higher-rate taxpayer: 24% on gains regardless of holding period above the
+            // £3,000 annual exempt amount, rewards taxed as income at 40%.
+            Jurisdiction::UK => TaxProfile {
+                jurisdiction,
+                short_term_brackets: flat(0.24),
+                long_term_brackets: flat(0.24),
+                reward_income_rate: 0.40,
+                annual_exemption: 3_000.0,
+                staking_ten_year_rule: false,
+            },
+            // Private sales held over a year are tax-free; shorter holdings and rewards are taxed
+            // at the personal income rate, assumed 42% here. See germany.rs for the Freigrenzen.
+            Jurisdiction::Germany => TaxProfile {
+                jurisdiction,
+                short_term_brackets: flat(0.42),
+                long_term_brackets: flat(0.0),
+                reward_income_rate: 0.42,
+                annual_exemption: 0.0,
+                staking_ten_year_rule: false,
+            },
+            // Net capital gain, after losses and the 50% discount, is added to income; an assumed
+            // 37% marginal rate applies to it and to rewards. See australia.rs.
+            Jurisdiction::Australia => TaxProfile {
+                jurisdiction,
+                short_term_brackets: flat(0.37),
+                long_term_brackets: flat(0.37),
+                reward_income_rate: 0.37,
+                annual_exemption: 0.0,
+                staking_ten_year_rule: false,
+            },
+            Jurisdiction::Other => TaxProfile {
+                jurisdiction,
+                short_term_brackets: flat(0.0),
+                long_term_brackets: flat(0.0),
+                reward_income_rate: 0.0,
+                annual_exemption: 0.0,
+                staking_ten_year_rule: false,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default,serde::Serialize,serde::Deserialize)]
+pub struct TaxEstimate {
+    pub year: i32,
+    pub short_term_gain: f64,
+    pub long_term_gain: f64,
+    pub exemption_used: f64,
+    pub taxable_short_term: f64,
+    pub taxable_long_term: f64,
+    pub short_term_tax: f64,
+    pub long_term_tax: f64,
+    pub reward_income: f64,
+    pub reward_income_tax: f64,
+    pub total_tax: f64,
+    pub loss_carryforward: f64, // net loss left over after offsetting the year's gains
+}
+
+// Tax on `amount` under progressive brackets.
+pub fn bracket_tax(amount: f64, brackets: &[TaxBracket]) -> f64 {
+    let mut sorted = brackets.to_vec();
+    sorted.sort_by(|a, b| a.threshold.total_cmp(&b.threshold));
+    let mut tax = 0.0;
+    for (index, bracket) in sorted.iter().enumerate() {
+        let upper = sorted.get(index + 1).map(|next| next.threshold).unwrap_or(f64::INFINITY);
+        if amount > bracket.threshold {
+            tax += (amount.min(upper) - bracket.threshold) * bracket.rate;
+        }
+    }
+    tax
+}
+
+/// Applies a profile to one year's realized gains and reward income. A net loss in one holding
+/// period is offset against gains in the other, and the exemption is used against short-term
+/// gains first, as those are usually taxed at the higher rate.
+pub fn estimate(profile: &TaxProfile, summary: &TaxYearSummary) -> TaxEstimate {
+    let (mut short_term, mut long_term) = (summary.short_term_gain, summary.long_term_gain);
+    if short_term < 0.0 && long_term > 0.0 {
+        long_term += short_term;
+        short_term = 0.0;
+    } else if long_term < 0.0 && short_term > 0.0 {
+        short_term += long_term;
+        long_term = 0.0;
+    }
+    let loss_carryforward = short_term.min(0.0).abs() + long_term.min(0.0).abs();
+    let (mut short_term, mut long_term) = (short_term.max(0.0), long_term.max(0.0));
+
+    let exemption_used = profile.annual_exemption.max(0.0).min(short_term + long_term);
+    let from_short_term = exemption_used.min(short_term);
+    short_term -= from_short_term;
+    long_term -= exemption_used - from_short_term;
+
+    let short_term_tax = bracket_tax(short_term, &profile.short_term_brackets);
+    let long_term_tax = bracket_tax(long_term, &profile.long_term_brackets);
+    let reward_income_tax = summary.reward_income.max(0.0) * profile.reward_income_rate;
+    TaxEstimate {
+        year: summary.year,
+        short_term_gain: summary.short_term_gain,
+        long_term_gain: summary.long_term_gain,
+        exemption_used,
+        taxable_short_term: short_term,
+        taxable_long_term: long_term,
+        short_term_tax,
+        long_term_tax,
+        reward_income: summary.reward_income,
+        reward_income_tax,
+        total_tax: short_term_tax + long_term_tax + reward_income_tax,
+        loss_carryforward,
+    }
+}
+
+pub fn load_tax_profile(conn: &Connection) -> Result<TaxProfile, AppError> {
+    let stored: Option<String> = conn.query_row("SELECT tax_profile FROM app_settings WHERE id = 1", [], |row| row.get(0))?;
+    match stored {
+        Some(json) => Ok(serde_json::from_str(&json)?),
+        None => Ok(TaxProfile::default()),
+    }
+}
+
+#[tauri::command]
+pub fn get_tax_profile() -> Result<TaxProfile, AppError> {
+    let conn = connect_and_setup_db()?;
+    load_tax_profile(&conn)
+}
+
+#[tauri::command]
+pub fn set_tax_profile(profile: TaxProfile) -> Result<(), AppError> {
+    let conn = connect_and_setup_db()?;
+    let before = audit::settings_image(&conn)?;
+    conn.execute("UPDATE app_settings SET tax_profile = ?1 WHERE id = 1", params![serde_json::to_string(&profile)?])?;
+    audit::record_settings_change(&conn, "set_tax_profile", before)?;
+    Ok(())
+}
+
+#[tauri::command]
+pub fn tax_profile_preset(jurisdiction: Jurisdiction) -> TaxProfile {
+    TaxProfile::preset(jurisdiction)
+}
+
+/// Estimated tax for `year` under the stored profile. Germany and Australia have their own rules
+/// (exempt disposals and Freigrenzen, the CGT discount over a July-June year); every other
+/// jurisdiction uses the brackets and exemption as given.
+#[tauri::command]
+pub fn estimate_tax(year: i32) -> Result<TaxEstimate, AppError> {
+    let conn = connect_and_setup_db()?;
+    let linked = with_lot_links(&conn)?;
+    let conn = linked.as_ref().unwrap_or(&conn);
+    let profile = load_tax_profile(conn)?;
+    match profile.jurisdiction {
+        Jurisdiction::Germany => return Ok(build_germany_report(conn, &profile, year)?.estimate),
+        Jurisdiction::Australia => return Ok(build_australia_report(conn, &profile, year, 0.0)?.estimate),
+        _ => {}
+    }
+    let report = build_portfolio_report(conn, None)?;
+    let summary = tax_year_summaries(&report)
+        .into_iter()
+        .find(|summary| summary.year == year)
+        .unwrap_or(TaxYearSummary { year, ..Default::default() });
+    Ok(estimate(&profile, &summary))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{day, test_db};
+    use crate::{record_purchase, use_tao};
+
+    #[test]
+    fn presets_against_known_figures() {
+        use Jurisdiction::{UK, US};
+        // (case, jurisdiction, short-term gain, long-term gain, reward income,
+        //  exemption used, taxable short-term, taxable long-term, total tax, loss carryforward)
+        let cases = [
+            ("US long-term at the 0% edge", US, 0.0, 47_025.0, 0.0, 0.0, 0.0, 47_025.0, 0.0, 0.0),
+            ("US long-term just over it", US, 0.0, 47_026.0, 0.0, 0.0, 0.0, 47_026.0, 0.15, 0.0),
+            ("US long-term at the 20% edge", US, 0.0, 518_900.0, 0.0, 0.0, 0.0, 518_900.0, 70_781.25, 0.0),
+            ("US long-term into 20%", US, 0.0, 600_000.0, 0.0, 0.0, 0.0, 600_000.0, 87_001.25, 0.0),
+            ("US long-term loss offsets short-term", US, 10_000.0, -4_000.0, 0.0, 0.0, 6_000.0, 0.0, 1_440.0, 0.0),
+            ("US net loss carried forward", US, -10_000.0, 4_000.0, 0.0, 0.0, 0.0, 0.0, 0.0, 6_000.0),
+            ("US losses on both sides", US, -1_000.0, -2_000.0, 0.0, 0.0, 0.0, 0.0, 0.0, 3_000.0),
+            ("US rewards", US, 0.0, 0.0, 1_000.0, 0.0, 0.0, 0.0, 240.0, 0.0),
+            ("UK under the exemption", UK, 2_000.0, 0.0, 0.0, 2_000.0, 0.0, 0.0, 0.0, 0.0),
+            ("UK at the exemption", UK, 3_000.0, 0.0, 0.0, 3_000.0, 0.0, 0.0, 0.0, 0.0),
+            ("UK exemption short-term first", UK, 1_000.0, 5_000.0, 0.0, 3_000.0, 0.0, 3_000.0, 720.0, 0.0),
+            ("UK loss offset before the exemption", UK, -4_000.0, 5_000.0, 0.0, 1_000.0, 0.0, 0.0, 0.0, 0.0),
+            ("UK net loss carried forward", UK, -4_000.0, 1_000.0, 0.0, 0.0, 0.0, 0.0, 0.0, 3_000.0),
+            ("UK rewards", UK, 0.0, 0.0, 500.0, 0.0, 0.0, 0.0, 200.0, 0.0),
+        ];
+        for (case, jurisdiction, short_term_gain, long_term_gain, reward_income, exemption_used, taxable_short_term, taxable_long_term, total_tax, loss_carryforward) in cases {
+            let summary = TaxYearSummary { year: 2024, short_term_gain, long_term_gain, reward_income, ..Default::default() };
+            let estimate = estimate(&TaxProfile::preset(jurisdiction), &summary);
+            let figures = [
+                ("exemption used", estimate.exemption_used, exemption_used),
+                ("taxable short-term", estimate.taxable_short_term, taxable_short_term),
+                ("taxable long-term", estimate.taxable_long_term, taxable_long_term),
+                ("total tax", estimate.total_tax, total_tax),
+                ("loss carryforward", estimate.loss_carryforward, loss_carryforward),
+            ];
+            for (figure, actual, expected) in figures {
+                assert!((actual - expected).abs() < 1e-6, "{}: {} is {}, expected {}", case, figure, actual, expected);
+            }
+        }
+    }
+
+    // A ledger entry for the Germany cases: a purchase (or reward) or a sale of `quantity` at
+    // `price` on the given day of `year`.
+    enum Entry {
+        Buy(f32, f64, (i32, u32, u32)),
+        Reward(f32, f64, (i32, u32, u32)),
+        Sell(f32, f64, (i32, u32, u32)),
+    }
+
+    #[test]
+    fn germany_estimates_follow_the_stored_profile() {
+        use Entry::{Buy, Reward, Sell};
+        // (case, ledger, short-term gain, tax-free gain, Freigrenze used, taxable short-term,
+        //  total tax, loss carryforward) for 2024, when the Freigrenze is €1,000.
+        let cases = [
+            ("under the Freigrenze", vec![Buy(1.0, 1_000.0, (2024, 1, 1)), Sell(1.0, 1_999.0, (2024, 6, 1))], 999.0, 0.0, 999.0, 0.0, 0.0, 0.0),
+            ("at the Freigrenze, taxed in full", vec![Buy(1.0, 1_000.0, (2024, 1, 1)), Sell(1.0, 2_000.0, (2024, 6, 1))], 1_000.0, 0.0, 0.0, 1_000.0, 420.0, 0.0),
+            ("held over a year", vec![Buy(10.0, 1_000.0, (2022, 1, 1)), Sell(10.0, 6_000.0, (2024, 3, 1))], 0.0, 50_000.0, 0.0, 0.0, 0.0, 0.0),
+            (
+                "a loss is not set against tax-free gains",
+                vec![Buy(1.0, 1_000.0, (2022, 1, 1)), Buy(1.0, 3_000.0, (2024, 1, 1)), Sell(1.0, 6_000.0, (2024, 3, 1)), Sell(1.0, 1_000.0, (2024, 4, 1))],
+                -2_000.0, 5_000.0, 0.0, 0.0, 0.0, 2_000.0,
+            ),
+            ("net loss carried forward", vec![Buy(1.0, 1_000.0, (2024, 1, 1)), Sell(1.0, 500.0, (2024, 6, 1))], -500.0, 0.0, 0.0, 0.0, 0.0, 500.0),
+            ("rewards over their Freigrenze", vec![Reward(1.0, 300.0, (2024, 2, 1))], 0.0, 0.0, 0.0, 0.0, 126.0, 0.0),
+        ];
+        for (case, ledger, short_term_gain, tax_free_gain, exemption_used, taxable_short_term, total_tax, loss_carryforward) in cases {
+            let _db = test_db();
+            set_tax_profile(TaxProfile::preset(Jurisdiction::Germany)).unwrap();
+            for entry in ledger {
+                match entry {
+                    Buy(quantity, price, (y, m, d)) => record_purchase(quantity, price, day(y, m, d), None, None, None, None).unwrap(),
+                    Reward(quantity, price, (y, m, d)) => record_purchase(quantity, price, day(y, m, d), None, Some(true), None, None).unwrap(),
+                    Sell(quantity, price, (y, m, d)) => {
+                        use_tao(quantity, day(y, m, d), price, None, None, None).unwrap();
+                    }
+                }
+            }
+            let estimate = estimate_tax(2024).unwrap();
+            let figures = [
+                ("short-term gain", estimate.short_term_gain, short_term_gain),
+                ("tax-free gain", estimate.long_term_gain, tax_free_gain),
+                ("Freigrenze used", estimate.exemption_used, exemption_used),
+                ("taxable short-term", estimate.taxable_short_term, taxable_short_term),
+                ("taxable long-term", estimate.taxable_long_term, 0.0),
+                ("total tax", estimate.total_tax, total_tax),
+                ("loss carryforward", estimate.loss_carryforward, loss_carryforward),
+            ];
+            for (figure, actual, expected) in figures {
+                assert!((actual - expected).abs() < 1e-6, "{}: {} is {}, expected {}", case, figure, actual, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn bracket_tax_at_the_edges() {
+        let brackets = vec![TaxBracket { threshold: 10_000.0, rate: 0.2 }, TaxBracket { threshold: 0.0, rate: 0.1 }];
+        for (amount, expected) in [(0.0, 0.0), (-50.0, 0.0), (10_000.0, 1_000.0), (10_001.0, 1_000.2), (20_000.0, 3_000.0)] {
+            assert!((bracket_tax(amount, &brackets) - expected).abs() < 1e-9, "{}", amount);
+        }
+    }
+}
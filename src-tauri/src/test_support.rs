@@ -0,0 +1,43 @@
+// Helpers for the unit tests. Every test works on a database of its own, in a temporary directory
+// that connect_and_setup_db and the snapshot directory resolve to on the test's thread, so tests
+// can call commands directly and run in parallel.
+
+use std::cell::RefCell;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::timestamp::DateTime;
+
+thread_local! {
+    static TEST_DB_PATH: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+}
+
+/// The current test's database, if it opened one.
+pub fn db_path() -> Option<PathBuf> {
+    TEST_DB_PATH.with(|path| path.borrow().clone())
+}
+
+/// A fresh database for the current test, deleted with its snapshots when dropped.
+pub struct TestDb {
+    dir: PathBuf,
+}
+
+impl Drop for TestDb {
+    fn drop(&mut self) {
+        TEST_DB_PATH.with(|path| *path.borrow_mut() = None);
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+pub fn test_db() -> TestDb {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!("taocount-test-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::SeqCst)));
+    std::fs::create_dir_all(&dir).expect("create test directory");
+    TEST_DB_PATH.with(|path| *path.borrow_mut() = Some(dir.join("Tao_Inventory.db")));
+    TestDb { dir }
+}
+
+/// Midnight on the given day in the display timezone, which is UTC unless a test changes it.
+pub fn day(year: i32, month: u32, day: u32) -> DateTime {
+    DateTime::Fields { year, month, day, hour: 0, minute: 0, second: 0 }
+}
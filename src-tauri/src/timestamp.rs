@@ -0,0 +1,317 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use time::format_description::well_known::Rfc3339;
+use time::macros::format_description;
+use time::{Date, Month, OffsetDateTime, PrimitiveDateTime, Time, UtcOffset};
+
+use crate::{audit, connect_and_setup_db, mark_changed_at, periods, rehash_generated_refs, snapshots, AppError};
+
+// Every timestamp in the database is UTC ISO 8601, e.g. "2024-03-01T14:30:00Z". The fixed width
+// keeps them sorting chronologically as text, which the date comparisons in SQL rely on, and the
+// UTC date is always the first ten characters. Tax years, closed periods and holding periods go by
+// the date in the display timezone instead (local_date), so a sale at 23:00 UTC on 31 December
+// falls in the next year for a portfolio shown at +10:00.
+
+// Columns holding timestamps, rewritten by rewrite_legacy_dates.
+const DATE_COLUMNS: [(&str, &str); 6] = [
+    ("all_transactions", "purchase_date"),
+    ("all_transactions", "liquidation_date"),
+    ("timber_purchases", "purchase_date"),
+    ("used_timber", "purchase_date"),
+    ("used_timber", "liquidation_date"),
+    ("price_history", "price_date"),
+];
+
+/// A point in time as sent by the frontend: an RFC 3339 string with an offset, a wall-clock time
+/// as "YYYY-MM-DDTHH:MM[:SS]" (what a datetime-local input gives), or a
+/// {year, month, day, hour, minute, second} object. Wall-clock times are read in the portfolio's
+/// display timezone.
+#[derive(Debug, Clone,serde::Serialize,serde::Deserialize)]
+#[serde(untagged)]
+pub enum DateTime {
+    Text(String),
+    Fields { year: i32, month: u32, day: u32, hour: u32, minute: u32, second: u32 },
+}
+
+impl DateTime {
+    /// The instant this names, or InvalidDate saying what is wrong with it.
+    pub fn resolve(&self, display_offset: UtcOffset) -> Result<OffsetDateTime, AppError> {
+        match self {
+            DateTime::Text(text) => {
+                if let Ok(instant) = OffsetDateTime::parse(text, &Rfc3339) {
+                    return Ok(instant);
+                }
+                PrimitiveDateTime::parse(text, format_description!("[year]-[month]-[day]T[hour]:[minute]:[second]"))
+                    .or_else(|_| PrimitiveDateTime::parse(text, format_description!("[year]-[month]-[day]T[hour]:[minute]")))
+                    .map(|wall_clock| wall_clock.assume_offset(display_offset))
+                    .map_err(|e| AppError::InvalidDate(format!("{} ({})", text, e)))
+            }
+            DateTime::Fields { year, month, day, hour, minute, second } => {
+                let described = format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, hour, minute, second);
+                let invalid = |reason: String| AppError::InvalidDate(format!("{} ({})", described, reason));
+                let month = u8::try_from(*month)
+                    .ok()
+                    .and_then(|month| Month::try_from(month).ok())
+                    .ok_or_else(|| invalid("month must be in the range 1..=12".to_string()))?;
+                let component = |value: u32| u8::try_from(value).unwrap_or(u8::MAX);
+                let date = Date::from_calendar_date(*year, month, component(*day)).map_err(|e| invalid(e.to_string()))?;
+                let time = Time::from_hms(component(*hour), component(*minute), component(*second)).map_err(|e| invalid(e.to_string()))?;
+                Ok(PrimitiveDateTime::new(date, time).assume_offset(display_offset))
+            }
+        }
+    }
+
+    /// The stored form of this timestamp, reading wall-clock times in `conn`'s display timezone.
+    pub fn to_stored(&self, conn: &Connection) -> Result<String, AppError> {
+        Ok(format_stored(self.resolve(load_display_offset(conn)?)?))
+    }
+}
+
+pub fn format_stored(instant: OffsetDateTime) -> String {
+    let utc = instant.to_offset(UtcOffset::UTC);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        utc.year(), utc.month() as u8, utc.day(), utc.hour(), utc.minute(), utc.second()
+    )
+}
+
+pub fn now_stored() -> String {
+    format_stored(OffsetDateTime::now_utc())
+}
+
+pub fn parse_stored(stored: &str) -> Result<OffsetDateTime, AppError> {
+    OffsetDateTime::parse(stored, &Rfc3339).map_err(|_| AppError::InvalidDate(stored.to_string()))
+}
+
+/// The date a stored timestamp falls on in the display timezone.
+pub fn local_date(stored: &str, display_offset: UtcOffset) -> Result<Date, AppError> {
+    Ok(parse_stored(stored)?.to_offset(display_offset).date())
+}
+
+/// Midnight at the start of `date` in the display timezone, as stored.
+pub fn start_of_day(date: Date, display_offset: UtcOffset) -> String {
+    format_stored(date.midnight().assume_offset(display_offset))
+}
+
+/// Last second of `date` in the display timezone, as stored.
+pub fn end_of_day(date: Date, display_offset: UtcOffset) -> String {
+    format_stored(PrimitiveDateTime::new(date, Time::from_hms(23, 59, 59).unwrap_or(Time::MIDNIGHT)).assume_offset(display_offset))
+}
+
+pub fn load_display_offset(conn: &Connection) -> rusqlite::Result<UtcOffset> {
+    let seconds: i32 = conn.query_row("SELECT display_utc_offset_seconds FROM app_settings WHERE id = 1", [], |row| row.get(0))?;
+    Ok(UtcOffset::from_whole_seconds(seconds).unwrap_or(UtcOffset::UTC))
+}
+
+fn format_offset(offset: UtcOffset) -> String {
+    let (hours, minutes, _) = offset.as_hms();
+    let sign = if offset.is_negative() { '-' } else { '+' };
+    format!("{}{:02}:{:02}", sign, hours.abs(), minutes.abs())
+}
+
+fn parse_offset(offset: &str) -> Result<UtcOffset, AppError> {
+    let parsed = if offset == "Z" {
+        Ok(UtcOffset::UTC)
+    } else {
+        UtcOffset::parse(offset, format_description!("[offset_hour sign:mandatory]:[offset_minute]"))
+    };
+    parsed.map_err(|e| AppError::InvalidDate(format!("{} is not a UTC offset ({})", offset, e)))
+}
+
+/// Rewrites dates stored in the old "YYYY-MM-DD HH:MM:SS" form, which had no timezone, as UTC
+/// ISO 8601; part of migrate_ledger_format. Databases from before the display timezone have no
+/// record of the timezone these wall-clock times were entered in, so they are taken as UTC, and
+/// the ledger and price dates are noted in legacy_dates for reinterpret_legacy_dates. Dates that
+/// are not valid (month 13, day 32) are left as they are and reported as invalid wherever they are
+/// used.
+pub fn rewrite_legacy_dates(conn: &Connection) -> rusqlite::Result<()> {
+    let legacy = format_description!("[year]-[month]-[day] [hour]:[minute]:[second]");
+    for (table, column) in DATE_COLUMNS {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT rowid, {column} FROM {table} WHERE {column} GLOB '[0-9][0-9][0-9][0-9]-[0-9][0-9]-[0-9][0-9] [0-9][0-9]:[0-9][0-9]:[0-9][0-9]'"
+        ))?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))?.collect::<rusqlite::Result<Vec<_>>>()?;
+        for (rowid, old) in rows {
+            if let Ok(wall_clock) = PrimitiveDateTime::parse(&old, legacy) {
+                let stored = format_stored(wall_clock.assume_utc());
+                conn.execute(&format!("UPDATE {table} SET {column} = ?1 WHERE rowid = ?2"), params![stored, rowid])?;
+                // The lot tables are rebuilt from the ledger, so they follow it when it is moved.
+                if matches!(table, "all_transactions" | "price_history") {
+                    conn.execute(
+                        "INSERT OR REPLACE INTO legacy_dates (table_name, column_name, row_id, migrated_to) VALUES (?1, ?2, ?3, ?4)",
+                        params![table, column, rowid, stored],
+                    )?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The portfolio's display timezone as a UTC offset, e.g. "+10:00".
+#[tauri::command]
+pub fn get_display_timezone() -> Result<String, AppError> {
+    let conn = connect_and_setup_db()?;
+    Ok(format_offset(load_display_offset(&conn)?))
+}
+
+/// Sets the display timezone as a UTC offset ("+10:00", "-05:00" or "Z"). Stored timestamps are
+/// not changed, but the dates they fall on are, and with them the tax year of anything near
+/// midnight; while any fiscal year is closed the offset cannot be changed. The offset is fixed,
+/// so it has to be updated by hand when daylight saving time starts or ends.
+#[tauri::command]
+pub fn set_display_timezone(offset: String) -> Result<(), AppError> {
+    let parsed = parse_offset(&offset)?;
+    let conn = connect_and_setup_db()?;
+    let closed: i64 = conn.query_row("SELECT COUNT(*) FROM closed_periods", [], |row| row.get(0))?;
+    if closed > 0 && load_display_offset(&conn)? != parsed {
+        return Err(AppError::PeriodLock("reopen the closed fiscal years before changing the display timezone".to_string()));
+    }
+    let before = audit::settings_image(&conn)?;
+    conn.execute("UPDATE app_settings SET display_utc_offset_seconds = ?1 WHERE id = 1", params![parsed.whole_seconds()])?;
+    audit::record_settings_change(&conn, "set_display_timezone", before)?;
+    Ok(())
+}
+
+/// Moves the dates that rewrite_legacy_dates took as UTC to the timezone they were entered in,
+/// given as a UTC offset like set_display_timezone takes, and returns how many were moved. Dates
+/// edited since the migration are left alone, and once run there is nothing left to move. The lots
+/// are rebuilt from the earliest date moved by the next replay; a move in or before a closed fiscal
+/// year is refused unless the lock is overridden.
+#[tauri::command]
+pub fn reinterpret_legacy_dates(offset: String, override_closed_period: Option<bool>) -> Result<usize, AppError> {
+    let entered_in = parse_offset(&offset)?;
+    let conn = connect_and_setup_db()?;
+    let mut stmt = conn.prepare("SELECT table_name, column_name, row_id, migrated_to FROM legacy_dates ORDER BY table_name, column_name, row_id")?;
+    let legacy = stmt
+        .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, i64>(2)?, row.get::<_, String>(3)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let mut moves = Vec::new();
+    for (table, column, rowid, migrated_to) in legacy {
+        // Only names from DATE_COLUMNS are ever recorded.
+        if !DATE_COLUMNS.contains(&(table.as_str(), column.as_str())) {
+            continue;
+        }
+        let current: Option<String> = conn
+            .query_row(&format!("SELECT {column} FROM {table} WHERE rowid = ?1"), params![rowid], |row| row.get(0))
+            .optional()?
+            .flatten();
+        if current.as_deref() == Some(migrated_to.as_str()) {
+            let moved = format_stored(parse_stored(&migrated_to)?.replace_offset(entered_in));
+            moves.push((table, column, rowid, migrated_to, moved));
+        }
+    }
+
+    let earliest = moves.iter().filter(|(table, ..)| table == "all_transactions").flat_map(|(.., from, to)| [from, to]).min();
+    if let Some(earliest) = earliest {
+        periods::ensure_open(&conn, earliest, override_closed_period.unwrap_or(false))?;
+    }
+    snapshots::take_snapshot("reinterpret_legacy_dates")?;
+    let tx = conn.unchecked_transaction()?;
+    let ledger_before = audit::ledger_images(&tx)?;
+    for (table, column, rowid, _, moved) in &moves {
+        let update = format!("UPDATE {table} SET {column} = ?1 WHERE rowid = ?2");
+        if table == "all_transactions" {
+            // Both where the row was and where it goes must be replayed.
+            mark_changed_at(&tx, *rowid as i32)?;
+            tx.execute(&update, params![moved, rowid])?;
+            mark_changed_at(&tx, *rowid as i32)?;
+        } else {
+            let before = audit::price_image(&tx, *rowid)?;
+            tx.execute(&update, params![moved, rowid])?;
+            audit::record_change(&tx, "reinterpret_legacy_dates", "price_history", Some(*rowid), before.as_ref(), audit::price_image(&tx, *rowid)?.as_ref())?;
+        }
+    }
+    tx.execute("DELETE FROM legacy_dates", [])?;
+    // Generated references hash the dates.
+    rehash_generated_refs(&tx)?;
+    audit::record_ledger_changes(&tx, "reinterpret_legacy_dates", &ledger_before)?;
+    tx.commit()?;
+    Ok(moves.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_db;
+    use crate::{content_hash, show_all_transactions, LEDGER_FORMAT};
+
+    fn insert_purchase(conn: &Connection, date: &str, external_ref: &str) {
+        conn.execute(
+            "INSERT INTO all_transactions (quantity, price_per_ton, purchase_date, is_used, external_ref) VALUES (10, 5, ?1, 0, ?2)",
+            params![date, external_ref],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn legacy_dates_are_migrated_once() {
+        let _db = test_db();
+        let conn = connect_and_setup_db().unwrap();
+        insert_purchase(&conn, "2024-01-01 00:00:00", "hash:legacy");
+        conn.execute("UPDATE app_settings SET ledger_format = 0 WHERE id = 1", []).unwrap();
+        drop(conn);
+
+        let conn = connect_and_setup_db().unwrap();
+        let all = show_all_transactions().unwrap();
+        assert_eq!(all[0].purchase_date.as_deref(), Some("2024-01-01T00:00:00Z"));
+        assert_eq!(all[0].external_ref, Some(content_hash(&all[0])));
+        let format: i64 = conn.query_row("SELECT ledger_format FROM app_settings", [], |row| row.get(0)).unwrap();
+        assert_eq!(format, LEDGER_FORMAT);
+
+        // Once migrated, connecting no longer scans for old dates.
+        insert_purchase(&conn, "2024-02-01 00:00:00", "hash:other");
+        drop(conn);
+        connect_and_setup_db().unwrap();
+        assert_eq!(show_all_transactions().unwrap()[1].purchase_date.as_deref(), Some("2024-02-01 00:00:00"));
+    }
+
+    #[test]
+    fn migrated_rows_matching_an_existing_row_are_flagged() {
+        let _db = test_db();
+        let conn = connect_and_setup_db().unwrap();
+        insert_purchase(&conn, "2024-01-01T00:00:00Z", "hash:current");
+        insert_purchase(&conn, "2024-01-01 00:00:00", "hash:legacy");
+        conn.execute("UPDATE app_settings SET ledger_format = 0 WHERE id = 1", []).unwrap();
+        drop(conn);
+
+        let conn = connect_and_setup_db().unwrap();
+        let all = show_all_transactions().unwrap();
+        let hash = content_hash(&all[0]);
+        assert_eq!(all[0].external_ref, Some(hash.clone()));
+        assert_eq!(all[1].external_ref, Some(format!("{}#2", hash)));
+        let flagged: (i32, i32, String) = conn
+            .query_row("SELECT transaction_id, duplicate_of_id, status FROM duplicate_candidates", [], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap();
+        assert_eq!(flagged, (2, 1, "pending".to_string()));
+    }
+
+    #[test]
+    fn legacy_dates_can_be_moved_once_to_the_timezone_they_were_entered_in() {
+        let _db = test_db();
+        let conn = connect_and_setup_db().unwrap();
+        insert_purchase(&conn, "2024-01-01 00:00:00", "hash:first");
+        insert_purchase(&conn, "2024-02-01 09:30:00", "hash:second");
+        conn.execute("INSERT INTO price_history (price_date, price) VALUES ('2024-01-15 12:00:00', 7.0)", []).unwrap();
+        conn.execute("UPDATE app_settings SET ledger_format = 0 WHERE id = 1", []).unwrap();
+        drop(conn);
+        connect_and_setup_db().unwrap();
+        // A date edited after the migration is the user's own and stays where it is.
+        assert_eq!(
+            crate::edit_transaction_via_id(2, 10.0, 5.0, 0.0, 0.0, DateTime::Text("2024-02-01T10:00:00Z".to_string()), DateTime::Text("2024-02-01T10:00:00Z".to_string()), false, None, None),
+            "Completed"
+        );
+
+        assert_eq!(reinterpret_legacy_dates("+10:00".to_string(), None).unwrap(), 2);
+        let all = show_all_transactions().unwrap();
+        assert_eq!(all[0].purchase_date.as_deref(), Some("2023-12-31T14:00:00Z"));
+        assert_eq!(all[0].external_ref, Some(content_hash(&all[0])));
+        assert_eq!(all[1].purchase_date.as_deref(), Some("2024-02-01T10:00:00Z"));
+        let conn = connect_and_setup_db().unwrap();
+        let price_date: String = conn.query_row("SELECT price_date FROM price_history", [], |row| row.get(0)).unwrap();
+        assert_eq!(price_date, "2024-01-15T02:00:00Z");
+
+        // Running it again moves nothing.
+        assert_eq!(reinterpret_legacy_dates("+10:00".to_string(), None).unwrap(), 0);
+        assert_eq!(show_all_transactions().unwrap()[0].purchase_date.as_deref(), Some("2023-12-31T14:00:00Z"));
+    }
+}
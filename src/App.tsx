@@ -1,5 +1,6 @@
 import { useState, useEffect } from "react";
 import { invoke } from "@tauri-apps/api/tauri";
+import { save } from "@tauri-apps/api/dialog";
 import "./App.css";
 
 interface TaoPurchase {
@@ -43,6 +44,8 @@ function App() {
   const [salePrice, setSalePrice] = useState('');
   const [price_per_ton, set_price_per_ton] = useState('');
   const [date, setDate] = useState('');
+  const [isReward, setIsReward] = useState(false);
+  const [currentPrice, setCurrentPrice] = useState('');
   const [showPopup, setShowPopup] = useState(false);
   const [transactions, setTransactions] = useState<AllTransactions[]>([]);
   const [editingTransaction, setEditingTransaction] = useState<AllTransactions | null>(null);
@@ -94,8 +97,22 @@ function App() {
   }
 
   async function handleWriteToExcel() {
-    await invoke("write_inventory_to_excel");
-    alert("Inventory report generated successfully!");
+    const path = await save({
+      defaultPath: "inventory_report.xlsx",
+      filters: [{ name: "Excel Workbook", extensions: ["xlsx"] }],
+    });
+    if (path === null) {
+      return;
+    }
+    try {
+      const written = await invoke("write_inventory_to_excel", {
+        path,
+        currentPrice: currentPrice === '' ? null : parseFloat(currentPrice),
+      }) as string;
+      alert(`Inventory report written to ${written}`);
+    } catch (error) {
+      alert(`Could not generate inventory report: ${error}`);
+    }
   }
 
   async function fetchUsedInventory() {
@@ -103,13 +120,32 @@ function App() {
     setUsedInventory(inventoryUsed);
   }
 
+  // Recording commands refuse a transaction that is already in the ledger, which is usually a
+  // double click or a re-run import; the user can still record it again on purpose.
+  async function record(command: string, args: Record<string, unknown>) {
+    try {
+      await invoke(command, args);
+    } catch (error) {
+      const message = String(error);
+      if (!message.startsWith("Duplicate transaction:")) {
+        alert(`Could not record the transaction: ${message}`);
+        return;
+      }
+      if (confirm(`${message}\n\nThis looks like a transaction that is already recorded. Record it again anyway?`)) {
+        await invoke(command, { ...args, allowDuplicate: true }).catch((retryError) =>
+          alert(`Could not record the transaction: ${retryError}`)
+        );
+      }
+    }
+  }
+
   async function handleRecordPurchase(e: React.FormEvent) {
     e.preventDefault();
 
     const dateTimeParts = date.split("-");
     let time_whole = dateTimeParts[2].split("T")[1];
     let time = time_whole.split(":");
-    await invoke("record_purchase", { 
+    await record("record_purchase", { 
       quantity: parseFloat(quantity), 
       pricePerTon: parseFloat(price_per_ton), 
       dateTime: {
@@ -119,7 +155,8 @@ function App() {
         hour: parseInt(time[0]), 
         minute: parseInt(time[1]), 
         second: 0 
-      } 
+      },
+      isReward,
     });
     fetchInventory();
     fetchUsedInventory();
@@ -132,7 +169,7 @@ function App() {
     let time_whole = dateTimeParts[2].split("T")[1];
     let time = time_whole.split(":");
 
-    await invoke("use_tao", { 
+    await record("use_tao", { 
       quantityNeeded: parseFloat(quantityNeeded), 
       liquidationDateTime: {
         year: parseInt(dateTimeParts[0]), 
@@ -157,6 +194,10 @@ function App() {
             <input type="number" value={quantity} onChange={(e) => setQuantity(e.target.value)} placeholder="Quantity" />
             <input type="number" value={price_per_ton} onChange={(e) => set_price_per_ton(e.target.value)} placeholder="Price Per Tao" />
             <input type="datetime-local" value={date} onChange={(e) => setDate(e.target.value)} />
+            <label>
+              <input type="checkbox" checked={isReward} onChange={(e) => setIsReward(e.target.checked)} />
+              Reward
+            </label>
             <button type="submit">Record Purchase</button>
           </div>
         </form>
@@ -238,6 +279,7 @@ function App() {
         </div>
       </div>
       <div className="excel">
+        <input type="number" value={currentPrice} onChange={(e) => setCurrentPrice(e.target.value)} placeholder="Current Price (optional)" />
         <button onClick={handleWriteToExcel}>Generate Excel Report</button>
       </div>
       <button onClick={() => setShowPopup(true)}>View & Manage Transactions</button>